@@ -1,4 +1,9 @@
+import { execFileSync, spawn } from "node:child_process";
+import { closeSync, existsSync, fstatSync, mkdtempSync, openSync, readFileSync, realpathSync } from "node:fs";
+import { tmpdir } from "node:os";
 import { dirname, join } from "node:path";
+import { once } from "node:events";
+import zlib from "node:zlib";
 import { fileURLToPath } from 'node:url';
 
 import test from 'ava'
@@ -6,7 +11,7 @@ import test from 'ava'
 // Earlier versions of node@20 don't have `import.meta.dirname`.
 const __dirname = import.meta.dirname || dirname(fileURLToPath(import.meta.url));
 
-import { SyncRpcChannel } from '../index.js';
+import { MessageType, SyncRpcChannel, SyncRpcPool, messageTypeFromU8, requestOneshot } from '../index.js';
 
 test("should be able to send a message and get a response, synchronously.", t => {
   const channel = makeChannel();
@@ -15,6 +20,88 @@ test("should be able to send a message and get a response, synchronously.", t =>
   channel.close();
 });
 
+test("round-trips large binary payloads intact", t => {
+  const channel = makeChannel();
+  const payload = new Uint8Array(8 * 1024 * 1024).map((_, i) => i % 251);
+  const response = channel.requestBinarySync("echo", payload);
+  t.is(response.length, payload.length);
+  t.deepEqual(response, payload);
+  channel.close();
+});
+
+test("round-trips Buffers", t => {
+  const channel = makeChannel();
+  const payload = Buffer.alloc(4 * 1024 * 1024, "abc");
+  const response = channel.requestBufferSync("echo", payload);
+  t.true(Buffer.isBuffer(response));
+  t.true(response.equals(payload));
+  channel.close();
+});
+
+test("can receive a response streamed in chunks", t => {
+  const channel = makeChannel();
+  const chunks = [];
+  const res = channel.requestStreamSync("stream", new Uint8Array([1, 2, 3]), chunk => chunks.push([...chunk]));
+  t.deepEqual(chunks, [[1], [2], [3]]);
+  t.is(res.length, 0);
+  channel.close();
+});
+
+// The Rust example child, if built with `cargo build --example socket_child`.
+const socketChild = join(__dirname, "../target/debug/examples", process.platform === "win32" ? "socket_child.exe" : "socket_child");
+
+(existsSync(socketChild) ? test : test.skip)("receives a response streamed in chunks by the Rust example child", t => {
+  const channel = new SyncRpcChannel(socketChild, []);
+  const payload = new Uint8Array(1000).map((_, i) => i % 251);
+  const chunks = [];
+  const res = channel.requestStreamSync("stream", payload, chunk => chunks.push(chunk));
+  t.is(chunks.length, payload.length);
+  t.deepEqual(Buffer.concat(chunks), Buffer.from(payload));
+  t.is(res.length, 0);
+  t.is(channel.requestSync("echo", "after streaming"), "after streaming");
+  channel.close();
+});
+
+test("throws once the child is done streaming if a chunk handler throws", t => {
+  const channel = makeChannel();
+  let calls = 0;
+  t.throws(() => {
+    channel.requestStreamSync("stream", new Uint8Array([1, 2, 3]), () => {
+      calls++;
+      throw new Error("oops");
+    });
+  }, { message: /Error handling response chunk for `stream`: .*oops/ });
+  t.is(calls, 1);
+  t.is(channel.requestSync("echo", '"hello"'), '"hello"');
+  channel.close();
+});
+
+test("requestJson encodes the request and decodes the response as JSON", t => {
+  const channel = makeChannel();
+  const payload = { list: [1, "two", null, true], nested: { value: 1.5 } };
+  t.deepEqual(channel.requestJson("echo", payload), payload);
+  channel.close();
+});
+
+test("requestJson throws with a snippet of an invalid JSON response", t => {
+  const cwd = mkdtempSync(join(tmpdir(), "libsyncrpc-"));
+  const channel = new SyncRpcChannel("node", [join(__dirname, "../echo.mjs")], { cwd });
+  t.throws(() => channel.requestJson("cwd", null), { message: /^Error while decoding response as JSON: .* \(response starts with `.+`\)$/ });
+  channel.close();
+});
+
+test("requestAckSync returns nothing for an empty response", t => {
+  const channel = makeChannel();
+  t.is(channel.requestAckSync("empty"), undefined);
+  channel.close();
+});
+
+test("requestAckSync throws if the response is not empty", t => {
+  const channel = makeChannel();
+  t.throws(() => channel.requestAckSync("cwd"), { message: /expected an empty response to `cwd`, got \d+ bytes/ });
+  channel.close();
+});
+
 test("can register a callback that will be requested by the child process before returning", t => {
   const channel = makeChannel();
   channel.registerCallback("echo", (_name, message) => message);
@@ -32,27 +119,1148 @@ test("callbacks are handled in the order in which they're requested", t => {
   channel.close();
 });
 
+test("scoped requests can only invoke the allowed callbacks", t => {
+  const channel = makeChannel();
+  const called = [];
+  for (const name of ["one", "two", "three"]) {
+    channel.registerCallback(name, () => {
+      called.push(name);
+      return name;
+    });
+  }
+  t.is(channel.requestSyncScoped("concat", "", ["one", "two", "three"]), "onetwothree");
+  called.length = 0;
+  t.throws(() => channel.requestSyncScoped("concat", "", ["one", "three"]), {
+    message: /callback `two` is not allowed during this request/,
+  });
+  t.deepEqual(called, ["one"]);
+  channel.close();
+});
+
+test("can register a binary callback that receives and returns raw bytes", t => {
+  const channel = makeChannel();
+  channel.registerBinaryCallback("echo", (_name, message) => message.map(b => b ^ 0xff));
+  const response = channel.requestBinarySync("callback-echo", new Uint8Array([0xff, 0xfe, 0x00]));
+  t.deepEqual([...response], [0x00, 0x01, 0xff]);
+  channel.close();
+});
+
+test("says where a response that isn't valid UTF-8 goes wrong", t => {
+  const channel = makeChannel();
+  channel.registerBinaryCallback("echo", () => new Uint8Array([0x68, 0x69, 0xff, 0x21]));
+  t.throws(() => channel.requestSync("callback-echo", ""), {
+    message: /invalid UTF-8 sequence of 1 bytes at byte offset 2 of a 4-byte payload\. Use `requestBinarySync`/,
+  });
+  channel.registerBinaryCallback("echo", () => new Uint8Array([0x68, 0xc3]));
+  t.throws(() => channel.requestSync("callback-echo", ""), {
+    message: /incomplete UTF-8 sequence at byte offset 1 of a 2-byte payload/,
+  });
+  channel.close();
+});
+
+test("reports an error payload that isn't valid UTF-8 by its bytes", t => {
+  const channel = makeChannel();
+  t.throws(() => channel.requestSync("binary-error", ""), {
+    message: new RegExp(`^request \`binary-error\` failed after \\d+ms: remote error \\(non-UTF-8, 40 bytes\\): ff${"ab".repeat(31)}\\.\\.\\.$`),
+  });
+  channel.close();
+});
+
+test("rejects callback payloads over the limit without invoking the callback", t => {
+  const channel = makeChannel();
+  let calls = 0;
+  channel.registerCallback("echo", (_name, message) => {
+    calls++;
+    return message;
+  });
+  channel.setCallbackPayloadLimit(4);
+  t.throws(() => channel.requestSync("callback-echo", "abcde"), {
+    message: /^request `callback-echo` failed after \d+ms: payload of 5 bytes for callback `echo` exceeds the limit of 4 bytes$/,
+  });
+  t.is(calls, 0);
+  // The channel is still usable, and payloads at the limit go through.
+  t.is(channel.requestSync("callback-echo", "abcd"), "abcd");
+  channel.setCallbackPayloadLimit(null);
+  t.is(channel.requestSync("callback-echo", "abcde"), "abcde");
+  t.is(calls, 2);
+  channel.close();
+});
+
+test("rejects empty method and callback names", t => {
+  const channel = makeChannel();
+  t.throws(() => channel.requestSync("", "hi"), { message: /^request `` failed after \d+ms: method name must not be empty$/ });
+  t.throws(() => channel.requestBatchSync([{ method: "echo", payload: new Uint8Array() }, { method: "", payload: new Uint8Array() }]), {
+    message: "method name must not be empty",
+  });
+  let calls = 0;
+  channel.registerDefaultCallback(() => {
+    calls++;
+    return "called";
+  });
+  t.is(channel.requestSync("call-named", "anything"), "called");
+  t.throws(() => channel.requestSync("call-named", ""), {
+    message: /^request `call-named` failed after \d+ms: callback name must not be empty$/,
+  });
+  t.is(calls, 1);
+  // Nothing was sent for the rejected requests, so the channel is still usable.
+  t.is(channel.requestSync("echo", "hi"), "hi");
+  channel.close();
+});
+
+test("can register a callback that receives a header and a body", t => {
+  const channel = makeChannel();
+  channel.registerHeaderCallback("echo", (name, header, body) => `${name}:${header}:${body}`);
+  t.is(channel.requestSync("callback-echo", "id-1\tsome\tbody"), "echo:id-1:some\tbody");
+  channel.close();
+});
+
+test("throws if a header callback's payload has no tab", t => {
+  const channel = makeChannel();
+  let called = false;
+  channel.registerHeaderCallback("echo", () => {
+    called = true;
+    return "";
+  });
+  t.throws(() => channel.requestSync("callback-echo", "no tab here"), { message: /two tab-separated fields/ });
+  t.false(called);
+  channel.close();
+});
+
+test("registering a callback replaces one of the other kind with the same name", t => {
+  const channel = makeChannel();
+  channel.registerBinaryCallback("echo", (_name, message) => message);
+  channel.registerCallback("echo", (_name, _message) => '"replaced"');
+  t.is(channel.requestSync("callback-echo", '"hello"'), '"replaced"');
+  channel.close();
+});
+
+test("can unregister callbacks", t => {
+  const channel = makeChannel();
+  channel.registerCallback("echo", (_name, payload) => payload);
+  t.true(channel.unregisterCallback("echo"));
+  t.false(channel.unregisterCallback("echo"));
+  t.throws(() => channel.requestSync("callback-echo", '"hello"'), { message: /unknown callback: `echo`/ });
+  channel.close();
+});
+
+test("can clear all callbacks", t => {
+  const channel = makeChannel();
+  channel.registerCallback("echo", (_name, payload) => payload);
+  channel.registerBinaryCallback("other", (_name, payload) => payload);
+  channel.clearCallbacks();
+  t.false(channel.unregisterCallback("other"));
+  t.throws(() => channel.requestSync("callback-echo", '"hello"'), { message: /unknown callback: `echo`/ });
+  channel.close();
+});
+
+test("lists registered callbacks in sorted order", t => {
+  const channel = makeChannel();
+  t.deepEqual(channel.registeredCallbacks(), []);
+  channel.registerCallback("echo", (_name, payload) => payload);
+  channel.registerBinaryCallback("another", (_name, payload) => payload);
+  channel.registerAsyncCallback("later", (_name, payload) => payload);
+  channel.registerDefaultCallback(() => null);
+  t.deepEqual(channel.registeredCallbacks(), ["another", "echo", "later"]);
+  channel.unregisterCallback("echo");
+  t.deepEqual(channel.registeredCallbacks(), ["another", "later"]);
+  channel.close();
+});
+
+test("a default callback handles unregistered callbacks and can decline them", t => {
+  const channel = makeChannel();
+  channel.registerCallback("one", (_name, _message) => "one");
+  channel.registerDefaultCallback((name, message) => (name === "two" ? `default:${message}` : null));
+  t.throws(() => channel.requestSync("concat", ""), { message: /unknown callback: `three`/ });
+  channel.registerCallback("three", (_name, _message) => "three");
+  t.is(channel.requestSync("concat", ""), "onedefault:2three");
+  t.true(channel.unregisterDefaultCallback());
+  t.false(channel.unregisterDefaultCallback());
+  t.throws(() => channel.requestSync("concat", ""), { message: /unknown callback: `two`/ });
+  channel.close();
+});
+
+test("handles several calls sent by the child before it reads any responses", t => {
+  const channel = makeChannel();
+  channel.registerCallback("one", (_name, _message) => "one");
+  channel.registerCallback("two", (_name, _message) => "two");
+  channel.registerCallback("three", (_name, _message) => "three");
+  const response = channel.requestSync("batch", "");
+  t.is(response, "onetwothree");
+  channel.close();
+});
+
 test("throws if the child responds with an error", t => {
   const channel = makeChannel();
   t.throws(() => {
     channel.requestSync("error", "");
-  }, { code: "GenericFailure", message: '"something went wrong"' });
+  }, { code: "GenericFailure", message: /^request `error` failed after \d+ms: "something went wrong"$/ });
   channel.close();
 });
 
-test("throws if a callback throws", t => {
+test("throws errors with a code and data if the child sends them", t => {
   const channel = makeChannel();
-  channel.registerCallback("throw", () => { throw new Error("callback error") });
+  const err = t.throws(() => channel.requestSync("coded-error", ""), { code: "ENOENT", message: "no such file" });
+  t.deepEqual(err.data, { path: "/nope" });
+  channel.close();
+});
+
+test("tryRequestSync returns errors from the child instead of throwing", t => {
+  const channel = makeChannel();
+  t.deepEqual(channel.tryRequestSync("echo", '"hello"'), { ok: true, value: '"hello"' });
+  t.deepEqual(channel.tryRequestSync("error", ""), { ok: false, error: '"something went wrong"' });
+  channel.close();
+});
+
+test("throws if a callback throws and the child gives up on the request", t => {
+  const channel = makeChannel();
+  channel.registerCallback("echo", () => { throw new Error("callback error") });
   t.throws(() => {
-    channel.requestSync("throw", "");
+    channel.requestSync("callback-echo", "");
   }, { code: "GenericFailure", message: /callback error/ });
+  t.is(channel.requestSync("echo", '"hello"'), '"hello"');
+  channel.close();
+});
+
+test("throws naming the type of a non-string value returned by a callback", t => {
+  const channel = makeChannel();
+  channel.registerCallback("echo", () => undefined);
+  t.throws(() => channel.requestSync("callback-echo", ""), {
+    message: /callback `echo` returned a non-string value of type undefined/,
+  });
+  t.is(channel.stats().messagesSent[MessageType.CallError], 1);
+  channel.registerCallback("echo", () => 42);
+  t.throws(() => channel.requestSync("callback-echo", ""), {
+    message: /callback `echo` returned a non-string value of type number/,
+  });
+  t.is(channel.requestSync("echo", '"hello"'), '"hello"');
+  channel.close();
+});
+
+test("errors from the child say how long the request took to fail", t => {
+  const channel = makeChannel();
+  const fast = t.throws(() => channel.requestSync("coded-error", ""), { code: "ENOENT" });
+  t.is(typeof fast.elapsedMs, "number");
+  channel.registerCallback("echo", () => {
+    const start = Date.now();
+    while (Date.now() - start < 100) {}
+    throw new Error("callback error");
+  });
+  const slow = t.throws(() => channel.requestSync("callback-echo", ""), { code: "GenericFailure" });
+  t.true(slow.elapsedMs >= 100);
+  const remote = t.throws(() => channel.requestSync("error", ""), { code: "GenericFailure" });
+  t.regex(remote.message, /^request `error` failed after \d+ms: "something went wrong"$/);
+  t.is(typeof remote.elapsedMs, "number");
+  t.throws(() => channel.requestSync("exit", ""), {
+    message: /^request `exit` failed after \d+ms: child process closed the connection before responding to `exit`/,
+  });
+  channel.close();
+  const framed = new SyncRpcChannel("node", [join(__dirname, "../echo.mjs")], { checksum: "crc32" });
+  t.throws(() => framed.requestSync("corrupt", "hi"), {
+    code: "ERR_CHECKSUM",
+    message: /^request `corrupt` failed after \d+ms: checksum mismatch/,
+  });
+  framed.close();
+});
+
+test("succeeds if a callback throws and the child recovers", t => {
+  const channel = makeChannel();
+  channel.registerCallback("throw", () => { throw new Error("callback error") });
+  t.is(channel.requestSync("throw", ""), "recovered");
+  t.is(channel.stats().messagesSent[MessageType.CallError], 1);
+  channel.close();
+});
+
+test("throws if the child exits before responding", t => {
+  const channel = makeChannel();
+  t.throws(() => {
+    channel.requestSync("exit", "");
+  }, { code: "GenericFailure", message: /`exit`.*exit status: 3/ });
+  channel.close();
+});
+
+test("delivers a response the child wrote right before exiting", t => {
+  // Respond without reading the request, and exit straight away.
+  const script = `process.stdout.write(Buffer.from([0x93, 4, 0xc4, 4, ...Buffer.from("echo"), 0xc4, 2, ...Buffer.from("hi")]), () => process.exit(0))`;
+  for (let i = 0; i < 10; i++) {
+    const channel = new SyncRpcChannel("node", ["-e", script]);
+    t.is(channel.requestSync("echo", "x".repeat(1024 * 1024)), "hi");
+    channel.close();
+  }
+});
+
+test("exposes how the child exited", t => {
+  const channel = makeChannel();
+  t.is(channel.exitStatus(), null);
+  t.is(channel.signal(), null);
+  t.throws(() => channel.requestSync("exit", ""), { message: /exit status: 3/ });
+  t.is(channel.exitStatus(), 3);
+  t.is(channel.signal(), null);
+});
+
+test("exposes the signal that terminated the child", t => {
+  if (process.platform === "win32") {
+    t.pass();
+    return;
+  }
+  const channel = makeChannel();
+  channel.close();
+  sleep(100);
+  t.is(channel.exitStatus(), null);
+  t.is(channel.signal(), 9);
+});
+
+test("throws if a request does not complete before its timeout", t => {
+  const channel = makeChannel();
+  t.throws(() => {
+    channel.requestSyncTimeout("hang", "", 100);
+  }, { code: "GenericFailure", message: /^request `hang` failed after \d+ms: request to `hang` timed out after 100ms$/ });
+  channel.close();
+});
+
+test("request timeouts also cover slow callbacks", t => {
+  const channel = makeChannel();
+  channel.registerCallback("echo", (_name, message) => {
+    const start = Date.now();
+    while (Date.now() - start < 200) {}
+    return message;
+  });
+  t.throws(() => {
+    channel.requestSyncTimeout("callback-echo", '"hello"', 50);
+  }, { code: "GenericFailure", message: /timed out/ });
+  channel.close();
+});
+
+test("with request ids, a late response to a timed-out request is discarded", t => {
+  const channel = new SyncRpcChannel("node", [join(__dirname, "../echo.mjs")], { requestIds: true });
+  // The child responds to "delayed" after 50ms, so the first response
+  // arrives while the second request is waiting for its own.
+  t.throws(() => channel.requestSyncTimeout("delayed", "first", 10), {
+    message: /^request `delayed` failed after \d+ms: request to `delayed` timed out after 10ms$/,
+  });
+  t.is(channel.requestSync("delayed", "second"), "second");
+  t.is(channel.requestSync("echo", "third"), "third");
+  // This response doesn't echo the request's id.
+  channel.registerCallback("echo", (_name, message) => message);
+  t.throws(() => channel.requestSync("callback-echo", ""), { message: /has no `<id>`/ });
+  channel.close();
+});
+
+test("can resync a channel poisoned by a timeout once the child finishes", t => {
+  const channel = makeChannel();
+  t.true(channel.resync(0));
+  t.throws(() => channel.requestSyncTimeout("delayed", "late", 10), { message: /timed out/ });
+  t.throws(() => channel.requestSync("echo", "hi"), { message: /no longer usable/ });
+  t.true(channel.resync(1000));
+  t.is(channel.requestSync("echo", "hi"), "hi");
+  t.throws(() => channel.requestSyncTimeout("hang", "", 10), { message: /timed out/ });
+  t.false(channel.resync(50));
+  channel.close();
+});
+
+test("can't resync a channel poisoned by anything but a timeout", t => {
+  const channel = new SyncRpcChannel("node", [join(__dirname, "../echo.mjs")], { checksum: "crc32" });
+  t.throws(() => channel.requestSync("corrupt", "hi"), { message: /checksum mismatch/ });
+  t.throws(() => channel.resync(1000), { message: /cannot be resynchronized: checksum mismatch/ });
+  channel.close();
+});
+
+test("can send the child a deadline along with a request", t => {
+  const channel = makeChannel();
+  t.is(channel.requestSyncWithDeadline("deadline", "", 5000), "done");
+  t.throws(() => {
+    channel.requestSyncWithDeadline("deadline", "", 50);
+  }, { code: "GenericFailure", message: /^request `deadline` failed after \d+ms: Deadline exceeded$/ });
+  t.is(channel.requestSync("deadline", ""), "done");
+  channel.close();
+});
+
+test("waiting on a slow child does not busy-wait", t => {
+  const channel = makeChannel();
+  const before = process.cpuUsage();
+  t.throws(() => channel.requestSyncTimeout("hang", "", 1000), { message: /timed out/ });
+  const { user, system } = process.cpuUsage(before);
+  t.true(user + system < 250_000, `used ${(user + system) / 1000}ms of CPU`);
+  channel.close();
+});
+
+test("a timed out channel fails fast on subsequent requests", t => {
+  const channel = makeChannel();
+  t.throws(() => channel.requestSyncTimeout("hang", "", 50), { message: /timed out/ });
+  t.throws(() => {
+    channel.requestSync("echo", '"hello"');
+  }, { code: "GenericFailure", message: /no longer usable/ });
+  channel.close();
+});
+
+test("counts the messages of each type that cross the wire", t => {
+  const channel = makeChannel();
+  channel.registerCallback("one", (_name, _message) => "one");
+  channel.registerCallback("two", (_name, _message) => "two");
+  channel.registerCallback("three", (_name, _message) => "three");
+  channel.requestSync("concat", "");
+  const stats = channel.stats();
+  t.is(stats.messagesSent[MessageType.Request], 1);
+  t.is(stats.messagesSent[MessageType.CallResponse], 3);
+  t.is(stats.messagesReceived[MessageType.Call], 3);
+  t.is(stats.messagesReceived[MessageType.Response], 1);
+  t.is(stats.messagesReceived[MessageType.Error], 0);
+  channel.close();
+});
+
+test("keeps totals of requests, response bytes, callbacks and errors", t => {
+  const channel = makeChannel();
+  channel.registerCallback("echo", (_name, message) => message);
+  channel.requestSync("echo", "hello");
+  channel.requestSync("callback-echo", "hi");
+  t.throws(() => channel.requestSync("error", ""));
+  let stats = channel.stats();
+  t.is(stats.requests, 3);
+  t.is(stats.responseBytes, 7);
+  t.is(stats.callbacks, 1);
+  t.is(stats.errors, 1);
+  channel.resetStats();
+  stats = channel.stats();
+  t.is(stats.requests, 0);
+  t.is(stats.responseBytes, 0);
+  t.is(stats.messagesSent[MessageType.Request], 0);
+  channel.requestSync("echo", "again");
+  t.is(channel.stats().requests, 1);
+  channel.close();
+});
+
+test("can check whether the child is alive and responsive", t => {
+  const channel = makeChannel();
+  t.true(channel.isAlive());
+  t.true(channel.ping(5000));
+  t.is(channel.requestSync("echo", '"hello"'), '"hello"');
+  channel.close();
+  sleep(100);
+  t.false(channel.isAlive());
+});
+
+test("a timed out ping skips its late response", t => {
+  // Respond to the ping late, and then to the next request.
+  const frames = [0x93, 4, 0xc4, 6, ...Buffer.from("$/ping"), 0xc4, 0, 0x93, 4, 0xc4, 4, ...Buffer.from("echo"), 0xc4, 2, ...Buffer.from("hi")];
+  const script = `setTimeout(() => process.stdout.write(Buffer.from(${JSON.stringify(frames)})), 300); setInterval(() => {}, 1000)`;
+  const channel = new SyncRpcChannel("node", ["-e", script]);
+  t.false(channel.ping(50));
+  t.is(channel.requestSync("echo", ""), "hi");
   channel.close();
 });
 
+test("can negotiate a protocol version with the child", t => {
+  let channel = makeChannel();
+  t.is(channel.protocolVersion(), null);
+  channel.close();
+  channel = new SyncRpcChannel("node", [join(__dirname, "../echo.mjs")], { handshake: "required" });
+  t.is(channel.protocolVersion(), 1);
+  t.is(channel.requestSync("echo", '"hello"'), '"hello"');
+  channel.close();
+});
+
+test("can run children that don't support the handshake in legacy mode", t => {
+  // Reject the handshake like an unknown method, then keep running.
+  const frames = [0x93, 5, 0xc4, 11, ...Buffer.from("$/handshake"), 0xc4, 7, ...Buffer.from("unknown")];
+  const script = `process.stdout.write(Buffer.from(${JSON.stringify(frames)})); setInterval(() => {}, 1000)`;
+  const channel = new SyncRpcChannel("node", ["-e", script], { handshake: "optional" });
+  t.is(channel.protocolVersion(), null);
+  channel.close();
+  t.throws(() => {
+    new SyncRpcChannel("node", ["-e", script], { handshake: "required" });
+  }, { message: "child process does not support the protocol handshake: unknown" });
+});
+
+test("rejects children that speak an incompatible protocol version", t => {
+  const frames = [0x93, 4, 0xc4, 11, ...Buffer.from("$/handshake"), 0xc4, 1, ...Buffer.from("0")];
+  const script = `process.stdout.write(Buffer.from(${JSON.stringify(frames)})); setInterval(() => {}, 1000)`;
+  t.throws(() => {
+    new SyncRpcChannel("node", ["-e", script], { handshake: "optional" });
+  }, { message: /speaks protocol version 0, but at least version 1 is required/ });
+});
+
+test("compressed and uncompressed payloads interleave on the same connection", t => {
+  const channel = new SyncRpcChannel("node", [join(__dirname, "../echo.mjs")], { compression: "zstd", compressionThreshold: 64 });
+  // The child only supports compression if this version of node has zstd.
+  t.is(channel.compression(), zlib.zstdCompressSync ? "zstd" : null);
+  channel.registerCallback("echo", (_name, payload) => payload);
+  const small = '"small"';
+  const large = JSON.stringify("large".repeat(10000));
+  for (let i = 0; i < 3; i++) {
+    t.is(channel.requestSync("echo", small), small);
+    t.is(channel.requestSync("echo", large), large);
+    t.is(channel.requestSync("callback-echo", large), large);
+    t.is(channel.requestSync("callback-echo", small), small);
+  }
+  channel.close();
+});
+
+test("checksums every message once the child agrees to it", t => {
+  const channel = new SyncRpcChannel("node", [join(__dirname, "../echo.mjs")], { checksum: "crc32", compression: "zstd" });
+  t.is(channel.checksum(), "crc32");
+  channel.registerCallback("echo", (_name, payload) => payload);
+  const large = JSON.stringify("large".repeat(10000));
+  t.is(channel.requestSync("echo", '"small"'), '"small"');
+  t.is(channel.requestSync("callback-echo", large), large);
+  t.throws(() => channel.requestSync("corrupt", '"hello"'), {
+    code: "ERR_CHECKSUM",
+    message: /^request `corrupt` failed after \d+ms: checksum mismatch: message carries [0-9a-f]{8}, but its contents hash to [0-9a-f]{8}$/,
+  });
+  t.throws(() => channel.requestSync("echo", '"hello"'), { message: /channel is no longer usable: checksum mismatch/ });
+  channel.close();
+});
+
+test("can wait for the child to create a readiness file", t => {
+  const readyFile = join(mkdtempSync(join(tmpdir(), "libsyncrpc-")), "ready");
+  const channel = new SyncRpcChannel("node", [join(__dirname, "../echo.mjs"), readyFile]);
+  channel.waitReadyFile(readyFile, 10000);
+  t.true(existsSync(readyFile));
+  t.is(channel.requestSync("echo", '"hello"'), '"hello"');
+  channel.close();
+});
+
+test("throws if the readiness file does not appear in time", t => {
+  const readyFile = join(mkdtempSync(join(tmpdir(), "libsyncrpc-")), "never");
+  const channel = makeChannel();
+  t.throws(() => {
+    channel.waitReadyFile(readyFile, 50);
+  }, { code: "GenericFailure", message: /did not create readiness file/ });
+  channel.close();
+});
+
+test("exposes the child's process ID", t => {
+  const channel = makeChannel();
+  const pid = channel.pid();
+  t.true(pid > 0);
+  t.notThrows(() => process.kill(pid, 0));
+  channel.close();
+  t.is(channel.pid(), pid);
+});
+
+test("reaps idle children and respawns them on the next request", t => {
+  const channel = makeChannel();
+  channel.registerCallback("echo", (_name, message) => message);
+  t.is(channel.requestSync("echo", '"hello"'), '"hello"');
+  const pid = channel.pid();
+  channel.setIdleTimeout(100);
+  sleep(500);
+  t.throws(() => process.kill(pid, 0));
+  t.is(channel.requestSync("callback-echo", '"hello"'), '"hello"');
+  t.not(channel.pid(), pid);
+  channel.close();
+});
+
+test("can spawn the child in a given working directory", t => {
+  const cwd = mkdtempSync(join(tmpdir(), "libsyncrpc-"));
+  const channel = new SyncRpcChannel("node", [join(__dirname, "../echo.mjs")], { cwd });
+  t.is(realpathSync(channel.requestSync("cwd", "")), realpathSync(cwd));
+  channel.close();
+});
+
+test("throws a clear error for a nonexistent working directory", t => {
+  t.throws(() => {
+    new SyncRpcChannel("node", [join(__dirname, "../echo.mjs")], { cwd: join(__dirname, "nope") });
+  }, { code: "GenericFailure", message: /working directory `.*nope` does not exist/ });
+});
+
+test("can set and clear the child's environment variables", t => {
+  process.env.LIBSYNCRPC_INHERITED = "inherited";
+  let channel = new SyncRpcChannel("node", [join(__dirname, "../echo.mjs")], { env: { LIBSYNCRPC_TOKEN: "secret" } });
+  t.is(channel.requestSync("env", "LIBSYNCRPC_TOKEN"), '"secret"');
+  t.is(channel.requestSync("env", "LIBSYNCRPC_INHERITED"), '"inherited"');
+  channel.close();
+  channel = new SyncRpcChannel(process.execPath, [join(__dirname, "../echo.mjs")], { env: { LIBSYNCRPC_TOKEN: "secret" }, clearEnv: true });
+  t.is(channel.requestSync("env", "LIBSYNCRPC_TOKEN"), '"secret"');
+  t.is(channel.requestSync("env", "LIBSYNCRPC_INHERITED"), "null");
+  channel.close();
+});
+
+test("closeGraceful lets the child exit on its own", t => {
+  const channel = makeChannel();
+  const pid = channel.pid();
+  t.true(channel.closeGraceful(5000));
+  t.throws(() => process.kill(pid, 0));
+});
+
+test("closeGraceful terminates children that don't exit in time", t => {
+  const channel = new SyncRpcChannel("node", ["-e", "setInterval(() => {}, 1000)"]);
+  const pid = channel.pid();
+  t.false(channel.closeGraceful(100));
+  t.throws(() => process.kill(pid, 0));
+});
+
+test("can forward the child's stderr to a callback, line by line", async t => {
+  const lines = [];
+  const channel = new SyncRpcChannel("node", [join(__dirname, "../echo.mjs")], { stderr: line => lines.push(line) });
+  channel.requestSync("stderr", "");
+  await new Promise(resolve => setTimeout(resolve, 200));
+  t.deepEqual(lines, ["first line", "second line"]);
+  channel.close();
+});
+
+test("fails requests once the child writes to stderr with stderrIsFatal", async t => {
+  const lines = [];
+  const channel = new SyncRpcChannel("node", [join(__dirname, "../echo.mjs")], {
+    stderr: line => lines.push(line),
+    stderrIsFatal: true,
+  });
+  t.is(channel.requestSync("echo", "ok"), "ok");
+  // The output may arrive before or after the response, failing either this
+  // request or the next.
+  const messages = [];
+  for (const method of ["stderr", "echo"]) {
+    try {
+      channel.requestSync(method, "");
+    } catch (e) {
+      messages.push(e.message);
+    }
+    sleep(100);
+  }
+  t.regex(messages.join("\n"), /^request `(stderr|echo)` failed after \d+ms: child process wrote to stderr:\nfirst line\nsecond line/);
+  t.is(channel.requestSync("echo", "ok"), "ok");
+  await new Promise(resolve => setTimeout(resolve, 100));
+  t.deepEqual(lines, ["first line", "second line"]);
+  channel.close();
+  t.throws(() => {
+    new SyncRpcChannel("node", [join(__dirname, "../echo.mjs")], { stderr: "ignore", stderrIsFatal: true });
+  }, { message: /can't be combined with `stderr: "ignore"`/ });
+});
+
+test("rejects invalid stderr options", t => {
+  t.throws(() => {
+    new SyncRpcChannel("node", [join(__dirname, "../echo.mjs")], { stderr: "bogus" });
+  }, { message: /invalid `stderr` option `bogus`/ });
+});
+
+test("can write a JSON Lines trace of every message", t => {
+  const traceFile = join(mkdtempSync(join(tmpdir(), "libsyncrpc-")), "trace.jsonl");
+  const channel = new SyncRpcChannel("node", [join(__dirname, "../echo.mjs")], { traceFile, tracePayloadHashes: true });
+  channel.registerCallback("one", (_name, _message) => "one");
+  channel.registerCallback("two", (_name, _message) => "two");
+  channel.registerCallback("three", (_name, _message) => "three");
+  channel.requestSync("concat", "");
+  channel.close();
+  const records = readFileSync(traceFile, "utf8").trim().split("\n").map(line => JSON.parse(line));
+  t.deepEqual(records.map(r => [r.dir, r.type, r.name]), [
+    ["send", MessageType.Request, "concat"],
+    ["recv", MessageType.Call, "one"],
+    ["send", MessageType.CallResponse, "one"],
+    ["recv", MessageType.Call, "two"],
+    ["send", MessageType.CallResponse, "two"],
+    ["recv", MessageType.Call, "three"],
+    ["send", MessageType.CallResponse, "three"],
+    ["recv", MessageType.Response, "concat"],
+  ]);
+  t.is(records[0].typeName, "Request");
+  t.is(records[2].len, 3);
+  t.regex(records[2].hash, /^[0-9a-f]{16}$/);
+  t.true(records.every(r => typeof r.ts === "number"));
+});
+
+test("applies the default timeout to requests that don't set their own", t => {
+  const channel = new SyncRpcChannel("node", [join(__dirname, "../echo.mjs")], { requestIds: true });
+  channel.setDefaultTimeout(10);
+  t.throws(() => channel.requestSync("delayed", "late"), { message: /timed out after 10ms/ });
+  t.throws(() => channel.requestBinarySync("delayed", new Uint8Array([1])), { message: /timed out after 10ms/ });
+  t.is(channel.requestSyncTimeout("delayed", "overridden", 1000), "overridden");
+  channel.setDefaultTimeout(null);
+  t.is(channel.requestSync("delayed", "on time"), "on time");
+  channel.close();
+});
+
+test("can start and stop tracing, with payload previews", t => {
+  const traceFile = join(mkdtempSync(join(tmpdir(), "libsyncrpc-")), "trace.jsonl");
+  const channel = new SyncRpcChannel("node", [join(__dirname, "../echo.mjs")], { tracePreviewBytes: 4 });
+  channel.requestSync("echo", "untraced");
+  channel.setTraceFile(traceFile);
+  // The preview stops short of a character cut in half.
+  channel.requestSync("echo", "h\u00e9\u00e9");
+  channel.requestBinarySync("echo", new Uint8Array([0xff, 0x00, 0x01, 0x02, 0x03]));
+  channel.setTraceFile(null);
+  channel.requestSync("echo", "untraced");
+  channel.close();
+  const records = readFileSync(traceFile, "utf8").trim().split("\n").map(line => JSON.parse(line));
+  t.deepEqual(records.map(r => [r.dir, r.preview, r.previewHex]), [
+    ["send", "h\u00e9", undefined],
+    ["recv", "h\u00e9", undefined],
+    ["send", undefined, "ff000102"],
+    ["recv", undefined, "ff000102"],
+  ]);
+});
+
+test("rejects arguments containing NUL bytes", t => {
+  t.throws(() => {
+    new SyncRpcChannel("node", ["ok", "not\0ok"]);
+  }, { code: "GenericFailure", message: "argument 1 contains an invalid NUL byte" });
+});
+
 // function makeChannel() {
 //   return new SyncRpcChannel("cargo", ["run", "--release", "--example", "socket_child"]);
 // }
 
 function makeChannel() {
   return new SyncRpcChannel("node", [join(__dirname, "../echo.mjs")]);
-}
\ No newline at end of file
+}
+
+function sleep(ms) {
+  Atomics.wait(new Int32Array(new SharedArrayBuffer(4)), 0, 0, ms);
+}
+
+test("async callbacks can await a promise before returning during requestAsync", async t => {
+  const channel = makeChannel();
+  channel.registerAsyncCallback("echo", async (name, payload) => {
+    await new Promise(resolve => setTimeout(resolve, 10));
+    return `${name}:${payload}`;
+  });
+  const response = channel.requestAsync("callback-echo", "hello");
+  t.throws(() => channel.requestSync("echo", "nope"), { message: /busy/ });
+  t.is(await response, "echo:hello");
+  t.is(channel.requestSync("echo", "again"), "again");
+  t.throws(() => channel.requestSync("callback-echo", "hello"), { message: /asynchronous/ });
+  channel.close();
+});
+
+test("requestAsync rejects if an async callback rejects or a sync callback is invoked", async t => {
+  const channel = makeChannel();
+  channel.registerAsyncCallback("echo", async () => {
+    throw new Error("nope");
+  });
+  await t.throwsAsync(channel.requestAsync("callback-echo", "hello"), { message: /nope/ });
+  channel.close();
+  const other = makeChannel();
+  other.registerCallback("echo", (_name, payload) => payload);
+  await t.throwsAsync(other.requestAsync("callback-echo", "hello"), { message: /synchronous/ });
+  other.close();
+});
+
+test("can batch requests and get their results in order", t => {
+  const channel = makeChannel();
+  const encode = s => new TextEncoder().encode(s);
+  const results = channel.requestBatchSync([
+    { method: "delayed", payload: encode("first") },
+    { method: "echo", payload: encode("second") },
+    { method: "error", payload: encode("") },
+  ]);
+  t.is(results.length, 3);
+  t.true(results[0].ok);
+  t.is(new TextDecoder().decode(results[0].value), "first");
+  t.true(results[1].ok);
+  t.is(new TextDecoder().decode(results[1].value), "second");
+  t.false(results[2].ok);
+  t.is(results[2].error, '"something went wrong"');
+  t.deepEqual(channel.requestBatchSync([]), []);
+  t.is(channel.requestSync("echo", "after"), "after");
+  channel.close();
+});
+
+test("can cancel a request from within a callback", async t => {
+  const channel = makeChannel();
+  const handle = channel.cancellationHandle();
+  channel.registerCallback("check-in", () => {
+    handle.cancel();
+    return "";
+  });
+  t.throws(() => channel.requestSync("cancellable", ""), { code: "ECANCELED", message: "request was cancelled" });
+  channel.registerCallback("check-in", () => "");
+  t.is(channel.requestSync("cancellable", ""), "done");
+  channel.registerAsyncCallback("check-in", async () => {
+    handle.cancel();
+    return "";
+  });
+  await t.throwsAsync(channel.requestAsync("cancellable", ""), { code: "ECANCELED" });
+  channel.close();
+});
+
+test("cancels the request in progress by its id with requestIds", t => {
+  const channel = new SyncRpcChannel("node", [join(__dirname, "../echo.mjs")], { requestIds: true });
+  const handle = channel.cancellationHandle();
+  channel.registerCallback("check-in", () => {
+    handle.cancel();
+    return "";
+  });
+  t.is(channel.requestSync("echo", "first"), "first");
+  t.throws(() => channel.requestSync("cancellable", ""), { code: "ECANCELED" });
+  channel.close();
+});
+
+test("rejects messages longer than maxPayloadLength", t => {
+  const channel = new SyncRpcChannel("node", [join(__dirname, "../echo.mjs")], { maxPayloadLength: 4 });
+  t.is(channel.requestSync("echo", "abcd"), "abcd");
+  t.throws(() => channel.requestSync("echo", "abcde"), {
+    code: "ERR_PAYLOAD_TOO_LARGE",
+    message: /^request `echo` failed after \d+ms: message item of 5 bytes exceeds the maximum length of 4 bytes$/,
+  });
+  t.throws(() => channel.requestSync("echo", "ab"), { message: /no longer usable: message item of 5 bytes/ });
+  channel.close();
+});
+
+test("parses message types from their byte values", t => {
+  t.is(messageTypeFromU8(1), MessageType.Request);
+  t.is(messageTypeFromU8(9), MessageType.Cancel);
+  t.throws(() => messageTypeFromU8(0), { message: "Invalid message type: 0" });
+  t.throws(() => messageTypeFromU8(MessageType._UnusedPlaceholderVariant), {
+    message: /Invalid message type/,
+  });
+});
+
+test("restarts a child that exits, as allowed by the restart policy", t => {
+  const channel = new SyncRpcChannel("node", [join(__dirname, "../echo.mjs")], {
+    restartPolicy: { maxRestarts: 1 },
+  });
+  channel.registerCallback("echo", (_name, message) => message);
+  const pid = channel.pid();
+  process.kill(pid, "SIGKILL");
+  t.is(channel.requestSync("callback-echo", "hi"), "hi");
+  t.is(channel.restartCount(), 1);
+  t.not(channel.pid(), pid);
+  process.kill(channel.pid(), "SIGKILL");
+  t.throws(() => channel.requestSync("echo", '"hello"'), {
+    message: /all 1 restarts allowed by the restart policy were used/,
+  });
+  t.is(channel.restartCount(), 1);
+  channel.close();
+});
+
+test("can respawn the child, keeping callbacks and optionally switching executables", t => {
+  const channel = makeChannel();
+  channel.registerCallback("echo", (_name, message) => message);
+  const pid = channel.pid();
+  channel.respawn();
+  t.not(channel.pid(), pid);
+  t.is(channel.requestSync("callback-echo", "hi"), "hi");
+  t.throws(() => channel.respawn("does-not-exist"));
+  t.throws(() => channel.requestSync("echo", '"hello"'), { message: /failed to respawn child process/ });
+  channel.respawn("node");
+  t.is(channel.requestSync("callback-echo", "again"), "again");
+  channel.close();
+  channel.respawn();
+  t.is(channel.requestSync("echo", '"reopened"'), '"reopened"');
+  channel.close();
+});
+
+test("doesn't deadlock when the child floods stdout without reading stdin", t => {
+  // Writes its whole response with a blocking write before reading any of
+  // the request, which can only finish if the parent drains the response
+  // while it is still writing the request.
+  const child = `
+    const { readSync, writeSync } = require("node:fs");
+    const len = 8 * 1024 * 1024;
+    const header = Buffer.from([0x93, 0x04, 0xc4, 0x05, ...Buffer.from("flood"), 0xc6, 0, 0, 0, 0]);
+    header.writeUInt32BE(len, header.length - 4);
+    writeSync(1, Buffer.concat([header, Buffer.alloc(len, "x")]));
+    const buf = Buffer.alloc(64 * 1024);
+    for (let read = 0; read < len; ) {
+      read += readSync(0, buf);
+    }
+  `;
+  const channel = new SyncRpcChannel("node", ["-e", child]);
+  const response = channel.requestSync("flood", "y".repeat(8 * 1024 * 1024));
+  t.is(response.length, 8 * 1024 * 1024);
+  channel.close();
+});
+
+test("times out sending a request to a child that doesn't read its stdin", t => {
+  const channel = new SyncRpcChannel("node", ["-e", "setInterval(() => {}, 1000)"]);
+  t.throws(() => channel.requestSyncTimeout("echo", "x".repeat(8 * 1024 * 1024), 100), {
+    message: /^request `echo` failed after \d+ms: timed out waiting for the child to read its stdin$/,
+  });
+  t.throws(() => channel.requestSync("echo", "x"), {
+    message: /^request `echo` failed after \d+ms: channel is no longer usable: timed out waiting for the child to read its stdin$/,
+  });
+  channel.close();
+});
+
+test("a pool routes requests to its children in turn, with shared callbacks", t => {
+  const pool = new SyncRpcPool("node", [join(__dirname, "../echo.mjs")], 2);
+  t.is(pool.size(), 2);
+  const pids = pool.pids().map(String);
+  t.deepEqual([1, 2, 3].map(() => pool.requestSyncAny("pid", "")), [pids[0], pids[1], pids[0]]);
+  pool.registerCallback("echo", (name, payload) => `${name}:${payload}`);
+  t.deepEqual(pool.requestSyncAll("callback-echo", "hi"), [
+    { ok: true, value: "echo:hi" },
+    { ok: true, value: "echo:hi" },
+  ]);
+  t.true(pool.unregisterCallback("echo"));
+  t.false(pool.unregisterCallback("echo"));
+  pool.close();
+});
+
+test("a pool broadcast reports a child that exited without losing the others' responses", t => {
+  const pool = new SyncRpcPool("node", [join(__dirname, "../echo.mjs")], 3);
+  process.kill(pool.pids()[1], "SIGKILL");
+  const [first, second, third] = pool.requestSyncAll("echo", '"hello"');
+  t.deepEqual(first, { ok: true, value: '"hello"' });
+  t.false(second.ok);
+  t.regex(second.error, /closed the connection before responding to `echo`/);
+  t.deepEqual(third, { ok: true, value: '"hello"' });
+  const errors = pool.requestSyncAll("error", "");
+  t.deepEqual(errors.map(res => res.ok), [false, false, false]);
+  t.is(errors[0].error, '"something went wrong"');
+  t.is(errors[2].error, '"something went wrong"');
+  pool.close();
+});
+
+test("a pool needs at least one child", t => {
+  t.throws(() => new SyncRpcPool("node", [join(__dirname, "../echo.mjs")], 0), {
+    message: "a pool needs at least one child",
+  });
+});
+
+test("retries a request only once after restarting the child", t => {
+  const channel = new SyncRpcChannel("node", [join(__dirname, "../echo.mjs")], {
+    restartPolicy: { maxRestarts: 5 },
+  });
+  t.throws(() => channel.requestSync("exit", ""), { message: /exit status: 3/ });
+  t.is(channel.restartCount(), 1);
+  t.is(channel.requestSync("echo", '"hello"'), '"hello"');
+  t.is(channel.restartCount(), 2);
+  channel.close();
+  t.throws(() => channel.requestSync("echo", '"hello"'));
+  t.is(channel.restartCount(), 2);
+});
+
+test("terminate sends SIGTERM before killing the child", t => {
+  if (process.platform === "win32") {
+    t.pass();
+    return;
+  }
+  const channel = makeChannel();
+  t.true(channel.terminate(1000));
+  t.is(channel.signal(), 15);
+
+  const stubborn = new SyncRpcChannel("node", [
+    "-e",
+    "process.on('SIGTERM', () => {}); setInterval(() => {}, 1000)",
+  ]);
+  sleep(200);
+  t.false(stubborn.terminate(100));
+  t.is(stubborn.signal(), 9);
+});
+
+test("accepts custom read and write buffer sizes", t => {
+  const channel = new SyncRpcChannel("node", [join(__dirname, "../echo.mjs")], {
+    readBufferSize: 16,
+    writeBufferSize: 1024 * 1024,
+  });
+  const payload = "x".repeat(100_000);
+  t.is(channel.requestSync("echo", payload), payload);
+  channel.close();
+  t.throws(
+    () => new SyncRpcChannel("node", [join(__dirname, "../echo.mjs")], { readBufferSize: 0 }),
+    { message: /readBufferSize/ },
+  );
+});
+
+test("names the command that failed to spawn", t => {
+  t.throws(() => new SyncRpcChannel("./does-not-exist", ["--flag"]), {
+    message: /^failed to spawn `\.\/does-not-exist --flag`: /,
+  });
+});
+
+test("kills the child of a channel that is garbage collected", t => {
+  // The child would also exit once this process does, so check on it from
+  // within the process that dropped the channel.
+  const script = `
+    const { SyncRpcChannel } = require(${JSON.stringify(join(__dirname, "../index.js"))});
+    let channel = new SyncRpcChannel("node", [${JSON.stringify(join(__dirname, "../echo.mjs"))}]);
+    const pid = channel.pid();
+    channel = null;
+    global.gc();
+    setTimeout(() => {
+      global.gc();
+      try {
+        process.kill(pid, 0);
+        console.log("alive");
+      } catch {
+        console.log("dead");
+      }
+    }, 100);
+  `;
+  const output = execFileSync("node", ["--expose-gc", "-e", script], { encoding: "utf8" });
+  t.is(output.trim(), "dead");
+});
+
+test("forwards log messages from the child to the log option", async t => {
+  const logged = [];
+  const channel = new SyncRpcChannel("node", [join(__dirname, "../echo.mjs")], {
+    log: message => logged.push(message),
+  });
+  channel.registerCallback("echo", (_name, message) => message);
+  t.is(channel.requestSync("log", "hi"), "hi");
+  t.is(channel.stats().messagesReceived[MessageType.Log], 2);
+  // Log messages are delivered through the event loop.
+  await new Promise(resolve => setTimeout(resolve, 50));
+  t.deepEqual(logged, ["starting", "finishing"]);
+  channel.close();
+
+  const quiet = makeChannel();
+  quiet.registerCallback("echo", (_name, message) => message);
+  t.is(quiet.requestSync("log", "hi"), "hi");
+  quiet.close();
+});
+
+test("passes notifications from the child to the onNotify handler", async t => {
+  const notified = [];
+  const channel = makeChannel();
+  channel.onNotify((name, payload) => notified.push([name, payload]));
+  t.is(channel.requestSync("notify", "hi"), "hi");
+  // The notification after the response is picked up along with the idle one.
+  t.is(channel.pumpNotifications(200), 2);
+  t.is(channel.stats().messagesReceived[MessageType.Notify], 3);
+  // Notifications are delivered through the event loop.
+  await new Promise(resolve => setTimeout(resolve, 50));
+  t.deepEqual(notified, [
+    ["progress", "started"],
+    ["progress", "done"],
+    ["idle", "hi"],
+  ]);
+
+  channel.onNotify(null);
+  t.is(channel.requestSync("notify", "bye"), "bye");
+  t.is(channel.pumpNotifications(200), 2);
+  await new Promise(resolve => setTimeout(resolve, 50));
+  t.is(notified.length, 3);
+  t.is(channel.pumpNotifications(0), 0);
+  channel.close();
+});
+
+test("runs a single request against a one-shot child", t => {
+  const child = join(__dirname, "../oneshot.mjs");
+  const payload = new Uint8Array([0x00, 0xff, 0x68, 0x69]);
+  t.deepEqual(requestOneshot("node", [child], "echo", payload), payload);
+  t.throws(() => requestOneshot("node", [child], "fail", new Uint8Array()), {
+    message: /^failed \(exit (status|code): 2\), stderr:\nsomething went wrong$/,
+  });
+  t.throws(() => requestOneshot("node", [child], "silent", new Uint8Array()), {
+    message: /^child process closed the connection before responding to `silent` \(exit (status|code): 3\)$/,
+  });
+  t.throws(() => requestOneshot("./does-not-exist", [], "echo", new Uint8Array()), {
+    message: /failed to spawn `\.\/does-not-exist`/,
+  });
+});
+
+test("can attach to a process it didn't spawn", async t => {
+  if (process.platform === "win32") {
+    t.pass();
+    return;
+  }
+  const dir = mkdtempSync(join(tmpdir(), "libsyncrpc-"));
+  const input = join(dir, "in");
+  const output = join(dir, "out");
+  execFileSync("mkfifo", [input, output]);
+  const child = spawn("sh", ["-c", 'exec node "$0" < "$1" > "$2"', join(__dirname, "../echo.mjs"), input, output], {
+    stdio: "inherit",
+  });
+  const exited = once(child, "exit");
+  // Opening either end of a FIFO blocks until the other end is opened.
+  const channel = SyncRpcChannel.fromRaw(openSync(input, "w"), openSync(output, "r"));
+  t.is(channel.requestSync("echo", "hi"), "hi");
+  t.throws(() => channel.pid(), { message: /`pid` is unavailable for a channel created with `fromRaw`/ });
+  t.throws(() => channel.setIdleTimeout(10), { message: /`setIdleTimeout` is unavailable/ });
+  // Closing the channel leaves the process running, until it sees EOF.
+  channel.close();
+  t.throws(() => channel.requestSync("echo", "hi"), { message: /channel is no longer usable: the channel was closed/ });
+  const [, signal] = await exited;
+  t.is(signal, null);
+});
+
+test("leaves a good descriptor open if the other passed to fromRaw is bad", t => {
+  if (process.platform === "win32") {
+    t.pass();
+    return;
+  }
+  const dir = mkdtempSync(join(tmpdir(), "libsyncrpc-"));
+  const good = openSync(join(dir, "good"), "w");
+  t.throws(() => SyncRpcChannel.fromRaw(good, 1_000_000), {
+    message: "`stdoutFd` is not an open file descriptor: 1000000",
+  });
+  t.notThrows(() => fstatSync(good));
+  closeSync(good);
+});
+
+test("passes inherited file descriptors to the child", t => {
+  if (process.platform === "win32") {
+    t.throws(() => new SyncRpcChannel("node", ["-e", ""], { inheritFds: [3] }), {
+      message: /only supported on Unix/,
+    });
+    return;
+  }
+  const dir = mkdtempSync(join(tmpdir(), "libsyncrpc-"));
+  const first = openSync(join(dir, "first"), "w");
+  const second = openSync(join(dir, "second"), "w");
+  const script = `
+    const fds = process.env.LIBSYNCRPC_INHERITED_FDS.split(",").map(Number);
+    require("fs").writeSync(fds[0], "first");
+    require("fs").writeSync(fds[1], "second");
+  `;
+  const channel = new SyncRpcChannel("node", ["-e", script], { inheritFds: [first, second] });
+  while (channel.isAlive()) {
+    sleep(10);
+  }
+  closeSync(first);
+  closeSync(second);
+  t.is(channel.exitStatus(), 0);
+  t.is(readFileSync(join(dir, "first"), "utf8"), "first");
+  t.is(readFileSync(join(dir, "second"), "utf8"), "second");
+  t.throws(() => new SyncRpcChannel("node", ["-e", ""], { inheritFds: [1] }), {
+    message: /cannot pass descriptor 1/,
+  });
+});
+
+test("round-trips values encoded as CBOR", t => {
+  const channel = makeChannel();
+  const value = { a: [1, -2, 300, -70000, 2 ** 40, 1.5, "héllo"], b: { c: null, d: true, e: false }, f: "" };
+  t.deepEqual(channel.requestCbor("echo", value), value);
+  t.deepEqual(channel.requestCbor("cbor-fixed", null), { a: [1, -2, "x", true, null, 1.5] });
+  t.throws(() => channel.requestCbor("cbor-truncated", null), {
+    message: "Error while decoding response as CBOR: unexpected end of input at byte offset 2",
+  });
+  channel.close();
+});
+
+test("reports requests, responses, errors and callbacks to the observer", t => {
+  const channel = makeChannel();
+  const events = [];
+  channel.setObserver((event, name, bytes) => events.push([event, name, bytes]));
+  channel.registerCallback("echo", (_, payload) => payload + payload);
+  t.is(channel.requestSync("callback-echo", "abc"), "abcabc");
+  t.throws(() => channel.requestSync("error", ""));
+  t.deepEqual(events, [
+    ["request", "callback-echo", 3],
+    ["callback", "echo", 3],
+    ["response", "callback-echo", 6],
+    ["request", "error", 0],
+    ["error", "error", 22],
+  ]);
+  channel.setObserver(() => {
+    throw new Error("observer failed");
+  });
+  t.is(channel.requestSync("echo", "still works"), "still works");
+  channel.setObserver(null);
+  events.length = 0;
+  channel.requestSync("echo", "");
+  t.deepEqual(events, []);
+  channel.close();
+});
+
+test("tags responses as text or binary depending on whether they are valid UTF-8", t => {
+  const channel = makeChannel();
+  t.deepEqual(channel.requestAutoSync("echo", new TextEncoder().encode("héllo")), { type: "text", value: "héllo" });
+  const binary = new Uint8Array([0x68, 0x69, 0xff, 0x00]);
+  const res = channel.requestAutoSync("echo", binary);
+  t.is(res.type, "binary");
+  t.deepEqual(res.value, binary);
+  channel.close();
+});
+
+test("accepts responses under any name without strictResponseNames", t => {
+  const strict = makeChannel();
+  t.throws(() => strict.requestSync("renamed", "other"), {
+    code: "ERR_NAME_MISMATCH",
+    message: /name mismatch for response: expected `renamed`, got `other`/,
+  });
+  strict.close();
+  const lax = new SyncRpcChannel("node", [join(__dirname, "../echo.mjs")], { strictResponseNames: false });
+  t.is(lax.requestSync("renamed", "other"), "other");
+  t.is(lax.requestSync("echo", "still in sync"), "still in sync");
+  lax.close();
+});
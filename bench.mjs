@@ -3,15 +3,30 @@ import { SyncRpcChannel } from "./index.js";
 
 const rustChannel = new SyncRpcChannel("cargo", ["run", "--release", "--example", "socket_child"])
 const nodeChannel = new SyncRpcChannel("node", ["./echo.mjs"])
+const bufferedNodeChannel = new SyncRpcChannel("node", ["./echo.mjs"], {
+    readBufferSize: 1024 * 1024,
+    writeBufferSize: 1024 * 1024,
+})
 const bench = new Bench();
 
 const ENCODER = new TextEncoder();
 const smallMsg = ENCODER.encode('"hello"');
 const bigMsg = new Uint8Array(1024 * 1024);
+const tenMiBMsg = new Uint8Array(10 * 1024 * 1024);
+const sixtyFourMiBBuf = Buffer.alloc(64 * 1024 * 1024);
 const hugeMsg = new Uint8Array(1024 * 1024 * 1024);
 const smallStr = '"hello"';
 const bigStr = "x".repeat(1024 * 1024);
 
+// Peak memory growth of a single 64MiB echo, measured before the benchmarks
+// grow the heap. Since the response isn't copied, this should stay close to
+// the size of the request (which is first touched when it is sent) plus that
+// of the response, or 128MiB, where a copy would add another 64MiB.
+const maxRssBefore = process.resourceUsage().maxRSS;
+nodeChannel.requestBufferSync("echo", sixtyFourMiBBuf);
+const maxRssGrowthMiB = (process.resourceUsage().maxRSS - maxRssBefore) / 1024;
+console.log(`peak RSS growth for a 64MiB response: ${maxRssGrowthMiB.toFixed(1)}MiB`);
+
 bench
     .add('simple echo request to Rust child', () => {
         rustChannel.requestSync("echo", smallStr);
@@ -40,6 +55,15 @@ bench
     .add('simple binary echo request to Node child with a bigger 1MiB message', () => {
         nodeChannel.requestBinarySync("echo", bigMsg);
     })
+    .add('simple binary echo request to Node child with a 10MiB message', () => {
+        nodeChannel.requestBinarySync("echo", tenMiBMsg);
+    })
+    .add('simple binary echo request to Node child with a 10MiB message and 1MiB buffers', () => {
+        bufferedNodeChannel.requestBinarySync("echo", tenMiBMsg);
+    })
+    .add('simple Buffer echo request to Node child with a 64MiB message', () => {
+        nodeChannel.requestBufferSync("echo", sixtyFourMiBBuf);
+    })
     .add('js noop baseline', () => {
         noopjs(smallMsg);
     })
@@ -0,0 +1,482 @@
+use std::{
+  collections::BTreeMap,
+  io,
+  process::Stdio,
+  sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc, Mutex, MutexGuard, PoisonError,
+  },
+};
+
+use tokio::{
+  io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+  process::{Child, Command},
+  sync::{mpsc, oneshot},
+  task::JoinHandle,
+};
+
+use crate::{
+  encode_message, MessageBuffers, MessageParser, MessageType, Result, RpcError,
+  DEFAULT_MAX_PAYLOAD_LEN,
+};
+
+/// Size of the chunks read from the child's stdout (or other reader) at a time.
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// The payload of the `MessageType::CallError` sent for each callback the
+/// child invokes.
+const NO_CALLBACKS: &[u8] = b"AsyncRpcChannel does not support callbacks";
+
+/// An asynchronous channel to a child process (or anything else at the other
+/// end of a pair of streams) speaking the same protocol as `RpcConnection`,
+/// for making requests from Tokio without blocking a thread for their
+/// duration.
+///
+/// Each request is sent with an `<id>` (see `RpcConnection::write_with_id`),
+/// so that any number of them can be in flight at once, and the child may
+/// respond to them in any order as long as it echoes their `<id>`. A response
+/// without an `<id>` goes to the oldest request in flight for its method.
+///
+/// The child can't invoke callbacks: each `MessageType::Call` it sends is
+/// answered with a `MessageType::CallError`. `MessageType::Log`,
+/// `MessageType::Notify` and `MessageType::ResponseChunk` messages are
+/// discarded, and payloads are never compressed.
+pub struct AsyncRpcChannel {
+  // The child the channel spawned, if any.
+  child: Option<Child>,
+  // The reader and writer tasks, stopped when the channel is dropped.
+  tasks: [JoinHandle<()>; 2],
+  // Whole messages for the writer task to send, in order, which keeps a
+  // request whose future is dropped midway from leaving a partial message.
+  outgoing: mpsc::UnboundedSender<Vec<u8>>,
+  requests: Arc<Mutex<Requests>>,
+  next_id: AtomicU32,
+}
+
+// The requests waiting for a response, by `<id>`, or why no more responses
+// will arrive.
+#[derive(Default)]
+struct Requests {
+  waiting: BTreeMap<u32, Waiting>,
+  closed: Option<RpcError>,
+}
+
+struct Waiting {
+  method: Vec<u8>,
+  tx: oneshot::Sender<Result<Vec<u8>>>,
+}
+
+// Removes a request from `Requests::waiting` once its future is done with it,
+// including when the future is dropped before the response arrives.
+struct WaitingGuard<'a> {
+  requests: &'a Mutex<Requests>,
+  id: u32,
+}
+
+impl Drop for WaitingGuard<'_> {
+  fn drop(&mut self) {
+    lock(self.requests).waiting.remove(&self.id);
+  }
+}
+
+impl AsyncRpcChannel {
+  /// Spawns `cmd` with piped stdin and stdout, to speak the protocol over.
+  /// The child is killed when the channel is dropped.
+  ///
+  /// Must be called from within a Tokio runtime, which the channel's reader
+  /// and writer tasks are spawned on.
+  pub fn spawn(cmd: &mut Command) -> Result<Self> {
+    Self::spawn_with_max_payload_len(cmd, DEFAULT_MAX_PAYLOAD_LEN)
+  }
+
+  /// Like `spawn`, but with a limit on the length of responses, as with
+  /// `with_max_payload_len`.
+  pub fn spawn_with_max_payload_len(cmd: &mut Command, max_payload_len: usize) -> Result<Self> {
+    let mut child = cmd
+      .stdin(Stdio::piped())
+      .stdout(Stdio::piped())
+      .kill_on_drop(true)
+      .spawn()?;
+    let stdin = child.stdin.take().expect("Where did ChildStdin go?");
+    let stdout = child.stdout.take().expect("Where did ChildStdout go?");
+    let mut channel = Self::with_max_payload_len(stdout, stdin, max_payload_len)?;
+    channel.child = Some(child);
+    Ok(channel)
+  }
+
+  /// Speaks the protocol over `reader` and `writer` rather than the stdio of
+  /// a child the channel spawns, such as over the two halves of a socket.
+  /// Both are dropped along with the channel.
+  ///
+  /// Must be called from within a Tokio runtime, as with `spawn`.
+  pub fn new<R, W>(reader: R, writer: W) -> Result<Self>
+  where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+  {
+    Self::with_max_payload_len(reader, writer, DEFAULT_MAX_PAYLOAD_LEN)
+  }
+
+  /// Like `new`, but a message from the other end whose `<name>` or
+  /// `<payload>` is longer than `max_payload_len` bytes closes the channel,
+  /// failing every request with `RpcError::PayloadTooLarge`, instead of
+  /// being read.
+  pub fn with_max_payload_len<R, W>(reader: R, writer: W, max_payload_len: usize) -> Result<Self>
+  where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+  {
+    let (outgoing, rx) = mpsc::unbounded_channel();
+    let requests = Arc::new(Mutex::new(Requests::default()));
+    let tasks = [
+      tokio::spawn(write_messages(writer, rx, requests.clone())),
+      tokio::spawn(read_messages(
+        reader,
+        max_payload_len,
+        requests.clone(),
+        outgoing.clone(),
+      )),
+    ];
+    Ok(Self {
+      child: None,
+      tasks,
+      outgoing,
+      requests,
+      next_id: AtomicU32::new(0),
+    })
+  }
+
+  /// Sends a request to the child and waits for its response, without
+  /// holding up any other request. An error reported by the child is
+  /// returned as `RpcError::RemoteError`, and once the child exits, every
+  /// request fails with `RpcError::ChildDisconnected`.
+  pub async fn request(&self, method: &str, payload: &[u8]) -> Result<Vec<u8>> {
+    let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+    let msg = encode_message(
+      MessageType::Request as u8,
+      method.as_bytes(),
+      payload,
+      Some(id),
+    )?;
+    let (tx, rx) = oneshot::channel();
+    {
+      let mut requests = lock(&self.requests);
+      if let Some(e) = &requests.closed {
+        return Err(duplicate(e));
+      }
+      let method = method.as_bytes().to_vec();
+      requests.waiting.insert(id, Waiting { method, tx });
+    }
+    let _guard = WaitingGuard {
+      requests: &self.requests,
+      id,
+    };
+    // Should the writer task be gone, the channel is closed (or about to
+    // be), which fails the request.
+    let _ = self.outgoing.send(msg);
+    rx.await.unwrap_or(Err(RpcError::ChildDisconnected))
+  }
+
+  /// Returns the OS process ID of the child, unless it has been waited for
+  /// or the channel didn't spawn one.
+  pub fn pid(&self) -> Option<u32> {
+    self.child.as_ref()?.id()
+  }
+
+  /// Kills the child, if the channel spawned one, and waits for it to exit.
+  /// Requests still in flight fail with `RpcError::ChildDisconnected`.
+  pub async fn close(mut self) -> Result<()> {
+    if let Some(child) = &mut self.child {
+      child.kill().await?;
+    }
+    Ok(())
+  }
+}
+
+impl Drop for AsyncRpcChannel {
+  fn drop(&mut self) {
+    for task in &self.tasks {
+      task.abort();
+    }
+  }
+}
+
+// Helper function to run the task that sends messages to the child.
+async fn write_messages(
+  mut writer: impl AsyncWrite + Unpin,
+  mut rx: mpsc::UnboundedReceiver<Vec<u8>>,
+  requests: Arc<Mutex<Requests>>,
+) {
+  while let Some(msg) = rx.recv().await {
+    match writer.write_all(&msg).await {
+      Ok(()) => {}
+      // The child may have responded to everything before exiting, which the
+      // reader task finds out.
+      Err(e) if e.kind() == io::ErrorKind::BrokenPipe => return,
+      Err(e) => return close(&requests, e.into()),
+    }
+  }
+}
+
+// Helper function to run the task that receives messages from the child,
+// until it closes the connection.
+async fn read_messages(
+  reader: impl AsyncRead + Unpin,
+  max_payload_len: usize,
+  requests: Arc<Mutex<Requests>>,
+  outgoing: mpsc::UnboundedSender<Vec<u8>>,
+) {
+  let e = match receive(reader, max_payload_len, &requests, &outgoing).await {
+    Ok(()) => RpcError::ChildDisconnected,
+    Err(e) => e,
+  };
+  close(&requests, e);
+}
+
+async fn receive(
+  mut reader: impl AsyncRead + Unpin,
+  max_payload_len: usize,
+  requests: &Mutex<Requests>,
+  outgoing: &mpsc::UnboundedSender<Vec<u8>>,
+) -> Result<()> {
+  let mut parser = MessageParser::default();
+  let mut chunk = vec![0; READ_CHUNK_SIZE];
+  let mut bufs = MessageBuffers::default();
+  loop {
+    let n = reader.read(&mut chunk).await?;
+    if n == 0 {
+      return Ok(());
+    }
+    let mut input = &chunk[..n];
+    while !input.is_empty() {
+      let (used, ty) = parser.feed(input, &mut bufs, max_payload_len)?;
+      input = &input[used..];
+      let Some(ty) = ty else {
+        break;
+      };
+      match MessageType::try_from(ty)? {
+        ty @ (MessageType::Response | MessageType::Error) => {
+          respond(requests, ty, &mut bufs);
+        }
+        MessageType::Call => {
+          let msg = encode_message(
+            MessageType::CallError as u8,
+            &bufs.name,
+            NO_CALLBACKS,
+            bufs.id,
+          )?;
+          let _ = outgoing.send(msg);
+        }
+        MessageType::Log | MessageType::Notify | MessageType::ResponseChunk => {}
+        ty => {
+          return Err(RpcError::FramingError(format!(
+            "Invalid message type from child: {ty:?}"
+          )))
+        }
+      }
+    }
+  }
+}
+
+// Helper function to resolve the request a `MessageType::Response` or
+// `MessageType::Error` is for. Responses to requests nobody is waiting for
+// anymore are dropped.
+fn respond(requests: &Mutex<Requests>, ty: MessageType, bufs: &mut MessageBuffers) {
+  let mut requests = lock(requests);
+  let id = match bufs.id {
+    Some(id) => Some(id),
+    None => requests
+      .waiting
+      .iter()
+      .find(|(_, waiting)| waiting.method == bufs.name)
+      .map(|(&id, _)| id),
+  };
+  let Some(waiting) = id.and_then(|id| requests.waiting.remove(&id)) else {
+    return;
+  };
+  let res = if bufs.name != waiting.method {
+    Err(RpcError::NameMismatch {
+      expected: String::from_utf8_lossy(&waiting.method).into_owned(),
+      actual: String::from_utf8_lossy(&bufs.name).into_owned(),
+    })
+  } else if ty == MessageType::Error {
+    Err(RpcError::RemoteError(
+      String::from_utf8_lossy(&bufs.payload).into_owned(),
+    ))
+  } else {
+    Ok(std::mem::take(&mut bufs.payload))
+  };
+  let _ = waiting.tx.send(res);
+}
+
+// Helper function to fail every request in flight with `e`, along with any
+// made from now on.
+fn close(requests: &Mutex<Requests>, e: RpcError) {
+  let mut requests = lock(requests);
+  for (_, waiting) in std::mem::take(&mut requests.waiting) {
+    let _ = waiting.tx.send(Err(duplicate(&e)));
+  }
+  requests.closed.get_or_insert(e);
+}
+
+fn lock(requests: &Mutex<Requests>) -> MutexGuard<'_, Requests> {
+  requests.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// Helper function to copy the error that closed the channel for each request
+// it fails, since `io::Error`s can't be cloned.
+fn duplicate(e: &RpcError) -> RpcError {
+  match e {
+    RpcError::NameMismatch { expected, actual } => RpcError::NameMismatch {
+      expected: expected.clone(),
+      actual: actual.clone(),
+    },
+    RpcError::FramingError(message) => RpcError::FramingError(message.clone()),
+    RpcError::PayloadTooLarge { len, max_len } => RpcError::PayloadTooLarge {
+      len: *len,
+      max_len: *max_len,
+    },
+    RpcError::ChecksumMismatch { expected, actual } => RpcError::ChecksumMismatch {
+      expected: *expected,
+      actual: *actual,
+    },
+    RpcError::ChildDisconnected => RpcError::ChildDisconnected,
+    RpcError::RemoteError(message) => RpcError::RemoteError(message.clone()),
+    RpcError::Io(e) => RpcError::Io(io::Error::new(e.kind(), e.to_string())),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{future::Future, task::Poll};
+
+  use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+  use super::*;
+
+  // The other end of a channel, reading its requests and responding by hand.
+  struct Peer {
+    stream: DuplexStream,
+    buf: Vec<u8>,
+    parser: MessageParser,
+    bufs: MessageBuffers,
+  }
+
+  impl Peer {
+    // Reads the next request, returning its `<name>`, `<payload>` and `<id>`.
+    async fn request(&mut self) -> (Vec<u8>, Vec<u8>, Option<u32>) {
+      loop {
+        let (used, ty) = self
+          .parser
+          .feed(&self.buf, &mut self.bufs, DEFAULT_MAX_PAYLOAD_LEN)
+          .unwrap();
+        self.buf.drain(..used);
+        if let Some(ty) = ty {
+          assert_eq!(ty, MessageType::Request as u8);
+          let bufs = std::mem::take(&mut self.bufs);
+          return (bufs.name, bufs.payload, bufs.id);
+        }
+        let mut chunk = [0; 1024];
+        let n = self.stream.read(&mut chunk).await.unwrap();
+        assert!(n > 0, "the channel closed the connection");
+        self.buf.extend_from_slice(&chunk[..n]);
+      }
+    }
+
+    async fn respond(&mut self, ty: MessageType, name: &[u8], payload: &[u8], id: Option<u32>) {
+      let msg = encode_message(ty as u8, name, payload, id).unwrap();
+      self.stream.write_all(&msg).await.unwrap();
+    }
+  }
+
+  // Helper function to create a channel connected to a `Peer`.
+  fn connect(max_payload_len: usize) -> (AsyncRpcChannel, Peer) {
+    let (ours, theirs) = tokio::io::duplex(64 * 1024);
+    let (reader, writer) = tokio::io::split(ours);
+    let channel = AsyncRpcChannel::with_max_payload_len(reader, writer, max_payload_len).unwrap();
+    let peer = Peer {
+      stream: theirs,
+      buf: Vec::new(),
+      parser: MessageParser::default(),
+      bufs: MessageBuffers::default(),
+    };
+    (channel, peer)
+  }
+
+  #[tokio::test]
+  async fn resolves_concurrent_requests_out_of_order() {
+    let (channel, mut peer) = connect(DEFAULT_MAX_PAYLOAD_LEN);
+    let (a, b, c, ()) = tokio::join!(
+      channel.request("a", b"1"),
+      channel.request("b", b"2"),
+      channel.request("c", b"3"),
+      async {
+        let mut requests = Vec::new();
+        for _ in 0..3 {
+          requests.push(peer.request().await);
+        }
+        for (name, payload, id) in requests.into_iter().rev() {
+          let ty = match &name[..] {
+            b"b" => MessageType::Error,
+            _ => MessageType::Response,
+          };
+          peer
+            .respond(ty, &name, &[&payload[..], b"!"].concat(), id)
+            .await;
+        }
+      },
+    );
+    assert_eq!(a.unwrap(), b"1!");
+    assert!(matches!(b, Err(RpcError::RemoteError(message)) if message == "2!"));
+    assert_eq!(c.unwrap(), b"3!");
+    assert!(lock(&channel.requests).waiting.is_empty());
+  }
+
+  #[tokio::test]
+  async fn forgets_requests_whose_future_is_dropped() {
+    let (channel, mut peer) = connect(DEFAULT_MAX_PAYLOAD_LEN);
+    let mut dropped = Box::pin(channel.request("dropped", b""));
+    let pending = std::future::poll_fn(|cx| Poll::Ready(dropped.as_mut().poll(cx).is_pending()));
+    assert!(pending.await);
+    assert_eq!(lock(&channel.requests).waiting.len(), 1);
+    drop(dropped);
+    assert!(lock(&channel.requests).waiting.is_empty());
+
+    // A late response to the dropped request is discarded.
+    let (res, ()) = tokio::join!(channel.request("next", b""), async {
+      let (name, _, id) = peer.request().await;
+      assert_eq!((&name[..], id), (&b"dropped"[..], Some(0)));
+      peer
+        .respond(MessageType::Response, b"dropped", b"late", id)
+        .await;
+      let (name, _, id) = peer.request().await;
+      peer
+        .respond(MessageType::Response, &name, b"on time", id)
+        .await;
+    });
+    assert_eq!(res.unwrap(), b"on time");
+  }
+
+  #[tokio::test]
+  async fn fails_requests_on_responses_over_the_limit() {
+    let (channel, mut peer) = connect(6);
+    let (res, ()) = tokio::join!(channel.request("method", b""), async {
+      let (name, _, id) = peer.request().await;
+      peer
+        .respond(MessageType::Response, &name, b"too long", id)
+        .await;
+    });
+    assert!(matches!(
+      res,
+      Err(RpcError::PayloadTooLarge {
+        len: Some(8),
+        max_len: 6
+      })
+    ));
+    // The channel can't be read from any further.
+    assert!(matches!(
+      channel.request("method", b"").await,
+      Err(RpcError::PayloadTooLarge { .. })
+    ));
+  }
+}
@@ -1,63 +1,1735 @@
-use std::io::{self, BufRead, Result, Write};
+use std::{
+  borrow::Cow,
+  fmt,
+  io::{self, BufRead, Read, Write},
+};
+
+#[cfg(feature = "async")]
+pub use async_channel::AsyncRpcChannel;
+
+#[cfg(feature = "async")]
+mod async_channel;
+
+/// The version of the protocol implemented by this crate, as exchanged in the
+/// optional handshake a channel performs when it starts a child (see
+/// `ChannelOptions.handshake` in `libsyncrpc`).
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The types of messages exchanged between a channel and its child, as sent in
+/// the `<type>` item of each message.
+///
+/// This is the definition of the protocol shared by both ends: child
+/// implementations written in Rust can use it with `RpcConnection::write_frame`
+/// and `RpcConnection::read_frame` rather than hardcoding the values, and the
+/// `MessageType` enum exposed to JavaScript by `libsyncrpc` is converted from
+/// it. See the latter for the protocol behavior of each type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum MessageType {
+  Request = 1,
+  CallResponse,
+  CallError,
+  Response,
+  Error,
+  Call,
+  Shutdown,
+  ResponseChunk,
+  Cancel,
+  Log,
+  Notify,
+}
+
+impl TryFrom<u8> for MessageType {
+  type Error = InvalidMessageType;
+
+  fn try_from(value: u8) -> std::result::Result<Self, InvalidMessageType> {
+    Ok(match value {
+      1 => MessageType::Request,
+      2 => MessageType::CallResponse,
+      3 => MessageType::CallError,
+      4 => MessageType::Response,
+      5 => MessageType::Error,
+      6 => MessageType::Call,
+      7 => MessageType::Shutdown,
+      8 => MessageType::ResponseChunk,
+      9 => MessageType::Cancel,
+      10 => MessageType::Log,
+      11 => MessageType::Notify,
+      _ => return Err(InvalidMessageType(value)),
+    })
+  }
+}
+
+/// The error returned when a byte does not correspond to any `MessageType`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidMessageType(pub u8);
+
+impl fmt::Display for InvalidMessageType {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "Invalid message type: {}", self.0)
+  }
+}
+
+impl std::error::Error for InvalidMessageType {}
+
+impl From<InvalidMessageType> for io::Error {
+  fn from(err: InvalidMessageType) -> Self {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+  }
+}
+
+impl From<InvalidMessageType> for RpcError {
+  fn from(err: InvalidMessageType) -> Self {
+    RpcError::FramingError(err.to_string())
+  }
+}
+
+/// The ways in which reading or writing messages can fail.
+#[derive(Debug)]
+pub enum RpcError {
+  /// The `<name>` of a response did not match that of its request.
+  NameMismatch { expected: String, actual: String },
+  /// The bytes read from the other end are not a valid message.
+  FramingError(String),
+  /// The `<name>` or `<payload>` of a message is longer than allowed (see
+  /// `RpcConnection::with_max_payload_len`), or than a message can carry at
+  /// all (see `RpcConnection::write`). `len` is unknown for
+  /// compressed payloads, which are only decompressed up to the limit.
+  PayloadTooLarge { len: Option<usize>, max_len: usize },
+  /// The checksum of a message (see `RpcConnection::set_checksums`) doesn't
+  /// match its contents, which were corrupted on the way.
+  ChecksumMismatch { expected: u32, actual: u32 },
+  /// The other end closed the connection in the middle of a message.
+  ChildDisconnected,
+  /// The other end reported an error, with the given message.
+  RemoteError(String),
+  /// Reading from or writing to the other end failed.
+  Io(io::Error),
+}
+
+impl fmt::Display for RpcError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      RpcError::NameMismatch { expected, actual } => write!(
+        f,
+        "name mismatch for response: expected `{expected}`, got `{actual}`"
+      ),
+      RpcError::FramingError(message) | RpcError::RemoteError(message) => f.write_str(message),
+      RpcError::PayloadTooLarge {
+        len: Some(len),
+        max_len,
+      } => write!(
+        f,
+        "message item of {len} bytes exceeds the maximum length of {max_len} bytes"
+      ),
+      RpcError::PayloadTooLarge { len: None, max_len } => write!(
+        f,
+        "decompressed payload exceeds the maximum length of {max_len} bytes"
+      ),
+      RpcError::ChecksumMismatch { expected, actual } => write!(
+        f,
+        "checksum mismatch: message carries {expected:08x}, but its contents hash to {actual:08x}"
+      ),
+      RpcError::ChildDisconnected => f.write_str("connection closed in the middle of a message"),
+      RpcError::Io(err) => err.fmt(f),
+    }
+  }
+}
+
+impl std::error::Error for RpcError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      RpcError::Io(err) => Some(err),
+      _ => None,
+    }
+  }
+}
+
+impl From<io::Error> for RpcError {
+  fn from(err: io::Error) -> Self {
+    match err.kind() {
+      io::ErrorKind::UnexpectedEof => RpcError::ChildDisconnected,
+      _ => RpcError::Io(err),
+    }
+  }
+}
+
+/// For callers that deal in `io::Error`s: errors about the data read from
+/// the other end become `io::ErrorKind::InvalidData`, and a connection closed
+/// mid-message becomes `io::ErrorKind::UnexpectedEof`.
+impl From<RpcError> for io::Error {
+  fn from(err: RpcError) -> Self {
+    let kind = match err {
+      RpcError::Io(err) => return err,
+      RpcError::FramingError(_)
+      | RpcError::PayloadTooLarge { .. }
+      | RpcError::ChecksumMismatch { .. } => io::ErrorKind::InvalidData,
+      RpcError::ChildDisconnected => io::ErrorKind::UnexpectedEof,
+      RpcError::NameMismatch { .. } | RpcError::RemoteError(_) => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, err)
+  }
+}
+
+impl From<rmp::decode::ValueReadError> for RpcError {
+  fn from(err: rmp::decode::ValueReadError) -> Self {
+    use rmp::decode::ValueReadError;
+    match err {
+      ValueReadError::InvalidMarkerRead(err) | ValueReadError::InvalidDataRead(err) => err.into(),
+      ValueReadError::TypeMismatch(marker) => {
+        RpcError::FramingError(format!("unexpected MessagePack marker: {marker:?}"))
+      }
+    }
+  }
+}
+
+impl From<rmp::decode::NumValueReadError> for RpcError {
+  fn from(err: rmp::decode::NumValueReadError) -> Self {
+    use rmp::decode::NumValueReadError;
+    match err {
+      NumValueReadError::InvalidMarkerRead(err) | NumValueReadError::InvalidDataRead(err) => {
+        err.into()
+      }
+      NumValueReadError::TypeMismatch(marker) => {
+        RpcError::FramingError(format!("unexpected MessagePack marker: {marker:?}"))
+      }
+      NumValueReadError::OutOfRange => {
+        RpcError::FramingError("MessagePack integer out of range".into())
+      }
+    }
+  }
+}
+
+impl From<rmp::encode::ValueWriteError> for RpcError {
+  fn from(err: rmp::encode::ValueWriteError) -> Self {
+    use rmp::encode::ValueWriteError;
+    match err {
+      ValueWriteError::InvalidMarkerWrite(err) | ValueWriteError::InvalidDataWrite(err) => {
+        RpcError::Io(err)
+      }
+    }
+  }
+}
+
+/// The result of reading or writing messages.
+pub type Result<T> = std::result::Result<T, RpcError>;
+
+/// The `(<type>, <name>, <payload>)` components of a single message.
+pub type MessageComponents = (u8, Vec<u8>, Vec<u8>);
+
+/// Like `MessageComponents`, but with a typed `<type>`.
+pub type TypedMessageComponents = (MessageType, Vec<u8>, Vec<u8>);
+
+/// The `(<type>, <name>, <payload length>)` of a message whose `<payload>`
+/// is yet to be read, see `RpcConnection::read_header`.
+pub type MessageHeader = (u8, Vec<u8>, usize);
+
+/// Reusable buffers for the `<name>` and `<payload>` of messages read with
+/// `RpcConnection::read_into`, along with the message's optional
+/// `<deadline>` and `<id>`.
+#[derive(Debug, Default)]
+pub struct MessageBuffers {
+  pub name: Vec<u8>,
+  pub payload: Vec<u8>,
+  pub deadline_ms: Option<u32>,
+  pub id: Option<u32>,
+  // The flag byte the `<payload>` started with while compression is on, read
+  // apart from the rest so that an uncompressed payload needn't be shifted
+  // to drop it.
+  flag: Option<u8>,
+}
+
+/// The default maximum length of the `<name>` or `<payload>` of a message
+/// read by an `RpcConnection`: 256 MiB.
+pub const DEFAULT_MAX_PAYLOAD_LEN: usize = 256 * 1024 * 1024;
+
+/// The longest `<name>` or `<payload>` a message can carry: MessagePack's
+/// largest `bin` format has a 32-bit length.
+const MAX_ITEM_LEN: usize = u32::MAX as usize;
+
+/// How many bytes a payload can grow by when written: a compression flag
+/// byte and a checksum.
+const PAYLOAD_OVERHEAD: usize = 1 + 4;
+
+/// How many bytes of a non-UTF-8 error payload `RpcConnection::create_error`
+/// includes in its message, as hex.
+const ERROR_HEX_PREFIX_LEN: usize = 32;
+
+/// The flag byte prefixed to uncompressed payloads once compression is on.
+#[cfg(feature = "zstd")]
+const PAYLOAD_RAW: u8 = 0;
+
+/// The flag byte prefixed to zstd-compressed payloads once compression is on.
+#[cfg(feature = "zstd")]
+const PAYLOAD_ZSTD: u8 = 1;
+
+/// The zstd compression level used for payloads.
+#[cfg(feature = "zstd")]
+const ZSTD_LEVEL: i32 = 3;
 
 /// Lower-level wrapper around RPC-related messaging and process management.
 pub struct RpcConnection<R: BufRead, W: Write> {
   reader: R,
   writer: W,
+  max_payload_len: usize,
+  #[cfg(feature = "zstd")]
+  compression_threshold: Option<usize>,
+  checksums: bool,
+  // The message `try_read` is in the middle of, if any, and what it has read
+  // of it so far.
+  parser: MessageParser,
+  partial: MessageBuffers,
+  // The rest of the message whose header was read by `read_header`, if its
+  // payload hasn't been fully read yet.
+  unread_payload: Option<UnreadPayload>,
+}
+
+/// An iterator over the messages read from an `RpcConnection`, see
+/// `RpcConnection::messages`.
+pub struct Messages<'a, R: BufRead, W: Write> {
+  conn: &'a mut RpcConnection<R, W>,
+  done: bool,
+}
+
+impl<R: BufRead, W: Write> Messages<'_, R, W> {
+  /// The connection the messages are read from, to write to it in between.
+  pub fn connection(&mut self) -> &mut RpcConnection<R, W> {
+    self.conn
+  }
+}
+
+impl<R: BufRead, W: Write> Iterator for Messages<'_, R, W> {
+  type Item = Result<MessageComponents>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.done {
+      return None;
+    }
+    let res = self.conn.read().transpose();
+    self.done = !matches!(res, Some(Ok(_)));
+    res
+  }
+}
+
+impl<R: BufRead, W: Write> std::iter::FusedIterator for Messages<'_, R, W> {}
+
+// What is left of a message after `RpcConnection::read_header`: the number of
+// payload bytes, and the number of optional items that follow the payload.
+struct UnreadPayload {
+  remaining: usize,
+  trailing_items: u32,
 }
 
 impl<R: BufRead, W: Write> RpcConnection<R, W> {
+  /// Wraps `reader` and `writer`, which are read from and written to from the
+  /// start of the next message on.
+  ///
+  /// To exchange something outside of the protocol first, such as a banner
+  /// line the other end prints before entering its protocol loop, read it
+  /// from `reader` (or write it to `writer`) before passing them in. Anything
+  /// left buffered in `reader` is read as the start of the first message.
   pub fn new(reader: R, writer: W) -> Result<Self> {
+    Self::with_max_payload_len(reader, writer, DEFAULT_MAX_PAYLOAD_LEN)
+  }
+
+  /// Like `new`, but reading a message whose `<name>` or `<payload>` is
+  /// longer than `max_payload_len` bytes fails with
+  /// `RpcError::PayloadTooLarge` instead of allocating room for it. After
+  /// such an error, the connection is no longer at a message boundary and
+  /// can't be read from any further.
+  pub fn with_max_payload_len(reader: R, writer: W, max_payload_len: usize) -> Result<Self> {
     Ok(Self {
       reader,
       writer,
+      max_payload_len,
+      #[cfg(feature = "zstd")]
+      compression_threshold: None,
+      checksums: false,
+      parser: MessageParser::default(),
+      partial: MessageBuffers::default(),
+      unread_payload: None,
     })
   }
 
+  /// Turns payload compression on or off for all subsequent messages, in both
+  /// directions. Both ends must agree on when to do this.
+  ///
+  /// While compression is on, every `<payload>` starts with a flag byte: `0`
+  /// if the rest of the payload is uncompressed, or `1` if it is compressed
+  /// with zstd. Payloads shorter than `threshold` bytes, or that don't shrink
+  /// when compressed, are sent uncompressed. `None` turns compression off.
+  #[cfg(feature = "zstd")]
+  pub fn set_compression(&mut self, threshold: Option<usize>) {
+    self.compression_threshold = threshold;
+    self.parser.flagged = threshold.is_some();
+  }
+
+  /// Turns payload checksums on or off for all subsequent messages, in both
+  /// directions. Both ends must agree on when to do this.
+  ///
+  /// While checksums are on, every `<payload>` ends with 4 more bytes: the
+  /// big-endian CRC-32 (as used by zlib) of the message's `<type>` byte, its
+  /// `<name>`, and the rest of the `<payload>`, in that order. Reading a
+  /// message whose checksum doesn't match fails with
+  /// `RpcError::ChecksumMismatch`. With compression on as well, the checksum
+  /// covers the payload as sent, flag byte included.
+  pub fn set_checksums(&mut self, on: bool) {
+    self.checksums = on;
+  }
+
+  /// Returns a mutable reference to the underlying reader.
+  ///
+  /// Reading from it directly is only safe between messages, and only if the
+  /// other end sends exactly what is read outside of the protocol: anything
+  /// else desynchronizes the connection, with no way to find the start of
+  /// the next message again. Bytes `try_read` took from the reader for an
+  /// incomplete message are not in it anymore.
+  pub fn reader_mut(&mut self) -> &mut R {
+    &mut self.reader
+  }
+
+  /// Returns a mutable reference to the underlying writer.
+  ///
+  /// As with `reader_mut`, writing to it directly is only safe between
+  /// messages, including any written with `write_deferred` and not flushed
+  /// yet, and only if the other end expects exactly what is written.
+  pub fn writer_mut(&mut self) -> &mut W {
+    &mut self.writer
+  }
+
+  /// Takes the connection apart, returning the underlying reader and writer,
+  /// such as to hand the streams over to something speaking another
+  /// protocol. Messages written with `write_deferred` are flushed first.
+  ///
+  /// Fails with `RpcError::FramingError`, dropping the connection, if it is
+  /// in the middle of a message: the rest of a payload `read_header` left
+  /// unread, or the start of a message `try_read` took from the reader,
+  /// which would otherwise be lost.
+  pub fn into_parts(mut self) -> Result<(R, W)> {
+    self.check_payload_read()?;
+    self.check_not_parsing()?;
+    self.flush()?;
+    Ok((self.reader, self.writer))
+  }
+
+  /// Sends a message to the other end. Fails with
+  /// `RpcError::PayloadTooLarge`, without writing anything, if the `<name>`
+  /// or `<payload>` is too long for a MessagePack `bin` (about 4 GiB).
   pub fn write(&mut self, ty: u8, name: &[u8], payload: &[u8]) -> Result<()> {
+    self.write_with_deadline(ty, name, payload, None)
+  }
+
+  /// Like `write`, but optionally appends a 4th `<deadline>` item to the
+  /// message: the number of milliseconds, from when the message was sent, that
+  /// the other end has to respond to it.
+  pub fn write_with_deadline(
+    &mut self,
+    ty: u8,
+    name: &[u8],
+    payload: &[u8],
+    deadline_ms: Option<u32>,
+  ) -> Result<()> {
+    self.write_with_id(ty, name, payload, deadline_ms, None)
+  }
+
+  /// Like `write_with_deadline`, but optionally appends a 5th `<id>` item to
+  /// the message, which the other end echoes back in messages about it. If
+  /// there is an `<id>` but no `<deadline>`, the `<deadline>` item is `nil`.
+  pub fn write_with_id(
+    &mut self,
+    ty: u8,
+    name: &[u8],
+    payload: &[u8],
+    deadline_ms: Option<u32>,
+    id: Option<u32>,
+  ) -> Result<()> {
+    self.write_deferred_with_id(ty, name, payload, deadline_ms, id)?;
+    self.flush()
+  }
+
+  /// Like `write`, but doesn't flush the writer afterwards, so that several
+  /// messages can be queued up and sent with a single `flush`.
+  ///
+  /// Until `flush` is called (or a message is written with any of the other
+  /// `write` methods, which flush), the message may sit in the writer's
+  /// buffer, where the other end can't see it. Reading a response to a
+  /// message that was never flushed waits forever.
+  pub fn write_deferred(&mut self, ty: u8, name: &[u8], payload: &[u8]) -> Result<()> {
+    self.write_deferred_with_id(ty, name, payload, None, None)
+  }
+
+  /// Like `write_with_id`, but doesn't flush the writer afterwards, see
+  /// `write_deferred`.
+  pub fn write_deferred_with_id(
+    &mut self,
+    ty: u8,
+    name: &[u8],
+    payload: &[u8],
+    deadline_ms: Option<u32>,
+    id: Option<u32>,
+  ) -> Result<()> {
+    if name.len() > MAX_ITEM_LEN {
+      return Err(RpcError::PayloadTooLarge {
+        len: Some(name.len()),
+        max_len: MAX_ITEM_LEN,
+      });
+    }
+    if payload.len() > MAX_ITEM_LEN - PAYLOAD_OVERHEAD {
+      return Err(RpcError::PayloadTooLarge {
+        len: Some(payload.len()),
+        max_len: MAX_ITEM_LEN - PAYLOAD_OVERHEAD,
+      });
+    }
+    let len = match (deadline_ms, id) {
+      (_, Some(_)) => 5,
+      (Some(_), None) => 4,
+      (None, None) => 3,
+    };
     let w = &mut self.writer;
-    rmp::encode::write_array_len(w, 3)?;
+    rmp::encode::write_array_len(w, len)?;
     rmp::encode::write_u8(w, ty)?;
     rmp::encode::write_bin(w, name)?;
-    rmp::encode::write_bin(w, payload)?;
-    w.flush()?;
+    self.write_payload(ty, name, payload)?;
+    let w = &mut self.writer;
+    match deadline_ms {
+      Some(deadline_ms) => rmp::encode::write_u32(w, deadline_ms)?,
+      None if id.is_some() => rmp::encode::write_nil(w)?,
+      None => {}
+    }
+    if let Some(id) = id {
+      rmp::encode::write_u32(w, id)?;
+    }
     Ok(())
   }
 
-  pub fn read(&mut self) -> Result<(u8, Vec<u8>, Vec<u8>)> {
-    let r = &mut self.reader;
-    assert_eq!(rmp::decode::read_array_len(r).map_err(to_io)?, 3, "Message components must be a valid 3-part messagepack array.");
-    Ok((
-      rmp::decode::read_int(r).map_err(to_io)?,
-      self.read_bin()?,
-      self.read_bin()?,
-    ))
+  /// Sends any messages written with `write_deferred` that are still
+  /// buffered to the other end.
+  pub fn flush(&mut self) -> Result<()> {
+    self.writer.flush()?;
+    Ok(())
+  }
+
+  fn write_payload(&mut self, ty: u8, name: &[u8], payload: &[u8]) -> Result<()> {
+    let (flag, data) = self.compress(payload)?;
+    let checksum = self
+      .checksums
+      .then(|| crc32(&[&[ty], name, flag, &data]).to_be_bytes());
+    let checksum = checksum.as_ref().map_or(&[][..], |checksum| &checksum[..]);
+    let w = &mut self.writer;
+    rmp::encode::write_bin_len(w, (flag.len() + data.len() + checksum.len()) as u32)?;
+    w.write_all(flag)?;
+    w.write_all(&data)?;
+    w.write_all(checksum)?;
+    Ok(())
+  }
+
+  // Helper method to compress a payload if compression is on and it's worth
+  // it, returning the flag byte to start the payload with (see
+  // `set_compression`), if any, and the data to follow it.
+  fn compress<'a>(&self, payload: &'a [u8]) -> Result<(&'static [u8], Cow<'a, [u8]>)> {
+    #[cfg(feature = "zstd")]
+    if let Some(threshold) = self.compression_threshold {
+      let compressed = if payload.len() >= threshold {
+        Some(zstd::bulk::compress(payload, ZSTD_LEVEL)?).filter(|c| c.len() < payload.len())
+      } else {
+        None
+      };
+      return Ok(match compressed {
+        Some(compressed) => (&[PAYLOAD_ZSTD], Cow::Owned(compressed)),
+        None => (&[PAYLOAD_RAW], Cow::Borrowed(payload)),
+      });
+    }
+    Ok((&[], Cow::Borrowed(payload)))
+  }
+
+  /// Like `write`, but takes a typed `MessageType`.
+  pub fn write_frame(&mut self, ty: MessageType, name: &[u8], payload: &[u8]) -> Result<()> {
+    self.write(ty as u8, name, payload)
+  }
+
+  /// Like `read`, but parses the message's `<type>` into a `MessageType`,
+  /// failing with `RpcError::FramingError` if it isn't a known type.
+  pub fn read_frame(&mut self) -> Result<Option<TypedMessageComponents>> {
+    match self.read()? {
+      Some((ty, name, payload)) => Ok(Some((ty.try_into()?, name, payload))),
+      None => Ok(None),
+    }
+  }
+
+  /// Reads the next message from the other end. Returns `Ok(None)` if the
+  /// other end closed the connection cleanly between messages.
+  pub fn read(&mut self) -> Result<Option<MessageComponents>> {
+    let mut bufs = MessageBuffers::default();
+    Ok(
+      self
+        .read_into(&mut bufs)?
+        .map(|ty| (ty, bufs.name, bufs.payload)),
+    )
   }
 
-  fn read_bin(&mut self) -> Result<Vec<u8>> {
+  /// Returns an iterator over the messages read from the other end, as with
+  /// `read`. It ends once the other end closes the connection between
+  /// messages, or right after yielding an error, since the connection can't
+  /// be read from any further after most of them.
+  ///
+  /// The iterator borrows the connection for as long as it is in use, so a
+  /// `for` loop can't write to it. To write (such as to respond) between
+  /// messages, loop over `while let Some(msg) = messages.next()` instead and
+  /// write through `Messages::connection`.
+  pub fn messages(&mut self) -> Messages<'_, R, W> {
+    Messages {
+      conn: self,
+      done: false,
+    }
+  }
+
+  /// Like `read`, but reads the message's `<name>` and `<payload>` into the
+  /// given buffers, reusing their allocations, and only returns its `<type>`.
+  pub fn read_into(&mut self, bufs: &mut MessageBuffers) -> Result<Option<u8>> {
+    self.check_payload_read()?;
+    if self.parser.in_progress() {
+      // Finish the message `try_read` started.
+      let ty = self.parse_available()?;
+      std::mem::swap(bufs, &mut self.partial);
+      self.decode(ty, bufs)?;
+      return Ok(Some(ty));
+    }
+    let ty = read_message(
+      &mut self.reader,
+      bufs,
+      self.max_payload_len,
+      self.parser.flagged,
+    )?;
+    if let Some(ty) = ty {
+      self.decode(ty, bufs)?;
+    }
+    Ok(ty)
+  }
+
+  /// Reads only the `<type>` and `<name>` of the next message, along with the
+  /// length of its `<payload>`, leaving the payload itself to be read in
+  /// pieces with `read_payload` or discarded with `skip_payload`. This saves
+  /// buffering payloads that are forwarded elsewhere or dropped. Returns
+  /// `Ok(None)` if the other end closed the connection cleanly between
+  /// messages.
+  ///
+  /// Until the whole payload has been read or skipped, reading the next
+  /// message in any way fails with `RpcError::FramingError`, so that the
+  /// connection can't lose track of message boundaries. The payload is read
+  /// as sent, without undoing compression (see `set_compression`) or
+  /// verifying its checksum (see `set_checksums`), and the
+  /// message's optional `<deadline>` and `<id>` are discarded once it has
+  /// been read. A message `try_read` started can't be read this way, and
+  /// fails with `RpcError::FramingError`.
+  pub fn read_header(&mut self) -> Result<Option<MessageHeader>> {
+    self.check_payload_read()?;
+    self.check_not_parsing()?;
     let r = &mut self.reader;
-    let payload_len = rmp::decode::read_bin_len(r).map_err(to_io)?;
-    let mut payload = vec![0u8; payload_len as usize];
-    r.read_exact(&mut payload)?;
-    Ok(payload)
+    if peek(r)?.is_none() {
+      return Ok(None);
+    }
+    let trailing_items = read_array_len(r)? - 3;
+    let ty = rmp::decode::read_int(r)?;
+    let mut name = Vec::new();
+    read_bin_into(r, &mut name, self.max_payload_len)?;
+    let payload_len = rmp::decode::read_bin_len(r)? as usize;
+    if payload_len == 0 {
+      skip_items(r, trailing_items)?;
+    }
+    if payload_len > 0 {
+      self.unread_payload = Some(UnreadPayload {
+        remaining: payload_len,
+        trailing_items,
+      });
+    }
+    Ok(Some((ty, name, payload_len)))
   }
 
-  // Helper method to create an error
-  pub fn create_error(&self, name: &str, payload: Vec<u8>, expected_method: &str) -> io::Error {
-    if name == expected_method {
-      let payload = match String::from_utf8(payload) {
-        Ok(payload) => payload,
-        Err(err) => return io::Error::other(format!("{err}")),
+  /// Reads the next `len` bytes of the payload of the message whose header
+  /// was read by `read_header` into `buf`, replacing its contents. Fails if
+  /// fewer than `len` bytes of the payload are left.
+  pub fn read_payload(&mut self, len: usize, buf: &mut Vec<u8>) -> Result<()> {
+    buf.clear();
+    buf.resize(len, 0);
+    self.take_payload(len, |r| Ok(r.read_exact(buf)?))
+  }
+
+  /// Like `read_payload`, but discards the bytes instead.
+  pub fn skip_payload(&mut self, len: usize) -> Result<()> {
+    self.take_payload(len, |r| {
+      let skipped = io::copy(&mut r.by_ref().take(len as u64), &mut io::sink())?;
+      if skipped < len as u64 {
+        return Err(RpcError::ChildDisconnected);
+      }
+      Ok(())
+    })
+  }
+
+  // Helper method to consume `len` bytes of the payload left by
+  // `read_header` with `f`, then the rest of the message once the payload is
+  // done.
+  fn take_payload(&mut self, len: usize, f: impl FnOnce(&mut R) -> Result<()>) -> Result<()> {
+    let Some(unread) = &self.unread_payload else {
+      return Err(RpcError::FramingError(
+        "No payload left to read: call `read_header` first".into(),
+      ));
+    };
+    if len > unread.remaining {
+      return Err(RpcError::FramingError(format!(
+        "Cannot read {len} bytes of a payload with {} bytes left",
+        unread.remaining
+      )));
+    }
+    let remaining = unread.remaining - len;
+    let trailing_items = unread.trailing_items;
+    f(&mut self.reader)?;
+    if remaining == 0 {
+      skip_items(&mut self.reader, trailing_items)?;
+    }
+    self.unread_payload = (remaining > 0).then_some(UnreadPayload {
+      remaining,
+      trailing_items,
+    });
+    Ok(())
+  }
+
+  // Helper method to fail if the payload of a message whose header was read
+  // by `read_header` hasn't been fully read yet.
+  fn check_payload_read(&self) -> Result<()> {
+    match &self.unread_payload {
+      Some(unread) => Err(RpcError::FramingError(format!(
+        "The previous message still has {} bytes of payload to read or skip",
+        unread.remaining
+      ))),
+      None => Ok(()),
+    }
+  }
+
+  // Helper method to fail if `try_read` is in the middle of a message, for
+  // ways of reading that can't pick up where it left off.
+  fn check_not_parsing(&self) -> Result<()> {
+    if self.parser.in_progress() {
+      return Err(RpcError::FramingError(format!(
+        "{} bytes of an incomplete message were already read",
+        self.parser.consumed
+      )));
+    }
+    Ok(())
+  }
+
+  /// Like `read`, but doesn't wait for data the reader doesn't have yet:
+  /// returns `Ok(None)` as soon as the reader fails with
+  /// `io::ErrorKind::WouldBlock` before a complete message has arrived. This
+  /// is meant for readers in non-blocking mode, such as a `BufReader` around
+  /// a non-blocking socket, and blocks like `read` with any other reader.
+  ///
+  /// An incomplete message is kept until the next call to `try_read` (or
+  /// `read`), which picks up where this one left off: each call parses
+  /// whatever the reader has buffered, a piece at a time, until either a
+  /// message is complete or the reader would block. Only the bytes of the
+  /// message are taken from the reader, and each is looked at once, so a
+  /// message that arrives in many pieces still takes time linear in its
+  /// length to read.
+  ///
+  /// Since `Ok(None)` means "not yet" here, the other end closing the
+  /// connection is reported as `RpcError::ChildDisconnected`, whether or not
+  /// it happened between messages.
+  pub fn try_read(&mut self) -> Result<Option<MessageComponents>> {
+    self.check_payload_read()?;
+    let ty = match self.parse_available() {
+      Ok(ty) => ty,
+      Err(RpcError::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+      Err(e) => return Err(e),
+    };
+    let mut bufs = std::mem::take(&mut self.partial);
+    self.decode(ty, &mut bufs)?;
+    Ok(Some((ty, bufs.name, bufs.payload)))
+  }
+
+  // Helper method to feed whatever the reader has to `parser` until a
+  // message is complete in `partial`, returning its `<type>`.
+  fn parse_available(&mut self) -> Result<u8> {
+    loop {
+      let available = match self.reader.fill_buf() {
+        Ok(available) => available,
+        Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+        Err(e) => return Err(e.into()),
       };
-      io::Error::other(payload)
+      if available.is_empty() {
+        return Err(RpcError::ChildDisconnected);
+      }
+      let (used, ty) = self
+        .parser
+        .feed(available, &mut self.partial, self.max_payload_len)?;
+      self.reader.consume(used);
+      if let Some(ty) = ty {
+        return Ok(ty);
+      }
+    }
+  }
+
+  // Helper method to verify and undo the encoding of a payload read while
+  // checksums or compression are on.
+  fn decode(&self, ty: u8, bufs: &mut MessageBuffers) -> Result<()> {
+    if self.checksums {
+      verify_checksum(ty, bufs)?;
+    }
+    #[cfg(feature = "zstd")]
+    if self.compression_threshold.is_some() {
+      decode_payload(bufs, self.max_payload_len)?;
+    }
+    Ok(())
+  }
+
+  // Helper method to create an error. A payload that isn't valid UTF-8 is
+  // reported by its length and the hex of its first bytes, so that some of
+  // it survives.
+  pub fn create_error(&self, name: &str, payload: Vec<u8>, expected_method: &str) -> RpcError {
+    if name == expected_method {
+      match String::from_utf8(payload) {
+        Ok(payload) => RpcError::RemoteError(payload),
+        Err(err) => {
+          let payload = err.as_bytes();
+          let prefix = &payload[..payload.len().min(ERROR_HEX_PREFIX_LEN)];
+          let hex: String = prefix.iter().map(|byte| format!("{byte:02x}")).collect();
+          let ellipsis = if prefix.len() < payload.len() {
+            "..."
+          } else {
+            ""
+          };
+          RpcError::RemoteError(format!(
+            "remote error (non-UTF-8, {} bytes): {hex}{ellipsis}",
+            payload.len()
+          ))
+        }
+      }
     } else {
-      io::Error::other(format!(
-        "name mismatch for response: expected `{expected_method}`, got `{name}`"
+      RpcError::NameMismatch {
+        expected: expected_method.into(),
+        actual: name.into(),
+      }
+    }
+  }
+}
+
+// Helper function to read a message from `r` into `bufs`, returning its
+// `<type>`, or `None` if `r` is at EOF. The `<payload>` is left encoded, but
+// if `flagged`, its first byte is read into `bufs.flag` instead.
+fn read_message<Rd: BufRead>(
+  r: &mut Rd,
+  bufs: &mut MessageBuffers,
+  max_len: usize,
+  flagged: bool,
+) -> Result<Option<u8>> {
+  if peek(r)?.is_none() {
+    return Ok(None);
+  }
+  let len = read_array_len(r)?;
+  let ty = rmp::decode::read_int(r)?;
+  read_bin_into(r, &mut bufs.name, max_len)?;
+  let mut payload_len = read_bin_len(r, max_len)?;
+  bufs.flag = None;
+  if flagged && payload_len > 0 {
+    let mut flag = [0];
+    r.read_exact(&mut flag)?;
+    bufs.flag = Some(flag[0]);
+    payload_len -= 1;
+  }
+  read_exact_into(r, &mut bufs.payload, payload_len)?;
+  bufs.deadline_ms = if len >= 4 {
+    read_optional_u32(r)?
+  } else {
+    None
+  };
+  bufs.id = if len == 5 {
+    read_optional_u32(r)?
+  } else {
+    None
+  };
+  Ok(Some(ty))
+}
+
+/// The longest MessagePack value `MessageParser` waits for all of: an integer
+/// with a 64-bit marker.
+const MAX_VALUE_LEN: usize = 9;
+
+/// Parses messages out of bytes that arrive in pieces, for readers that can't
+/// wait for the rest of a message (`RpcConnection::try_read` and
+/// `AsyncRpcChannel`). The message is read into `MessageBuffers` that must be
+/// passed to every call to `feed` until it is complete, with its `<payload>`
+/// left encoded.
+///
+/// Parsing is a state machine with a state per item of a message, which
+/// `feed` moves through for as long as it has bytes:
+///
+/// - `ArrayLen`, `Type`, `NameLen`, `PayloadLen`, `Deadline` and `Id` wait for
+///   a whole MessagePack value, keeping the bytes of one that has only
+///   partially arrived in `partial`. The array length decides whether
+///   `Deadline` and `Id` are visited at all.
+/// - `Name` and `Payload` copy bytes into their buffer as they arrive, until
+///   `remaining` reaches zero. Nothing is allocated for bytes that haven't
+///   arrived, so a length that isn't followed up costs nothing.
+/// - `Flag` waits for the compression flag byte a non-empty `<payload>`
+///   starts with, if `flagged`, and puts it in `MessageBuffers::flag`.
+///
+/// Once the last item is read, `feed` returns the message's `<type>` and goes
+/// back to `ArrayLen` for the next message. Since every byte is looked at
+/// once, however the message is split up, parsing it takes time linear in
+/// its length. After an error, the parser is left mid-message, which is fine
+/// since the connection can't be read from any further anyway.
+#[derive(Debug, Default)]
+struct MessageParser {
+  state: ParseState,
+  // The bytes of a value that has only partially arrived.
+  partial: Vec<u8>,
+  // The number of items in the message, and its `<type>`, once known.
+  items: u32,
+  ty: u8,
+  // The number of bytes of the message fed so far.
+  consumed: usize,
+  // Whether payloads start with a compression flag byte, see
+  // `RpcConnection::set_compression`.
+  flagged: bool,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+enum ParseState {
+  #[default]
+  ArrayLen,
+  Type,
+  NameLen,
+  Name {
+    remaining: usize,
+  },
+  PayloadLen,
+  Flag {
+    remaining: usize,
+  },
+  Payload {
+    remaining: usize,
+  },
+  Deadline,
+  Id,
+}
+
+impl MessageParser {
+  // Whether part of a message has been fed already.
+  fn in_progress(&self) -> bool {
+    self.consumed > 0
+  }
+
+  // Parses as much of a message as `input` holds into `bufs`, returning how
+  // many bytes of `input` it used, and the message's `<type>` if that
+  // completed it. Bytes past the end of the message are left unused.
+  fn feed(
+    &mut self,
+    input: &[u8],
+    bufs: &mut MessageBuffers,
+    max_len: usize,
+  ) -> Result<(usize, Option<u8>)> {
+    let mut pos = 0;
+    let ty = self.advance(input, &mut pos, bufs, max_len);
+    self.consumed += pos;
+    let ty = ty?;
+    if ty.is_some() {
+      self.consumed = 0;
+    }
+    Ok((pos, ty))
+  }
+
+  // Helper method for `feed`, advancing `pos` past the bytes it uses.
+  fn advance(
+    &mut self,
+    input: &[u8],
+    pos: &mut usize,
+    bufs: &mut MessageBuffers,
+    max_len: usize,
+  ) -> Result<Option<u8>> {
+    loop {
+      self.state = match self.state {
+        ParseState::ArrayLen => {
+          let Some(items) = self.value(input, pos, |r| read_array_len(r))? else {
+            return Ok(None);
+          };
+          self.items = items;
+          ParseState::Type
+        }
+        ParseState::Type => {
+          let Some(ty) = self.value(input, pos, |r| Ok(rmp::decode::read_int(r)?))? else {
+            return Ok(None);
+          };
+          self.ty = ty;
+          ParseState::NameLen
+        }
+        ParseState::NameLen => {
+          let Some(len) = self.value(input, pos, |r| read_bin_len(r, max_len))? else {
+            return Ok(None);
+          };
+          bufs.name.clear();
+          ParseState::Name { remaining: len }
+        }
+        ParseState::Name { remaining } => match copy_bytes(input, pos, &mut bufs.name, remaining) {
+          0 => ParseState::PayloadLen,
+          remaining => {
+            self.state = ParseState::Name { remaining };
+            return Ok(None);
+          }
+        },
+        ParseState::PayloadLen => {
+          let Some(len) = self.value(input, pos, |r| read_bin_len(r, max_len))? else {
+            return Ok(None);
+          };
+          bufs.payload.clear();
+          bufs.flag = None;
+          if self.flagged && len > 0 {
+            ParseState::Flag { remaining: len - 1 }
+          } else {
+            ParseState::Payload { remaining: len }
+          }
+        }
+        ParseState::Flag { remaining } => {
+          let Some(&flag) = input.get(*pos) else {
+            self.state = ParseState::Flag { remaining };
+            return Ok(None);
+          };
+          *pos += 1;
+          bufs.flag = Some(flag);
+          ParseState::Payload { remaining }
+        }
+        ParseState::Payload { remaining } => {
+          match copy_bytes(input, pos, &mut bufs.payload, remaining) {
+            0 => {
+              bufs.deadline_ms = None;
+              bufs.id = None;
+              if self.items < 4 {
+                return Ok(Some(self.finish()));
+              }
+              ParseState::Deadline
+            }
+            remaining => {
+              self.state = ParseState::Payload { remaining };
+              return Ok(None);
+            }
+          }
+        }
+        ParseState::Deadline => {
+          let Some(deadline_ms) = self.value(input, pos, |r| read_optional_u32(r))? else {
+            return Ok(None);
+          };
+          bufs.deadline_ms = deadline_ms;
+          if self.items < 5 {
+            return Ok(Some(self.finish()));
+          }
+          ParseState::Id
+        }
+        ParseState::Id => {
+          let Some(id) = self.value(input, pos, |r| read_optional_u32(r))? else {
+            return Ok(None);
+          };
+          bufs.id = id;
+          return Ok(Some(self.finish()));
+        }
+      };
+    }
+  }
+
+  // Helper method to decode a MessagePack value with `decode` from the bytes
+  // of it already kept in `partial` followed by `input[*pos..]`, or keep
+  // what there is of it in `partial` if it hasn't fully arrived.
+  fn value<T>(
+    &mut self,
+    input: &[u8],
+    pos: &mut usize,
+    decode: impl FnOnce(&mut &[u8]) -> Result<T>,
+  ) -> Result<Option<T>> {
+    let kept = self.partial.len();
+    let available = (MAX_VALUE_LEN - kept).min(input.len() - *pos);
+    self
+      .partial
+      .extend_from_slice(&input[*pos..*pos + available]);
+    let mut r = &self.partial[..];
+    match decode(&mut r) {
+      Ok(value) => {
+        *pos += self.partial.len() - r.len() - kept;
+        self.partial.clear();
+        Ok(Some(value))
+      }
+      // Running out of bytes only means the rest hasn't arrived yet.
+      Err(RpcError::ChildDisconnected) => {
+        *pos += available;
+        Ok(None)
+      }
+      Err(e) => Err(e),
+    }
+  }
+
+  // Helper method to get ready for the next message, returning the `<type>`
+  // of the one just parsed.
+  fn finish(&mut self) -> u8 {
+    self.state = ParseState::ArrayLen;
+    self.ty
+  }
+}
+
+// Helper function to copy up to `remaining` bytes of `input[*pos..]` to
+// `buf`, returning how many are still to come.
+fn copy_bytes(input: &[u8], pos: &mut usize, buf: &mut Vec<u8>, remaining: usize) -> usize {
+  let len = remaining.min(input.len() - *pos);
+  buf.extend_from_slice(&input[*pos..*pos + len]);
+  *pos += len;
+  remaining - len
+}
+
+// Helper function to encode a whole message into a new buffer, for writers
+// that aren't `Write`.
+#[cfg(feature = "async")]
+fn encode_message(ty: u8, name: &[u8], payload: &[u8], id: Option<u32>) -> Result<Vec<u8>> {
+  let mut conn = RpcConnection::new(io::empty(), Vec::new())?;
+  conn.write_with_id(ty, name, payload, None, id)?;
+  Ok(conn.writer)
+}
+
+// Helper function to read the length of the array a message starts with.
+fn read_array_len<Rd: BufRead>(r: &mut Rd) -> Result<u32> {
+  let len = rmp::decode::read_array_len(r)?;
+  if !matches!(len, 3..=5) {
+    return Err(RpcError::FramingError(format!(
+      "Message components must be a valid 3-, 4- or 5-part messagepack array, got {len} parts."
+    )));
+  }
+  Ok(len)
+}
+
+// Helper function to skip the optional `<deadline>` and `<id>` items.
+fn skip_items<Rd: BufRead>(r: &mut Rd, count: u32) -> Result<()> {
+  for _ in 0..count {
+    read_optional_u32(r)?;
+  }
+  Ok(())
+}
+
+// Helper function to peek at the next byte from `r`, or `None` at EOF. Like
+// `read_exact`, this retries reads interrupted by a signal rather than
+// failing, as `BufRead::fill_buf` itself doesn't.
+fn peek<Rd: BufRead>(r: &mut Rd) -> io::Result<Option<u8>> {
+  loop {
+    match r.fill_buf() {
+      Ok(available) => return Ok(available.first().copied()),
+      Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+      Err(e) => return Err(e),
+    }
+  }
+}
+
+// Helper function to read an integer that may be `nil` instead.
+fn read_optional_u32<Rd: BufRead>(r: &mut Rd) -> Result<Option<u32>> {
+  let nil = rmp::Marker::Null.to_u8();
+  if peek(r)? == Some(nil) {
+    r.consume(1);
+    return Ok(None);
+  }
+  Ok(Some(rmp::decode::read_int(r)?))
+}
+
+fn read_bin_into<Rd: BufRead>(r: &mut Rd, buf: &mut Vec<u8>, max_len: usize) -> Result<()> {
+  let len = read_bin_len(r, max_len)?;
+  read_exact_into(r, buf, len)
+}
+
+// Helper function to read the next `len` bytes of `r` into `buf`, replacing
+// its contents.
+fn read_exact_into<Rd: BufRead>(r: &mut Rd, buf: &mut Vec<u8>, len: usize) -> Result<()> {
+  buf.clear();
+  buf.resize(len, 0);
+  Ok(r.read_exact(buf)?)
+}
+
+// Helper function to read the length of a `<name>` or `<payload>`, failing if
+// it is too long to read.
+fn read_bin_len<Rd: Read>(r: &mut Rd, max_len: usize) -> Result<usize> {
+  let len = rmp::decode::read_bin_len(r)? as usize;
+  check_len(len, max_len)?;
+  Ok(len)
+}
+
+// Helper function to fail if a `<name>` or `<payload>` is too long to read.
+fn check_len(len: usize, max_len: usize) -> Result<()> {
+  if len > max_len {
+    return Err(RpcError::PayloadTooLarge {
+      len: Some(len),
+      max_len,
+    });
+  }
+  Ok(())
+}
+
+// Helper function to strip the checksum from the end of a payload received
+// while checksums are on, failing if it doesn't match the message.
+fn verify_checksum(ty: u8, bufs: &mut MessageBuffers) -> Result<()> {
+  let Some(split) = bufs.payload.len().checked_sub(4) else {
+    return Err(RpcError::FramingError("Missing payload checksum".into()));
+  };
+  let expected = u32::from_be_bytes(bufs.payload[split..].try_into().expect("4 bytes"));
+  bufs.payload.truncate(split);
+  let actual = crc32(&[&[ty], &bufs.name, bufs.flag.as_slice(), &bufs.payload]);
+  if actual != expected {
+    return Err(RpcError::ChecksumMismatch { expected, actual });
+  }
+  Ok(())
+}
+
+// Helper function to compute the CRC-32 of the concatenation of `parts`.
+fn crc32(parts: &[&[u8]]) -> u32 {
+  let mut crc = !0u32;
+  for &byte in parts.iter().copied().flatten() {
+    crc = CRC32_TABLE[((crc ^ u32::from(byte)) & 0xff) as usize] ^ (crc >> 8);
+  }
+  !crc
+}
+
+/// Lookup table for `crc32`, for the reflected IEEE 802.3 polynomial.
+const CRC32_TABLE: [u32; 256] = {
+  let mut table = [0; 256];
+  let mut i = 0;
+  while i < 256 {
+    let mut crc = i as u32;
+    let mut bit = 0;
+    while bit < 8 {
+      crc = if crc & 1 != 0 {
+        0xedb8_8320 ^ (crc >> 1)
+      } else {
+        crc >> 1
+      };
+      bit += 1;
+    }
+    table[i] = crc;
+    i += 1;
+  }
+  table
+};
+
+// Helper function to decompress a payload received while compression is on,
+// if its flag byte says to, up to `max_len` bytes.
+#[cfg(feature = "zstd")]
+fn decode_payload(bufs: &mut MessageBuffers, max_len: usize) -> Result<()> {
+  match bufs.flag {
+    Some(PAYLOAD_RAW) => {}
+    Some(PAYLOAD_ZSTD) => {
+      let mut decoded = Vec::new();
+      zstd::stream::read::Decoder::new(&bufs.payload[..])?
+        .take(max_len as u64 + 1)
+        .read_to_end(&mut decoded)?;
+      if decoded.len() > max_len {
+        return Err(RpcError::PayloadTooLarge { len: None, max_len });
+      }
+      bufs.payload = decoded;
+    }
+    Some(flag) => {
+      return Err(RpcError::FramingError(format!(
+        "Invalid payload compression flag: {flag}"
+      )))
+    }
+    None => {
+      return Err(RpcError::FramingError(
+        "Missing payload compression flag".into(),
       ))
     }
   }
+  Ok(())
 }
 
-fn to_io<T: std::error::Error>(err: T) -> io::Error {
-  io::Error::other(format!("{err}"))
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // Helper function to create a connection that writes into a `Vec<u8>`.
+  fn writer() -> RpcConnection<&'static [u8], Vec<u8>> {
+    RpcConnection::new(&[][..], Vec::new()).unwrap()
+  }
+
+  // Helper function to create a connection that reads `bytes`.
+  fn reader(bytes: &[u8]) -> RpcConnection<&[u8], io::Sink> {
+    RpcConnection::new(bytes, io::sink()).unwrap()
+  }
+
+  // A reader that has only received the first `arrived` bytes of `bytes`, and
+  // fails with `io::ErrorKind::WouldBlock` once it runs out of them, as a
+  // non-blocking socket does.
+  struct Trickle {
+    bytes: Vec<u8>,
+    arrived: usize,
+    pos: usize,
+  }
+
+  impl Read for Trickle {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+      let available = self.fill_buf()?;
+      let len = available.len().min(buf.len());
+      buf[..len].copy_from_slice(&available[..len]);
+      self.consume(len);
+      Ok(len)
+    }
+  }
+
+  impl BufRead for Trickle {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+      if self.pos == self.arrived && self.arrived < self.bytes.len() {
+        return Err(io::ErrorKind::WouldBlock.into());
+      }
+      Ok(&self.bytes[self.pos..self.arrived])
+    }
+
+    fn consume(&mut self, amt: usize) {
+      self.pos += amt;
+    }
+  }
+
+  // A reader that fails with `io::ErrorKind::Interrupted` on every other
+  // call, as reads interrupted by a signal do, and otherwise only has a
+  // byte at a time, so that reads are interrupted in the middle of every
+  // item.
+  struct Interrupting<R> {
+    inner: R,
+    interrupt: bool,
+  }
+
+  impl<R> Interrupting<R> {
+    fn interrupt(&mut self) -> io::Result<()> {
+      self.interrupt = !self.interrupt;
+      if self.interrupt {
+        return Err(io::ErrorKind::Interrupted.into());
+      }
+      Ok(())
+    }
+  }
+
+  impl<R: Read> Read for Interrupting<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+      self.interrupt()?;
+      let len = buf.len().min(1);
+      self.inner.read(&mut buf[..len])
+    }
+  }
+
+  impl<R: BufRead> BufRead for Interrupting<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+      self.interrupt()?;
+      let available = self.inner.fill_buf()?;
+      Ok(&available[..available.len().min(1)])
+    }
+
+    fn consume(&mut self, amt: usize) {
+      self.inner.consume(amt);
+    }
+  }
+
+  // A writer into a `Vec<u8>` that counts the calls to `flush`.
+  #[derive(Default)]
+  struct Counting {
+    bytes: Vec<u8>,
+    flushes: usize,
+  }
+
+  impl Write for Counting {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      self.bytes.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+      self.flushes += 1;
+      Ok(())
+    }
+  }
+
+  // The `(<type>, <name>, <payload>, <deadline>, <id>)` of a message to
+  // encode.
+  type Message<'a> = (u8, &'a [u8], &'a [u8], Option<u32>, Option<u32>);
+
+  // Helper function to encode `messages` back to back.
+  fn encode(messages: &[Message<'_>]) -> Vec<u8> {
+    let mut conn = writer();
+    for &(ty, name, payload, deadline_ms, id) in messages {
+      conn
+        .write_with_id(ty, name, payload, deadline_ms, id)
+        .unwrap();
+    }
+    conn.writer
+  }
+
+  #[test]
+  fn typed_and_raw_messages_match() {
+    for raw in 1..=11 {
+      let ty = MessageType::try_from(raw).unwrap();
+      assert_eq!(ty as u8, raw);
+      let mut typed = writer();
+      typed.write_frame(ty, b"method", b"payload").unwrap();
+      let mut untyped = writer();
+      untyped.write(raw, b"method", b"payload").unwrap();
+      assert_eq!(typed.writer, untyped.writer);
+
+      let bytes = typed.writer;
+      let (read_ty, name, payload) = reader(&bytes).read_frame().unwrap().unwrap();
+      assert_eq!(
+        (read_ty, &name[..], &payload[..]),
+        (ty, &b"method"[..], &b"payload"[..])
+      );
+      let (read_raw, name, payload) = reader(&bytes).read().unwrap().unwrap();
+      assert_eq!(
+        (read_raw, &name[..], &payload[..]),
+        (raw, &b"method"[..], &b"payload"[..])
+      );
+    }
+  }
+
+  #[test]
+  fn rejects_unknown_message_types() {
+    for raw in [0, 12, u8::MAX] {
+      assert_eq!(MessageType::try_from(raw), Err(InvalidMessageType(raw)));
+      let mut conn = writer();
+      conn.write(raw, b"method", b"").unwrap();
+      let bytes = conn.writer;
+      assert!(matches!(
+        reader(&bytes).read_frame(),
+        Err(RpcError::FramingError(message)) if message == format!("Invalid message type: {raw}")
+      ));
+      // Only the typed API cares.
+      assert_eq!(reader(&bytes).read().unwrap().unwrap().0, raw);
+    }
+  }
+
+  #[test]
+  fn round_trips_optional_items() {
+    let cases = [
+      (None, None),
+      (Some(1500), None),
+      (None, Some(7)),
+      (Some(0), Some(u32::MAX)),
+    ];
+    let mut conn = writer();
+    for (deadline_ms, id) in cases {
+      conn
+        .write_with_id(
+          MessageType::Request as u8,
+          b"method",
+          b"payload",
+          deadline_ms,
+          id,
+        )
+        .unwrap();
+    }
+    let bytes = conn.writer;
+    // A 3-item array, unless there is a `<deadline>` or an `<id>`.
+    assert_eq!(bytes[0], 0x93);
+
+    let mut conn = reader(&bytes);
+    let mut bufs = MessageBuffers::default();
+    for (deadline_ms, id) in cases {
+      assert_eq!(
+        conn.read_into(&mut bufs).unwrap(),
+        Some(MessageType::Request as u8)
+      );
+      assert_eq!(bufs.name, b"method");
+      assert_eq!(bufs.payload, b"payload");
+      assert_eq!((bufs.deadline_ms, bufs.id), (deadline_ms, id));
+    }
+    assert_eq!(conn.read_into(&mut bufs).unwrap(), None);
+  }
+
+  #[test]
+  fn try_read_takes_messages_a_byte_at_a_time() {
+    let big = vec![7; 1000];
+    let messages: [Message<'_>; 3] = [
+      (4, b"big", &big, None, None),
+      (6, b"", b"", Some(100), Some(3)),
+      (4, b"id", b"x", None, Some(u32::MAX)),
+    ];
+    let bytes = encode(&messages);
+    let mut ends = Vec::new();
+    let mut total = 0;
+    for (ty, name, payload, deadline_ms, id) in messages {
+      total += encode(&[(ty, name, payload, deadline_ms, id)]).len();
+      ends.push(total);
+    }
+    let trickle = Trickle {
+      bytes,
+      arrived: 0,
+      pos: 0,
+    };
+    let mut conn = RpcConnection::new(trickle, io::sink()).unwrap();
+    let mut read = Vec::new();
+    while conn.reader.arrived < total {
+      conn.reader.arrived += 1;
+      if let Some(msg) = conn.try_read().unwrap() {
+        read.push((conn.reader.arrived, msg));
+      }
+      if conn.reader.arrived < total {
+        assert!(conn.try_read().unwrap().is_none());
+      }
+    }
+    let expected: Vec<_> = messages
+      .iter()
+      .zip(ends)
+      .map(|(&(ty, name, payload, ..), end)| (end, (ty, name.to_vec(), payload.to_vec())))
+      .collect();
+    assert_eq!(read, expected);
+    assert!(matches!(conn.try_read(), Err(RpcError::ChildDisconnected)));
+  }
+
+  #[test]
+  fn parser_looks_at_each_byte_once() {
+    let bytes = encode(&[(1, b"method", &[1; 100], Some(5), Some(6))]);
+    let mut parser = MessageParser::default();
+    let mut bufs = MessageBuffers::default();
+    for (i, byte) in bytes.iter().enumerate() {
+      let (used, ty) = parser.feed(&[*byte], &mut bufs, 1000).unwrap();
+      assert_eq!(used, 1);
+      assert_eq!(ty.is_some(), i == bytes.len() - 1);
+    }
+    assert!(!parser.in_progress());
+    assert_eq!(bufs.name, b"method");
+    assert_eq!(bufs.payload, [1; 100]);
+    assert_eq!((bufs.deadline_ms, bufs.id), (Some(5), Some(6)));
+
+    // Bytes past the end of a message are left for the next one.
+    let twice = [&bytes[..], &bytes[..]].concat();
+    let (used, ty) = parser.feed(&twice, &mut bufs, 1000).unwrap();
+    assert_eq!((used, ty), (bytes.len(), Some(1)));
+  }
+
+  #[test]
+  fn parser_only_allocates_what_has_arrived() {
+    let len = 100 * 1024 * 1024;
+    let mut bytes = vec![0x93, 0x04, 0xc4, 0x00];
+    rmp::encode::write_bin_len(&mut bytes, len).unwrap();
+    bytes.extend_from_slice(&[1; 10]);
+    let mut parser = MessageParser::default();
+    let mut bufs = MessageBuffers::default();
+    let (used, ty) = parser
+      .feed(&bytes, &mut bufs, DEFAULT_MAX_PAYLOAD_LEN)
+      .unwrap();
+    assert_eq!((used, ty), (bytes.len(), None));
+    assert_eq!(bufs.payload, [1; 10]);
+    assert!(bufs.payload.capacity() < 1024);
+  }
+
+  #[test]
+  fn read_finishes_what_try_read_started() {
+    let bytes = encode(&[(4, b"method", b"payload", None, Some(1))]);
+    let trickle = Trickle {
+      arrived: bytes.len() / 2,
+      bytes,
+      pos: 0,
+    };
+    let mut conn = RpcConnection::new(trickle, io::sink()).unwrap();
+    assert!(conn.try_read().unwrap().is_none());
+    assert!(matches!(conn.read_header(), Err(RpcError::FramingError(_))));
+    conn.reader.arrived = conn.reader.bytes.len();
+    let mut bufs = MessageBuffers::default();
+    assert_eq!(conn.read_into(&mut bufs).unwrap(), Some(4));
+    assert_eq!(
+      (&bufs.name[..], &bufs.payload[..]),
+      (&b"method"[..], &b"payload"[..])
+    );
+    assert_eq!(bufs.id, Some(1));
+    assert_eq!(conn.read().unwrap(), None);
+  }
+
+  #[cfg(feature = "zstd")]
+  #[test]
+  fn reads_compressed_payloads_either_way() {
+    let big = vec![7; 1000];
+    let mut conn = writer();
+    conn.set_compression(Some(100));
+    conn.set_checksums(true);
+    conn.write(4, b"small", b"payload").unwrap();
+    conn.write(4, b"big", &big).unwrap();
+    let bytes = conn.writer;
+    assert!(bytes.len() < big.len());
+    let expected = [
+      (4, b"small".to_vec(), b"payload".to_vec()),
+      (4, b"big".to_vec(), big),
+    ];
+
+    let mut conn = reader(&bytes);
+    conn.set_compression(Some(100));
+    conn.set_checksums(true);
+    assert_eq!(conn.read().unwrap().as_ref(), Some(&expected[0]));
+    assert_eq!(conn.read().unwrap().as_ref(), Some(&expected[1]));
+
+    let trickle = Trickle {
+      arrived: 0,
+      bytes,
+      pos: 0,
+    };
+    let mut conn = RpcConnection::new(trickle, io::sink()).unwrap();
+    conn.set_compression(Some(100));
+    conn.set_checksums(true);
+    let mut read = Vec::new();
+    while conn.reader.arrived < conn.reader.bytes.len() {
+      conn.reader.arrived += 1;
+      read.extend(conn.try_read().unwrap());
+    }
+    assert_eq!(read, expected);
+  }
+
+  #[test]
+  fn skips_payloads_over_the_limit() {
+    let big = vec![1; 100];
+    let bytes = encode(&[
+      (4, b"big", &big, Some(10), Some(2)),
+      (4, b"next", b"small", None, Some(3)),
+    ]);
+    let mut conn = RpcConnection::with_max_payload_len(&bytes[..], io::sink(), 16).unwrap();
+    assert_eq!(
+      conn.read_header().unwrap(),
+      Some((4, b"big".to_vec(), big.len()))
+    );
+    assert!(matches!(conn.read(), Err(RpcError::FramingError(_))));
+    conn.skip_payload(big.len()).unwrap();
+    let mut bufs = MessageBuffers::default();
+    assert_eq!(conn.read_into(&mut bufs).unwrap(), Some(4));
+    assert_eq!(
+      (&bufs.name[..], &bufs.payload[..]),
+      (&b"next"[..], &b"small"[..])
+    );
+    assert_eq!(bufs.id, Some(3));
+    assert_eq!(conn.read().unwrap(), None);
+  }
+
+  #[test]
+  fn retries_interrupted_reads() {
+    let messages: [Message<'_>; 2] = [
+      (1, b"method", b"payload", Some(1), Some(2)),
+      (4, b"method", &[5; 20], None, None),
+    ];
+    let bytes = encode(&messages);
+    let interrupting = || Interrupting {
+      inner: &bytes[..],
+      interrupt: false,
+    };
+
+    let mut conn = RpcConnection::new(interrupting(), io::sink()).unwrap();
+    let mut bufs = MessageBuffers::default();
+    for (ty, name, payload, deadline_ms, id) in messages {
+      assert_eq!(conn.read_into(&mut bufs).unwrap(), Some(ty));
+      assert_eq!((&bufs.name[..], &bufs.payload[..]), (name, payload));
+      assert_eq!((bufs.deadline_ms, bufs.id), (deadline_ms, id));
+    }
+    assert_eq!(conn.read_into(&mut bufs).unwrap(), None);
+
+    let mut conn = RpcConnection::new(interrupting(), io::sink()).unwrap();
+    for (ty, name, payload, ..) in messages {
+      let msg = conn.try_read().unwrap();
+      assert_eq!(msg, Some((ty, name.to_vec(), payload.to_vec())));
+    }
+  }
+
+  #[test]
+  fn messages_end_at_eof() {
+    let bytes = encode(&[(1, b"a", b"1", None, None), (1, b"b", b"2", None, None)]);
+    let mut conn = reader(&bytes);
+    let messages: Vec<_> = conn.messages().map(Result::unwrap).collect();
+    assert_eq!(
+      messages,
+      [
+        (1, b"a".to_vec(), b"1".to_vec()),
+        (1, b"b".to_vec(), b"2".to_vec())
+      ]
+    );
+  }
+
+  #[test]
+  fn messages_stop_after_a_truncated_message() {
+    let bytes = encode(&[(1, b"a", b"1", None, None), (1, b"b", b"2", None, None)]);
+    let mut conn = reader(&bytes[..bytes.len() - 1]);
+    let mut messages = conn.messages();
+    assert_eq!(messages.next().unwrap().unwrap().1, b"a");
+    assert!(matches!(
+      messages.next(),
+      Some(Err(RpcError::ChildDisconnected))
+    ));
+    assert!(messages.next().is_none());
+    assert!(messages.next().is_none());
+  }
+
+  #[test]
+  fn deferred_messages_are_sent_on_flush() {
+    let writer = io::BufWriter::new(Counting::default());
+    let mut conn = RpcConnection::new(&[][..], writer).unwrap();
+    conn.write_deferred(1, b"a", b"1").unwrap();
+    conn
+      .write_deferred_with_id(1, b"b", b"2", None, Some(7))
+      .unwrap();
+    let counting = conn.writer.get_ref();
+    assert!(counting.bytes.is_empty());
+    assert_eq!(counting.flushes, 0);
+
+    conn.flush().unwrap();
+    let counting = conn.writer.get_ref();
+    let expected = encode(&[(1, b"a", b"1", None, None), (1, b"b", b"2", None, Some(7))]);
+    assert_eq!(counting.bytes, expected);
+    assert_eq!(counting.flushes, 1);
+
+    // Other writes flush right away, along with anything deferred before.
+    conn.write_deferred(1, b"c", b"3").unwrap();
+    conn.write(1, b"d", b"4").unwrap();
+    let counting = conn.writer.get_ref();
+    let rest = encode(&[(1, b"c", b"3", None, None), (1, b"d", b"4", None, None)]);
+    assert_eq!(counting.bytes[expected.len()..], rest);
+    assert_eq!(counting.flushes, 2);
+  }
+
+  #[test]
+  fn into_parts_leaves_the_rest_unread() {
+    let bytes = encode(&[(1, b"a", b"1", None, None), (1, b"b", b"2", None, None)]);
+    let mut conn = reader(&bytes);
+    assert_eq!(conn.read().unwrap().unwrap().1, b"a");
+    let (rest, _) = conn.into_parts().unwrap();
+    let mut conn = reader(rest);
+    assert_eq!(
+      conn.read().unwrap(),
+      Some((1, b"b".to_vec(), b"2".to_vec()))
+    );
+    assert_eq!(conn.read().unwrap(), None);
+  }
+
+  #[test]
+  fn rejects_lengths_over_the_limit() {
+    // A `<payload>` of `u32::MAX` bytes, the longest a bin32 can claim.
+    let bytes = [0x93, 0x04, 0xc4, 0x00, 0xc6, 0xff, 0xff, 0xff, 0xff];
+    assert!(matches!(
+      reader(&bytes).read(),
+      Err(RpcError::PayloadTooLarge { len: Some(len), max_len: DEFAULT_MAX_PAYLOAD_LEN })
+        if len == u32::MAX as usize
+    ));
+    // `read_header` leaves it to the caller to skip.
+    assert_eq!(
+      reader(&bytes).read_header().unwrap(),
+      Some((4, Vec::new(), u32::MAX as usize))
+    );
+
+    // There is no wider `bin`: an 8-byte length is not one at all.
+    let bytes = [0x93, 0x04, 0xc4, 0x00, 0xcf, 0, 0, 0, 1, 0, 0, 0, 0];
+    assert!(matches!(
+      reader(&bytes).read(),
+      Err(RpcError::FramingError(_))
+    ));
+  }
+
+  #[test]
+  fn create_error_tells_name_mismatches_apart() {
+    let conn = writer();
+    assert!(matches!(
+      conn.create_error("method", b"failed".to_vec(), "method"),
+      RpcError::RemoteError(message) if message == "failed"
+    ));
+    assert!(matches!(
+      conn.create_error("method", vec![0xff; 40], "method"),
+      RpcError::RemoteError(message) if message.starts_with("remote error (non-UTF-8, 40 bytes): ffff")
+    ));
+    assert!(matches!(
+      conn.create_error("other", b"failed".to_vec(), "method"),
+      RpcError::NameMismatch { expected, actual } if expected == "method" && actual == "other"
+    ));
+  }
 }
@@ -1,4 +1,6 @@
-import { on, once } from "node:events";
+import { EventEmitter, on, once } from "node:events";
+import { writeFileSync } from "node:fs";
+import zlib from "node:zlib";
 import { PackrStream, UnpackrStream } from "msgpackr";
 import { MessageType } from './index.js';
 
@@ -7,74 +9,263 @@ const packStream = new PackrStream();
 process.stdin.pipe(unpackStream);
 packStream.pipe(process.stdout);
 
+// Whether payload compression has been negotiated (zstd is only available
+// in newer versions of node).
+let compression = false;
+const COMPRESSION_THRESHOLD = 1024;
+
+// Whether message checksums have been negotiated.
+let checksums = false;
+
+// Incoming messages, with their payloads decompressed if need be.
+const incoming = new EventEmitter();
+unpackStream.on("data", msg => {
+    if (checksums) {
+        msg[2] = verifyChecksum(msg[0], msg[1], msg[2]);
+    }
+    if (compression) {
+        msg[2] = decodePayload(msg[2]);
+    }
+    incoming.emit("data", msg);
+});
+
 const DECODER = new TextDecoder();
 const ENCODER = new TextEncoder();
 
-let pendingCallResponse = false;
+let pendingCallResponses = 0;
 
-for await (const msgs of on(unpackStream, "data")) {
-    for (const [ty, binName, payload] of msgs) {
+// The error `call` throws when the parent responds with a CallError.
+class CallFailed extends Error {}
+
+// If given a path, signal readiness by creating a file there after a delay.
+const readyFile = process.argv[2];
+if (readyFile) {
+    setTimeout(() => writeFileSync(readyFile, ""), 200);
+}
+
+for await (const msgs of on(incoming, "data")) {
+    for (const [ty, binName, payload, deadlineMs, id] of msgs) {
         const name = DECODER.decode(binName);
-        top: switch (ty) {
-            case MessageType.Request:
-                switch (name) {
-                    case "echo":
-                        await write(MessageType.Response, name, payload);
-                        break top;
-                    case "callback-echo":
-                        const resPayload = await call("echo", payload);
-                        await write(MessageType.Response, name, resPayload);
-                        break top;
-                    case "concat":
-                        const one = await call("one", "1");
-                        const two = await call("two", "2");
-                        const three = await call("three", "3");
-                        const ret = new Uint8Array(one.length + two.length + three.length);
-                        ret.set(one);
-                        ret.set(two, one.length);
-                        ret.set(three, one.length + two.length);
-                        await write(MessageType.Response, name, ret);
-                        break top;
-                    case "error":
-                        await write(MessageType.Error, name, "\"something went wrong\"");
-                        break top;
-                    case "throw":
-                        await write(MessageType.Call, name, "");
-                        pendingCallResponse = true;
-                        const [[resTy, resName]] = await once(unpackStream, "data");
-                        const decResName = DECODER.decode(resName);
-                        if (resTy != MessageType.CallError || decResName) {
-                            throw new Error(`Unexpected response: (${resTy}) ${decResName}`);
-                        }
-                        // Do nothing
-                        break top;
-                }
-                break;
-            case MessageType.CallResponse:
-                if (pendingCallResponse) {
-                    pendingCallResponse = false;
-                } else {
-                    throw new Error("Unexpected CallResponse");
-                }
-                break;
-            default:
-                throw new Error(`Unexpected message: (${ty}) ${name}`)
+        try {
+            top: switch (ty) {
+                case MessageType.Request:
+                    switch (name) {
+                        case "$/handshake":
+                            await write(MessageType.Response, name, "1");
+                            break top;
+                        case "$/compression":
+                            if (zlib.zstdCompressSync && DECODER.decode(payload) == "zstd") {
+                                await write(MessageType.Response, name, "");
+                                compression = true;
+                            } else {
+                                await write(MessageType.Error, name, "unsupported");
+                            }
+                            break top;
+                        case "$/checksum":
+                            if (DECODER.decode(payload) == "crc32") {
+                                await write(MessageType.Response, name, "");
+                                checksums = true;
+                            } else {
+                                await write(MessageType.Error, name, "unsupported");
+                            }
+                            break top;
+                        case "$/ping":
+                            await write(MessageType.Response, name, "");
+                            break top;
+                        case "echo":
+                            await write(MessageType.Response, name, payload, id);
+                            break top;
+                        case "delayed":
+                            // Echo the payload after a delay, without holding up
+                            // the requests that follow.
+                            setTimeout(() => write(MessageType.Response, name, payload, id), 50);
+                            break top;
+                        case "callback-echo":
+                            const resPayload = await call("echo", payload);
+                            await write(MessageType.Response, name, resPayload);
+                            break top;
+                        case "call-named":
+                            // Call the callback the payload names.
+                            await write(MessageType.Response, name, await call(DECODER.decode(payload), ""));
+                            break top;
+                        case "renamed":
+                            // Respond under the name the payload gives.
+                            await write(MessageType.Response, DECODER.decode(payload), payload, id);
+                            break top;
+                        case "empty":
+                            await write(MessageType.Response, name, "");
+                            break top;
+                        case "stream":
+                            // Stream back the payload, one byte at a time.
+                            for (const byte of payload) {
+                                await write(MessageType.ResponseChunk, name, new Uint8Array([byte]));
+                            }
+                            await write(MessageType.Response, name, "");
+                            break top;
+                        case "concat":
+                            const one = await call("one", "1");
+                            const two = await call("two", "2");
+                            const three = await call("three", "3");
+                            const ret = new Uint8Array(one.length + two.length + three.length);
+                            ret.set(one);
+                            ret.set(two, one.length);
+                            ret.set(three, one.length + two.length);
+                            await write(MessageType.Response, name, ret);
+                            break top;
+                        case "batch":
+                            // Send all calls before reading any of their responses.
+                            const responses = collect(3);
+                            await write(MessageType.Call, "one", "1");
+                            await write(MessageType.Call, "two", "2");
+                            await write(MessageType.Call, "three", "3");
+                            pendingCallResponses += 3;
+                            const payloads = (await responses).map(([resTy, resName, resPayload]) => {
+                                if (resTy != MessageType.CallResponse) {
+                                    throw new Error(`Expected CallResponse but got ${resTy}`);
+                                }
+                                return resPayload;
+                            });
+                            await write(MessageType.Response, name, concatBytes(payloads));
+                            break top;
+                        case "deadline":
+                            // Pretend the work takes 100ms, and give up early if
+                            // the deadline doesn't allow for it.
+                            if (deadlineMs != null && deadlineMs < 100) {
+                                await write(MessageType.Error, name, "Deadline exceeded");
+                            } else {
+                                await write(MessageType.Response, name, "done");
+                            }
+                            break top;
+                        case "cancellable":
+                            // Check in with the parent, then give up if it
+                            // cancels the request in the meantime.
+                            const cancelled = waitForCancel(100, id);
+                            await call("check-in", payload);
+                            if (await cancelled) {
+                                await write(MessageType.Error, name, JSON.stringify({ code: "ECANCELED", message: "request was cancelled" }), id);
+                            } else {
+                                await write(MessageType.Response, name, "done", id);
+                            }
+                            break top;
+                        case "error":
+                            await write(MessageType.Error, name, "\"something went wrong\"", id);
+                            break top;
+                        case "coded-error":
+                            await write(MessageType.Error, name, JSON.stringify({ code: "ENOENT", message: "no such file", data: { path: "/nope" } }));
+                            break top;
+                        case "binary-error":
+                            // 40 bytes that aren't valid UTF-8.
+                            await write(MessageType.Error, name, new Uint8Array(40).fill(0xff, 0, 1).fill(0xab, 1));
+                            break top;
+                        case "hang":
+                            // Never respond.
+                            break top;
+                        case "pid":
+                            await write(MessageType.Response, name, String(process.pid));
+                            break top;
+                        case "corrupt":
+                            // Flip a bit of the payload after its checksum
+                            // was computed.
+                            await write(MessageType.Response, name, payload, id, data => data[0] ^= 1);
+                            break top;
+                        case "cwd":
+                            await write(MessageType.Response, name, process.cwd());
+                            break top;
+                        case "env":
+                            await write(MessageType.Response, name, JSON.stringify(process.env[DECODER.decode(payload)] ?? null));
+                            break top;
+                        case "stderr":
+                            process.stderr.write("first line\nsecond line\n");
+                            await write(MessageType.Response, name, "");
+                            break top;
+                        case "cbor-fixed":
+                            // {"a": [1, -2, "x", true, null, 1.5 (as a half float)]}
+                            await write(MessageType.Response, name, new Uint8Array([
+                                0xa1, 0x61, 0x61, 0x86, 0x01, 0x21, 0x61, 0x78, 0xf5, 0xf6, 0xf9, 0x3e, 0x00,
+                            ]));
+                            break;
+                        case "cbor-truncated":
+                            // An array of two items, with only one.
+                            await write(MessageType.Response, name, new Uint8Array([0x82, 0x01]));
+                            break;
+                        case "log":
+                            // Log in the middle of a request, around a callback.
+                            await write(MessageType.Log, "", "starting");
+                            const logged = await call("echo", payload);
+                            await write(MessageType.Log, "", "finishing");
+                            await write(MessageType.Response, name, logged);
+                            break;
+                        case "notify":
+                            // Notify around the response, then again once idle.
+                            await write(MessageType.Notify, "progress", "started");
+                            await write(MessageType.Response, name, payload);
+                            await write(MessageType.Notify, "progress", "done");
+                            setTimeout(() => write(MessageType.Notify, "idle", payload), 20);
+                            break;
+                        case "exit":
+                            process.exit(3);
+                        case "throw":
+                            // Recover from the callback failing.
+                            try {
+                                await call(name, "");
+                            } catch (e) {
+                                if (!(e instanceof CallFailed)) {
+                                    throw e;
+                                }
+                                await write(MessageType.Response, name, "recovered");
+                                break top;
+                            }
+                            throw new Error("Expected the callback to fail");
+                    }
+                    break;
+                case MessageType.CallResponse:
+                case MessageType.CallError:
+                    if (pendingCallResponses > 0) {
+                        pendingCallResponses--;
+                    } else {
+                        throw new Error("Unexpected CallResponse");
+                    }
+                    break;
+                case MessageType.Cancel:
+                    // Handled by the request it cancels, if still in progress.
+                    break;
+                case MessageType.Shutdown:
+                    process.exit(0);
+                default:
+                    throw new Error(`Unexpected message: (${ty}) ${name}`)
+            }
+        } catch (e) {
+            // The child gives up on a request when a callback it made fails.
+            if (!(e instanceof CallFailed)) {
+                throw e;
+            }
+            await write(MessageType.Error, name, e.message, id);
         }
     }
 }
 
-async function write(ty, name, payload) {
+// `corrupt`, if given, is called with the payload as sent, to tamper with.
+async function write(ty, name, payload, id, corrupt) {
     const ret = await new Promise((resolve, reject) => {
-        packStream.write([ty, bin(name), bin(payload)], (x) => x ? reject(x) : resolve());
+        let data = compression ? encodePayload(bin(payload)) : bin(payload);
+        if (checksums) {
+            data = concatBytes([data, checksum(ty, bin(name), data)]);
+        }
+        corrupt?.(data);
+        const msg = id === undefined ? [ty, bin(name), data] : [ty, bin(name), data, null, id];
+        packStream.write(msg, (x) => x ? reject(x) : resolve());
     });
     return ret;
 }
 
 async function call(name, payload) {
-    const waiter = once(unpackStream, "data");
+    const waiter = once(incoming, "data");
     await write(MessageType.Call, name, payload);
-    pendingCallResponse = true;
+    pendingCallResponses++;
     const [[resTy, resName, resPayload]] = await waiter;
+    if (resTy == MessageType.CallError) {
+        throw new CallFailed(DECODER.decode(resPayload));
+    }
     if (resTy != MessageType.CallResponse) {
         throw new Error(`Expected CallResponse but got ${resTy}`);
     }
@@ -85,6 +276,93 @@ async function call(name, payload) {
     return resPayload;
 }
 
+function collect(count) {
+    return new Promise(resolve => {
+        const msgs = [];
+        const onData = msg => {
+            msgs.push(msg);
+            if (msgs.length == count) {
+                incoming.off("data", onData);
+                resolve(msgs);
+            }
+        };
+        incoming.on("data", onData);
+    });
+}
+
+// Resolves to whether a `Cancel` carrying `id` (if the request had one)
+// arrives within `timeoutMs`.
+function waitForCancel(timeoutMs, id) {
+    return new Promise(resolve => {
+        const done = cancelled => {
+            incoming.off("data", onData);
+            clearTimeout(timer);
+            resolve(cancelled);
+        };
+        const onData = ([ty, , , , cancelId]) => ty == MessageType.Cancel && cancelId === id && done(true);
+        const timer = setTimeout(done, timeoutMs, false);
+        incoming.on("data", onData);
+    });
+}
+
+function concatBytes(arrays) {
+    const ret = new Uint8Array(arrays.reduce((len, arr) => len + arr.length, 0));
+    let offset = 0;
+    for (const arr of arrays) {
+        ret.set(arr, offset);
+        offset += arr.length;
+    }
+    return ret;
+}
+
+// Prefixes a payload with a flag byte saying whether it is compressed.
+function encodePayload(payload) {
+    if (payload.length >= COMPRESSION_THRESHOLD) {
+        const compressed = zlib.zstdCompressSync(payload);
+        if (compressed.length < payload.length) {
+            return concatBytes([[1], compressed]);
+        }
+    }
+    return concatBytes([[0], payload]);
+}
+
+function decodePayload(payload) {
+    switch (payload[0]) {
+        case 0:
+            return payload.subarray(1);
+        case 1:
+            return new Uint8Array(zlib.zstdDecompressSync(payload.subarray(1)));
+        default:
+            throw new Error(`Invalid payload compression flag: ${payload[0]}`);
+    }
+}
+
+// The big-endian CRC-32 of a message's type, name and payload.
+function checksum(ty, name, payload) {
+    let crc = ~0;
+    for (const bytes of [[ty], name, payload]) {
+        for (const byte of bytes) {
+            crc ^= byte;
+            for (let bit = 0; bit < 8; bit++) {
+                crc = crc & 1 ? (crc >>> 1) ^ 0xedb88320 : crc >>> 1;
+            }
+        }
+    }
+    const ret = new Uint8Array(4);
+    new DataView(ret.buffer).setUint32(0, ~crc >>> 0);
+    return ret;
+}
+
+// Strips the checksum off a payload, throwing if it doesn't match.
+function verifyChecksum(ty, name, payload) {
+    const data = payload.subarray(0, payload.length - 4);
+    const expected = checksum(ty, name, data);
+    if (expected.some((byte, i) => byte != payload[data.length + i])) {
+        throw new Error(`Checksum mismatch for message: (${ty}) ${DECODER.decode(name)}`);
+    }
+    return data;
+}
+
 function bin(input) {
     return typeof input === "string" ? ENCODER.encode(input) : input;
 }
\ No newline at end of file
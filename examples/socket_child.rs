@@ -1,74 +1,112 @@
 use std::io::{self, BufReader, BufWriter, Stdin, Stdout};
 
-use libsyncrpc_connection::RpcConnection;
-
-#[allow(non_snake_case)]
-mod MessageType {
-  #[allow(non_upper_case_globals)]
-  pub const Request: u8 = 1;
-  #[allow(non_upper_case_globals)]
-  pub const CallResponse: u8 = 2;
-  #[allow(non_upper_case_globals)]
-  pub const CallError: u8 = 3;
-  #[allow(non_upper_case_globals)]
-  pub const Response: u8 = 4;
-  #[allow(non_upper_case_globals)]
-  pub const Error: u8 = 5;
-  #[allow(non_upper_case_globals)]
-  pub const Call: u8 = 6;
-}
+use libsyncrpc_connection::{MessageBuffers, MessageType, RpcConnection, PROTOCOL_VERSION};
 
 static BIG_ARR: [u8; 1024 * 1024] = [0; 1024 * 1024];
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
   let mut conn = RpcConnection::new(BufReader::new(io::stdin()), BufWriter::new(io::stdout()))?;
   // eprintln!("Child initialized?");
-  loop {
-    let (ty, name, payload) = conn.read()?;
-    match (ty, &name[..], &payload[..]) {
+  let mut bufs = MessageBuffers::default();
+  while let Some(ty) = conn.read_into(&mut bufs)? {
+    match (
+      MessageType::try_from(ty)?,
+      &bufs.name[..],
+      &bufs.payload[..],
+    ) {
       (MessageType::Request, b"echo", payload) => {
         // Just echo it
-        conn.write(MessageType::Response, b"echo", payload)?;
+        conn.write_frame(MessageType::Response, b"echo", payload)?;
       }
       (MessageType::Request, b"callback-echo", payload) => {
+        // Log messages can be sent at any point, and are never responded to.
+        conn.write_frame(MessageType::Log, b"", b"calling back to echo")?;
         let res_payload = call(&mut conn, b"echo", payload)?;
-        conn.write(MessageType::Response, b"callback-echo", &res_payload)?;
+        conn.write_frame(MessageType::Response, b"callback-echo", &res_payload)?;
       }
       (MessageType::Request, b"binary", _) => {
-        conn.write(MessageType::Response, b"binary", &BIG_ARR)?;
+        conn.write_frame(MessageType::Response, b"binary", &BIG_ARR)?;
+      }
+      (MessageType::Request, b"$/handshake", _) => {
+        let version = PROTOCOL_VERSION.to_string();
+        conn.write_frame(MessageType::Response, b"$/handshake", version.as_bytes())?;
+      }
+      #[cfg(feature = "zstd")]
+      (MessageType::Request, b"$/compression", b"zstd") => {
+        conn.write_frame(MessageType::Response, b"$/compression", b"")?;
+        conn.set_compression(Some(1024));
+      }
+      (MessageType::Request, b"$/ping", _) => {
+        conn.write_frame(MessageType::Response, b"$/ping", b"")?;
+      }
+      (MessageType::Request, b"stream", payload) => {
+        // Stream back the payload, one byte at a time.
+        for byte in payload {
+          conn.write_frame(MessageType::ResponseChunk, b"stream", &[*byte])?;
+        }
+        conn.write_frame(MessageType::Response, b"stream", b"")?;
       }
       (MessageType::Request, b"empty", _) => {
-        conn.write(MessageType::Response, b"empty", b"")?;
+        conn.write_frame(MessageType::Response, b"empty", b"")?;
       }
       (MessageType::Request, b"concat", _) => {
         let one = call(&mut conn, b"one", b"1")?;
         let two = call(&mut conn, b"two", b"2")?;
         let three = call(&mut conn, b"three", b"3")?;
-        conn.write(
+        conn.write_frame(
           MessageType::Response,
           b"concat",
           &[one, two, three].concat(),
         )?;
       }
+      (MessageType::Request, b"batch", _) => {
+        // Send all calls before reading any of their responses.
+        let names: [&[u8]; 3] = [b"one", b"two", b"three"];
+        for (name, payload) in names.iter().zip([b"1", b"2", b"3"]) {
+          conn.write_frame(MessageType::Call, name, payload)?;
+        }
+        let mut res_payload = Vec::new();
+        for name in names {
+          res_payload.extend(read_call_response(&mut conn, name)?);
+        }
+        conn.write_frame(MessageType::Response, b"batch", &res_payload)?;
+      }
+      (MessageType::Request, b"deadline", _) => {
+        // Pretend the work takes 100ms, and give up early if the deadline
+        // doesn't allow for it.
+        if bufs
+          .deadline_ms
+          .is_some_and(|deadline_ms| deadline_ms < 100)
+        {
+          conn.write_frame(MessageType::Error, b"deadline", b"Deadline exceeded")?;
+        } else {
+          conn.write_frame(MessageType::Response, b"deadline", b"done")?;
+        }
+      }
+      (MessageType::Shutdown, _, _) => {
+        break;
+      }
       (MessageType::Request, b"error", _) => {
-        conn.write(MessageType::Error, b"error", b"\"something went wrong\"")?;
+        conn.write_frame(MessageType::Error, b"error", b"\"something went wrong\"")?;
       }
       (MessageType::Request, b"throw", _) => {
-        conn.write(MessageType::Call, b"throw", b"")?;
-        let (ty, name, _) = conn.read()?;
+        conn.write_frame(MessageType::Call, b"throw", b"")?;
+        let (ty, name, _) = conn.read_frame()?.ok_or("connection closed")?;
         if ty != MessageType::CallError || &name != b"throw" {
           panic!("Unexpected response : {:?}\\t{:?}\\t...", ty, name);
         }
-        // Do nothing
+        // The request stays open after a `CallError`, so recover from it.
+        conn.write_frame(MessageType::Response, b"throw", b"recovered")?;
       }
       (ty, name, _) => {
         panic!(
-          "Unexpected message : ({ty}) {}",
+          "Unexpected message : ({ty:?}) {}",
           String::from_utf8_lossy(name)
         );
       }
     }
   }
+  Ok(())
 }
 
 fn call(
@@ -76,10 +114,19 @@ fn call(
   name: &[u8],
   payload: &[u8],
 ) -> io::Result<Vec<u8>> {
-  conn.write(MessageType::Call, name, payload)?;
-  let (res_ty, res_name, res_payload) = conn.read()?;
+  conn.write_frame(MessageType::Call, name, payload)?;
+  read_call_response(conn, name)
+}
+
+fn read_call_response(
+  conn: &mut RpcConnection<BufReader<Stdin>, BufWriter<Stdout>>,
+  name: &[u8],
+) -> io::Result<Vec<u8>> {
+  let (res_ty, res_name, res_payload) = conn
+    .read_frame()?
+    .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
   if res_ty != MessageType::CallResponse {
-    panic!("Expected a CallResponse but got {res_ty}");
+    panic!("Expected a CallResponse but got {res_ty:?}");
   }
   if res_name != name {
     panic!(
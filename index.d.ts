@@ -1,5 +1,30 @@
 /* auto-generated by NAPI-RS */
 /* eslint-disable */
+/**
+ * Cancels the requests of the `SyncRpcChannel` it was obtained from, see
+ * `SyncRpcChannel#cancellationHandle`.
+ */
+export declare class CancellationHandle {
+  /**
+   * Asks the child to cancel the channel's request in progress by sending
+   * it a `MessageType.Cancel` (or one per outstanding request, for
+   * `requestBatchSync`). A child that abandons the request responds with a
+   * structured `MessageType.Error` with code `"ECANCELED"`, which the
+   * request throws (or rejects with) like any structured error, so
+   * cancellation can be told apart by the error's `code`. A child that
+   * ignores the cancellation responds as usual.
+   *
+   * The channel only gets to send the cancellation once the child invokes
+   * a callback: it is sent as soon as that callback returns. Calling this
+   * from within a callback invoked during the request, whether synchronous
+   * or made with `requestAsync`, is therefore the supported way to cancel a
+   * request. Calling it from elsewhere while a `requestAsync` is in progress
+   * only takes effect if the child invokes a callback later on, and calling
+   * it when no request is in progress does nothing.
+   */
+  cancel(): void
+}
+
 /**
  * A synchronous RPC channel that allows JavaScript to synchronously call out
  * to a child process and get a response over a line-based protocol,
@@ -12,15 +37,35 @@
  * integers, including the `<type>` and `<name>`, to avoid unnecessary
  * encoding/decoding at the protocol level.
  *
+ * Some messages carry optional 4th and 5th items, see `MessageType.Request`.
+ *
  * For specific message types and their corresponding protocol behavior, please
  * see `MessageType` below.
  */
 export declare class SyncRpcChannel {
   /**
    * Constructs a new `SyncRpcChannel` by spawning a child process with the
-   * given `exe` executable, and a given set of `args`.
+   * given `exe` executable, and a given set of `args`. See `ChannelOptions`
+   * for further settings.
    */
-  constructor(exe: string, args: Array<string>)
+  constructor(exe: string, args: Array<string>, options?: ChannelOptions | undefined | null)
+  /**
+   * Constructs a new `SyncRpcChannel` that talks to a process started by
+   * someone else, such as a process manager that supervises it, rather than
+   * spawning one. `stdinFd` is the descriptor to write requests to, as the
+   * process's stdin would be, and `stdoutFd` the one to read its responses
+   * from. On Windows, these are handles rather than file descriptors.
+   *
+   * The channel takes ownership of both, which must be separate pipes (or
+   * other files) rather than a single socket, and puts `stdinFd` in
+   * non-blocking mode. Options that concern spawning the process, such as
+   * `cwd`, `env`, `stderr` and `restartPolicy`, are ignored.
+   *
+   * The channel doesn't own the process, so `pid`, `exitStatus`, `signal`,
+   * `isAlive`, `terminate`, `setIdleTimeout` and `respawn` throw, and
+   * `close()` only closes the connection, leaving the process running.
+   */
+  static fromRaw(stdinFd: number, stdoutFd: number, options?: ChannelOptions | undefined | null): SyncRpcChannel
   /**
    * Send a request to the child process and wait for a response. The method
    * will not return, synchronously, until a response is received or an error
@@ -28,8 +73,66 @@ export declare class SyncRpcChannel {
    *
    * This method will take care of encoding and decoding the binary payload to
    * and from a JS string automatically and suitable for smaller payloads.
+   *
+   * If the connection to the child breaks, the thrown error's `code` says
+   * how: `ERR_FRAMING` for a malformed message, `ERR_PAYLOAD_TOO_LARGE` for
+   * one over `ChannelOptions.maxPayloadLength`, `ERR_CHECKSUM` for a corrupted
+   * one, `ERR_NAME_MISMATCH` for a response to something else,
+   * `ERR_CHILD_DISCONNECTED` if the child closed the connection in the middle
+   * of a message, and `ERR_IO` if reading or writing failed.
+   *
+   * Errors other than structured ones reported by the child (see
+   * `MessageType.Error`) say how long the request took to fail, as in
+   * "request `compile` failed after 4213ms: ...".
    */
   requestSync(method: string, payload: string): string
+  /**
+   * Like `requestSync`, but throws if the request, including any callbacks
+   * it invokes, does not complete within `timeoutMs` milliseconds.
+   *
+   * This includes sending the request, so a child that stopped reading its
+   * stdin can't block the channel forever either, except on Windows, where
+   * sending blocks until the child has read the request.
+   *
+   * Because the child may still send its response after the deadline, a
+   * timed-out channel cannot tell a late response apart from the next one.
+   * The channel is therefore poisoned after a timeout: all subsequent
+   * requests will throw immediately, and it should be closed and replaced,
+   * or waited out with `resync`.
+   * With `ChannelOptions.requestIds` set, the late response can be told apart,
+   * so the channel stays usable, unless the timeout hit while only part of a
+   * message had arrived.
+   */
+  requestSyncTimeout(method: string, payload: string, timeoutMs: number): string
+  /**
+   * Like `requestSync`, but also tells the child that it has `deadlineMs`
+   * milliseconds to respond, so that a cooperating child can abandon the
+   * work itself (typically responding with a `MessageType.Error`) rather than
+   * having the channel give up on it. See `MessageType.Request` for how the
+   * deadline is sent.
+   *
+   * As a backstop, the request times out as with `requestSyncTimeout` if the
+   * child has not responded a short grace period after the deadline.
+   */
+  requestSyncWithDeadline(method: string, payload: string, deadlineMs: number): string
+  /**
+   * Like `requestSync`, but only lets the child invoke the callbacks named
+   * in `allowedCallbacks` during this request. Invoking any other callback,
+   * registered or not, sends the child a `MessageType.CallError` without
+   * calling anything on the JavaScript side, including the default callback
+   * (see `registerDefaultCallback`).
+   */
+  requestSyncScoped(method: string, payload: string, allowedCallbacks: Array<string>): string
+  /**
+   * Like `requestSync`, but an error reported by the child is returned as
+   * `{ ok: false, error }` instead of being thrown, which is cheaper for
+   * requests where such errors are routine. A successful response is
+   * returned as `{ ok: true, value }`.
+   *
+   * Failures of the channel itself, such as a framing error or the child
+   * exiting, are still thrown.
+   */
+  tryRequestSync(method: string, payload: string): TryRequestResult
   /**
    * Send a request to the child process and wait for a response. The method
    * will not return, synchronously, until a response is received or an error
@@ -38,22 +141,740 @@ export declare class SyncRpcChannel {
    * Unlike `requestSync`, this method will not do any of its own encoding or
    * decoding of payload data. Everything will be as sent/received through the
    * underlying protocol.
+   *
+   * Neither direction copies the payload on the native side: the request is
+   * written straight out of the given array, and the returned array takes
+   * ownership of the received bytes. Runtimes that disallow external array
+   * buffers (such as Electron's V8 sandbox) will still copy the response.
    */
   requestBinarySync(method: string, payload: Uint8Array): Uint8Array
+  /**
+   * Like `requestBinarySync`, but takes and returns a Node.js `Buffer`. The
+   * response is no more copied than there: the returned `Buffer` is created
+   * over the bytes read from the child, which are freed once it is garbage
+   * collected.
+   */
+  requestBufferSync(method: string, payload: Buffer): Buffer
+  /**
+   * Like `requestBinarySync`, but for responses that may be either text or
+   * binary: a response that is valid UTF-8 is returned as a string, with
+   * `type: "text"`, and any other as a `Uint8Array`, with `type: "binary"`.
+   * Neither is decoded lossily, and an invalid UTF-8 response doesn't throw
+   * as it would with `requestSync`.
+   *
+   * Note that binary responses that happen to be valid UTF-8, such as empty
+   * ones, are returned as text.
+   */
+  requestAutoSync(method: string, payload: Uint8Array): AutoResponse
+  /**
+   * Sends all of `requests` to the child before waiting for any of their
+   * responses, to save a round trip per request when making several
+   * independent requests. Payloads are passed as is, as with
+   * `requestBinarySync`.
+   *
+   * Results are returned in the order of `requests`, whatever order the
+   * child responds in. As with `tryRequestSync`, an error reported by the
+   * child for a request is returned as `{ ok: false, error }` rather than
+   * thrown, while failures of the channel itself are thrown.
+   *
+   * Each request carries an `<id>` that the child must echo in its
+   * response (see `MessageType.Request`). Callbacks invoked by the child
+   * while it works on the batch are handled as usual. Since every request is
+   * written before any response is read, the child must keep reading
+   * requests while it responds to earlier ones, or a large enough batch can
+   * deadlock once the pipes between them fill up.
+   */
+  requestBatchSync(requests: Array<BatchRequest>): Array<BatchResult>
+  /**
+   * Like `requestBinarySync`, but lets the child stream its response as any
+   * number of `MessageType.ResponseChunk` messages before the final
+   * `MessageType.Response`, to avoid buffering very large responses in full.
+   *
+   * `onChunk` is called with the `<payload>` of each chunk, synchronously
+   * and in the order the child sent them, before this method returns the
+   * `<payload>` of the final response (which is often empty). Callbacks
+   * invoked by the child may be interleaved with the chunks.
+   *
+   * If `onChunk` throws, the remaining chunks are discarded and the error is
+   * thrown once the child has finished responding.
+   */
+  requestStreamSync(method: string, payload: Uint8Array, onChunk: (chunk: Uint8Array) => void): Uint8Array
+  /**
+   * Like `requestSync`, but takes care of JSON encoding the `payload` and
+   * decoding the response, for children that speak JSON.
+   *
+   * Throws if the response is not valid JSON, including the start of the
+   * offending response in the error message.
+   */
+  requestJson(method: string, payload: any): any
+  /**
+   * Like `requestJson`, but encodes the `payload` and decodes the response as
+   * CBOR (RFC 8949), which is usually smaller and faster to parse for deeply
+   * nested data. Integers are sent as CBOR integers, other numbers as 64-bit
+   * floats, and objects as maps with string keys.
+   *
+   * In the response, byte strings are decoded as arrays of numbers, tags are
+   * ignored, and `undefined` and non-finite floats are decoded as `null`.
+   * Map keys must be strings, and indefinite-length items are not
+   * supported. Throws if the response is not valid CBOR, including the byte
+   * offset of the failure in the error message.
+   */
+  requestCbor(method: string, payload: any): any
+  /**
+   * Sends a request with an empty payload to the child process and waits for
+   * it to acknowledge it with an empty response, for requests that are only
+   * made for their side effects.
+   *
+   * Throws if the child responds with an error, or with a non-empty payload.
+   */
+  requestAckSync(method: string): void
+  /**
+   * Like `requestSync`, but makes the request from a worker thread and
+   * returns a promise for the response, leaving the JavaScript thread free
+   * while the child works. Only during these requests can the child invoke
+   * callbacks registered with `registerAsyncCallback`; it is sent a
+   * `MessageType.CallError` if it invokes any other kind of callback.
+   *
+   * The channel can only do one thing at a time: until the promise settles,
+   * its other methods that talk to the child (including `requestAsync`
+   * itself) throw instead of waiting for it. Each request in progress also
+   * occupies a thread of libuv's thread pool.
+   */
+  requestAsync(method: string, payload: string): Promise<string>
+  /**
+   * Returns a handle that can cancel the channel's requests while they are
+   * in progress, see `CancellationHandle#cancel`. The channel itself can't
+   * be used from within a callback, but the handle can.
+   */
+  cancellationHandle(): CancellationHandle
   /**
    * Registers a JavaScript callback that the child can invoke before
    * completing a request. The callback will receive a string name and a string
    * payload as its arguments and should return a string as its result.
    *
-   * There is currently no `Uint8Array`-only equivalent to this functionality.
+   * See `registerBinaryCallback` for a `Uint8Array`-only equivalent to this
+   * functionality. Registering a callback replaces any callback of either
+   * kind previously registered under the same name.
    *
    * If the callback throws, an it will be handled appropriately by
-   * `requestSync` and the child will be notified.
+   * `requestSync` and the child will be notified. The same goes for a
+   * callback that returns anything but a string, which fails with an error
+   * naming the type of what it returned.
+   */
+  registerCallback(name: string, callback: (name: string, payload: string) => string): void
+  /**
+   * Registers a JavaScript callback that the child can invoke before
+   * completing a request. Unlike `registerCallback`, the payload is passed
+   * to the callback as a `Uint8Array` without any decoding, so it need not be
+   * valid UTF-8, and the callback must return a `Uint8Array`.
+   *
+   * Registering a callback replaces any callback of either kind previously
+   * registered under the same name.
+   */
+  registerBinaryCallback(name: string, callback: (name: string, payload: Uint8Array) => Uint8Array): void
+  /**
+   * Registers a JavaScript callback that the child can invoke before
+   * completing a request, with a payload made of two fields: a header (such
+   * as a request ID) and a body. The child separates them with the first tab
+   * character in the payload, so the header cannot contain tabs but the body
+   * can. The callback receives the name, header and body as strings, and
+   * should return a string.
+   *
+   * If the payload has no tab, the child is sent a `MessageType.CallError`
+   * without invoking the callback.
+   *
+   * Registering a callback replaces any callback of either kind previously
+   * registered under the same name.
+   */
+  registerHeaderCallback(name: string, callback: (name: string, header: string, body: string) => string): void
+  /**
+   * Registers a JavaScript callback that the child can invoke during a
+   * request made with `requestAsync`. Like `registerCallback`, the callback
+   * receives a string name and a string payload, but it may return either a
+   * string or a promise for one, which is awaited before the child is sent
+   * the result.
+   *
+   * #### Threading model
+   *
+   * The callback always runs on the JavaScript thread. While the child
+   * works on an asynchronous request, the worker thread making it sends each
+   * call over to the JavaScript thread and blocks until the callback's
+   * promise settles. This is why the callback can only be invoked during
+   * `requestAsync`: a synchronous request blocks the JavaScript thread
+   * itself, so the callback could never run. The child invoking it during a
+   * synchronous request is sent a `MessageType.CallError`.
+   *
+   * The request cannot complete until the callback's promise settles, so
+   * the promise must not wait on the request (directly or otherwise), or on
+   * anything else the channel is busy with: that deadlocks the request. It
+   * also must not block the JavaScript thread waiting for the worker, which
+   * is why the channel's methods throw, rather than wait, while it is busy.
+   *
+   * Registering a callback replaces any callback of either kind previously
+   * registered under the same name.
+   */
+  registerAsyncCallback(name: string, callback: (name: string, payload: string) => string | Promise<string>): void
+  /**
+   * Registers a JavaScript callback that is invoked in place of any callback
+   * the child invokes during a synchronous request that isn't registered
+   * under its name, such as to proxy calls elsewhere or to handle callbacks
+   * whose names are only known at runtime. Like `registerCallback`, it
+   * receives the name and payload as strings.
+   *
+   * The callback can decline the call by returning `null` or `undefined`,
+   * in which case the child is sent the same `MessageType.CallError` as for
+   * an unknown callback without a default. Throwing sends the child a
+   * `MessageType.CallError` with the error message, as with any callback.
+   *
+   * Callbacks registered under a name always take precedence, even
+   * asynchronous ones. Registering a default callback replaces the previous
+   * one, if any.
+   */
+  registerDefaultCallback(callback: (name: string, payload: string) => string | null | undefined): void
+  /**
+   * Removes the callback registered with `registerDefaultCallback`, if any.
+   * Returns whether a callback was removed.
+   */
+  unregisterDefaultCallback(): boolean
+  /**
+   * Removes the callback registered under `name`, if any, so it can be
+   * garbage collected. Returns whether a callback was removed.
+   *
+   * The child invoking a removed callback is handled like any other unknown
+   * callback: the child is sent a `MessageType.CallError`.
+   */
+  unregisterCallback(name: string): boolean
+  /**
+   * Removes all registered callbacks, as with `unregisterCallback`,
+   * including the default callback.
+   */
+  clearCallbacks(): void
+  /**
+   * Returns the names of the callbacks currently registered, of any kind,
+   * in sorted order, for checking which callbacks the child can invoke.
+   * The default callback (see `registerDefaultCallback`) has no name, and
+   * isn't included.
+   */
+  registeredCallbacks(): Array<string>
+  /**
+   * Sets a function to be called for every synchronous request the channel
+   * makes and every callback the child invokes during one, for auditing or
+   * logging individual calls rather than the aggregates of `stats`, or
+   * removes it if `null`.
+   *
+   * The observer is called with an `event`, the name of the method or
+   * callback involved, and the size in bytes of the payload:
+   * - `"request"` before a request is sent, with the request's payload.
+   * - `"response"` once the child has responded, with the response's payload.
+   * - `"error"` instead of `"response"` if the request failed, with the
+   *   size of the error reported by the child, or 0 for failures of the
+   *   channel itself.
+   * - `"callback"` before a callback is invoked, with the call's payload.
+   *
+   * Since synchronous requests block the JavaScript thread, the observer is
+   * called inline, on that same thread, and holds up the request while it
+   * runs. An observer that throws has its error discarded: it can't fail
+   * the request or affect the child. Requests made with `requestAsync` are
+   * not observed.
+   */
+  setObserver(observer: ((event: 'request' | 'response' | 'error' | 'callback', name: string, bytes: number) => void) | null | undefined): void
+  /**
+   * Returns a snapshot of the channel's counters: the number of messages of
+   * each `MessageType` that have crossed the wire in either direction, and
+   * totals of requests, response bytes, callback invocations and errors,
+   * since the channel was created or `resetStats` was last called.
+   */
+  stats(): ChannelStats
+  /**
+   * Sets all of the counters returned by `stats` back to zero, e.g. to
+   * sample them over fixed intervals.
+   */
+  resetStats(): void
+  /**
+   * Blocks until the child creates a file at `path`, for children that
+   * signal their readiness that way rather than by being able to respond to
+   * requests straight away. Throws if the file does not appear within
+   * `timeoutMs` milliseconds, or if the child exits first.
+   */
+  waitReadyFile(path: string, timeoutMs: number): void
+  /**
+   * Returns whether the child process is still running. This is cheap, but
+   * does not tell whether the child is responsive; see `ping` for that.
+   *
+   * Returns `false` after the child has been reaped by the idle timeout (see
+   * `setIdleTimeout`), even though the next request will respawn it.
+   */
+  isAlive(): boolean
+  /**
+   * Checks that the child is responsive by sending it a no-op
+   * `MessageType.Request` with the reserved `$/ping` method name and an empty
+   * payload, to which it must respond with an empty `MessageType.Response`.
+   * Returns whether the response arrived within `timeoutMs` milliseconds.
+   *
+   * Unlike a timed out request, a timed out ping does not poison the
+   * channel: its response is skipped if it arrives later.
+   */
+  ping(timeoutMs: number): boolean
+  /**
+   * Sets the function that notifications from the child (see
+   * `MessageType.Notify`) are passed to, with their name and payload, or
+   * removes it if `null`. Without one, notifications are discarded.
+   *
+   * Like `ChannelOptions.log`, the handler is called asynchronously through
+   * the event loop, after the request (or `pumpNotifications` call) that
+   * received the notification returns. Since the channel only reads from
+   * the child while it is busy, notifications the child sends between
+   * requests wait until the next request, unless `pumpNotifications` is
+   * called to pick them up.
+   */
+  onNotify(handler: ((name: string, payload: string) => void) | null | undefined): void
+  /**
+   * Waits up to `timeoutMs` milliseconds for notifications from the child
+   * while no request is in progress, passing each to the handler set with
+   * `onNotify`. Returns the number of notifications received.
+   *
+   * A `timeoutMs` of 0 doesn't even pick up notifications that have
+   * already arrived, so it should leave the child some time to send them.
+   * Throws if the child sends anything other than notifications and
+   * `MessageType.Log` messages, or if the channel is poisoned.
+   */
+  pumpNotifications(timeoutMs: number): number
+  /**
+   * Tries to make a channel poisoned by a timeout (see
+   * `requestSyncTimeout`) usable again, by waiting up to `timeoutMs`
+   * milliseconds for the child to finish the request that timed out. Its
+   * late response, along with anything else the child sends for that
+   * request, is read and discarded, and any callback it invokes is answered
+   * with a `MessageType.CallError`. Returns `true` once the channel is usable
+   * again, including when it wasn't poisoned, or `false` if the child is
+   * still working on the request, in which case `resync` can be called
+   * again later.
+   *
+   * This is best-effort: messages are length-prefixed rather than
+   * delimited, so there is no way to find the start of the next message in
+   * a stream whose position was lost. A channel poisoned by anything else,
+   * such as a framing error, a checksum mismatch, a timeout in the middle of
+   * a message or a timeout while sending, can't be resynchronized, and this
+   * throws: close it, or `respawn` the child, instead. The same goes for a
+   * child that never finishes the request, or that keeps state that the
+   * abandoned request may have left inconsistent.
+   */
+  resync(timeoutMs: number): boolean
+  /**
+   * Returns the child's exit code once it has exited, or `null` if it is
+   * still running or was terminated by a signal (see `signal`).
+   */
+  exitStatus(): number | null
+  /**
+   * Returns the number of the signal that terminated the child, or `null` if
+   * it is still running or exited on its own. A child terminated by `close()`
+   * reports `SIGKILL`. Always returns `null` on Windows.
+   */
+  signal(): number | null
+  /**
+   * Returns the protocol version negotiated with the child during the
+   * handshake (see `ChannelOptions.handshake`): the lower of the versions
+   * spoken by the channel and the child. Returns `null` if no handshake was
+   * performed, or if the child is running in legacy mode.
+   */
+  protocolVersion(): number | null
+  /**
+   * Returns the payload compression codec negotiated with the child (see
+   * `ChannelOptions.compression`), or `null` if payloads are not compressed.
+   */
+  compression(): string | null
+  /**
+   * Returns the checksum algorithm negotiated with the child (see
+   * `ChannelOptions.checksum`), or `null` if messages have no checksum.
+   */
+  checksum(): string | null
+  /**
+   * Returns the number of times the child was restarted after exiting
+   * unexpectedly, see `ChannelOptions.restartPolicy`. Respawns after the
+   * idle timeout reaped the child (see `setIdleTimeout`) don't count.
+   */
+  restartCount(): number
+  /**
+   * Returns the OS process ID of the child. This keeps returning the same
+   * value after `close()`, even though the process no longer exists. Throws
+   * for a channel created with `fromRaw`.
+   */
+  pid(): number
+  /**
+   * Automatically kills the child once no request has been issued for
+   * `timeoutMs` milliseconds, to reclaim the resources of idle children. The
+   * next request transparently respawns it with the original `exe` and
+   * `args`, keeping any registered callbacks. Any state the child held in
+   * memory is lost when it is reaped.
+   *
+   * Passing `null` or `undefined` disables the idle timeout.
+   */
+  setIdleTimeout(timeoutMs?: number | undefined | null): void
+  /**
+   * Applies a timeout of `timeoutMs` milliseconds to every subsequent
+   * synchronous request that doesn't set its own, such as with
+   * `requestSyncTimeout`, so it doesn't need repeating at each call site.
+   * Timing out has the same consequences as with `requestSyncTimeout`,
+   * including poisoning the channel unless `ChannelOptions.requestIds` is
+   * set. Batches, pools and `requestAsync` aren't affected.
+   *
+   * Passing `null` or `undefined` restores the default of waiting for
+   * responses indefinitely.
+   */
+  setDefaultTimeout(timeoutMs?: number | undefined | null): void
+  /**
+   * Starts appending a trace of every message to `path`, as with
+   * `ChannelOptions.traceFile`, replacing any trace in progress, or stops
+   * tracing if `path` is `null` or `undefined`. This allows tracing a
+   * channel only while reproducing a problem.
+   */
+  setTraceFile(path?: string | undefined | null): void
+  /**
+   * Limits the payload of each `MessageType.Call` the child makes to
+   * `maxBytes` bytes, checked before it is decoded and passed to the
+   * callback, so a misbehaving child can't make the channel decode huge
+   * strings. A call over the limit is answered with a `MessageType.CallError`
+   * without invoking the callback, and the request it was made during
+   * throws once the child finishes it.
+   *
+   * Unlike `ChannelOptions.maxPayloadLength`, which poisons the channel on any
+   * oversized message, this leaves the channel usable. Passing `null` or
+   * `undefined` removes the limit.
+   */
+  setCallbackPayloadLimit(maxBytes?: number | undefined | null): void
+  /**
+   * Replaces the child with a freshly spawned one, such as after rebuilding
+   * its executable, while keeping the channel itself along with its
+   * registered callbacks, options and stats. The current child is killed
+   * first, and the new one goes through the same handshake and negotiation
+   * as the first one did.
+   *
+   * `exe` and `args` default to those of the current child. When given,
+   * they are also used for any later restarts (see
+   * `ChannelOptions.restartPolicy`) and respawns after the idle timeout.
+   *
+   * This throws while a `requestAsync` is in progress, as do the channel's
+   * other methods, so a request is never cut short. A channel that was
+   * closed is reopened. If the new child fails to start, the channel can't
+   * make requests until `respawn` succeeds.
+   */
+  respawn(exe?: string | undefined | null, args?: Array<string> | undefined | null): void
+  /**
+   * Closes the channel by asking the child to exit on its own (see
+   * `MessageType.Shutdown`), giving it up to `timeoutMs` milliseconds to do
+   * so before terminating it as `close()` does.
+   *
+   * Returns `true` if the child exited on its own, or `false` if it had to be
+   * terminated. For a channel created with `fromRaw`, the connection is
+   * closed right after asking, and this returns whether the request to exit
+   * was sent.
+   */
+  closeGraceful(timeoutMs: number): boolean
+  /**
+   * Closes the channel by asking the OS to terminate the child (`SIGTERM`
+   * on Unix), giving it up to `graceMs` milliseconds to exit before killing
+   * it as `close()` does. Unlike `closeGraceful`, this doesn't rely on the
+   * child reading the protocol, so it also works for a child that is stuck.
+   *
+   * Returns `true` if the child exited within the grace period, or `false`
+   * if it had to be killed. Windows has no equivalent of `SIGTERM` for
+   * console processes, so there the child is killed straight away, and this
+   * only returns `true` if it had already exited.
+   */
+  terminate(graceMs: number): boolean
+  close(): void
+}
+
+/**
+ * A fixed set of `SyncRpcChannel`s to identical children, for spreading
+ * requests across several processes or broadcasting them to all of them.
+ *
+ * Callbacks are registered on the pool as a whole, and each child can
+ * invoke them as it would on its own channel.
+ */
+export declare class SyncRpcPool {
+  /**
+   * Constructs a new `SyncRpcPool` by spawning `size` child processes with
+   * the given `exe` executable and `args`, each set up as a `SyncRpcChannel`
+   * with the given `options`. Throws if any of them fails to start, after
+   * terminating the others.
+   */
+  constructor(exe: string, args: Array<string>, size: number, options?: ChannelOptions | undefined | null)
+  /**
+   * Sends a request to one of the children, as with
+   * `SyncRpcChannel#requestSync`. Children take turns handling requests, in
+   * round-robin order.
+   */
+  requestSyncAny(method: string, payload: string): string
+  /**
+   * Sends the same request to every child, then waits for all of them to
+   * respond. The request is sent to all children before waiting for any of
+   * them, so they handle it in parallel, while their callbacks are invoked
+   * one child at a time.
+   *
+   * Returns one result per child, in order, as with
+   * `SyncRpcChannel#tryRequestSync`. Unlike there, failures of a channel
+   * itself, such as its child exiting mid-request, are also returned as
+   * `{ ok: false, error }`, so that the other children's responses are not
+   * lost.
+   */
+  requestSyncAll(method: string, payload: string): Array<TryRequestResult>
+  /**
+   * Registers a JavaScript callback that any of the children can invoke
+   * before completing a request, see `SyncRpcChannel#registerCallback`.
    */
   registerCallback(name: string, callback: (name: string, payload: string) => string): void
+  /**
+   * Removes the callback registered under `name` from every child, as with
+   * `SyncRpcChannel#unregisterCallback`. Returns whether there was one.
+   */
+  unregisterCallback(name: string): boolean
+  /** The number of children in the pool. */
+  size(): number
+  /** The process IDs of the children, in order. */
+  pids(): Array<number>
+  /** Closes the pool, terminating all of its children. */
   close(): void
 }
 
+/**
+ * The response returned by `SyncRpcChannel#requestAutoSync`, tagged with
+ * how it was decoded.
+ */
+export interface AutoResponse {
+  /** `"text"` if the response is valid UTF-8, `"binary"` otherwise. */
+  type: 'text' | 'binary'
+  /**
+   * The response, as a string if `type` is `"text"`, or as is if it is
+   * `"binary"`.
+   */
+  value: string | Uint8Array
+}
+
+/** A request in a batch made with `SyncRpcChannel#requestBatchSync`. */
+export interface BatchRequest {
+  /** The method name of the request. */
+  method: string
+  /** The raw payload of the request. */
+  payload: Uint8Array
+}
+
+/**
+ * The outcome of a request in a batch made with
+ * `SyncRpcChannel#requestBatchSync`.
+ */
+export interface BatchResult {
+  /** Whether the child responded successfully. */
+  ok: boolean
+  /** The child's raw response, if `ok` is `true`. */
+  value?: Uint8Array
+  /** The error reported by the child, if `ok` is `false`. */
+  error?: string
+}
+
+/** Optional settings for constructing a `SyncRpcChannel`. */
+export interface ChannelOptions {
+  /**
+   * The working directory to spawn the child in. Defaults to the current
+   * working directory.
+   */
+  cwd?: string
+  /**
+   * Environment variables to set for the child, in addition to the
+   * inherited environment (unless `clearEnv` is set).
+   */
+  env?: Record<string, string>
+  /**
+   * Open file descriptors of this process for the child to inherit, such as
+   * a pre-bound socket, in addition to its stdio. The child receives them as
+   * descriptors 3, 4, and so on, in the order given, and the comma-separated
+   * list of their numbers in the child is set in its
+   * `LIBSYNCRPC_INHERITED_FDS` environment variable. Only supported on Unix.
+   */
+  inheritFds?: Array<number>
+  /**
+   * Whether to start the child with an empty environment, rather than
+   * inheriting this process's environment. Only variables in `env` will be
+   * set.
+   */
+  clearEnv?: boolean
+  /**
+   * Whether to hide the child's console window on Windows, by spawning it
+   * with the `CREATE_NO_WINDOW` flag. Defaults to `true`, so that apps without
+   * a console, such as Electron apps, don't flash a window for each child.
+   * Set it to `false` for a child that needs a console of its own. The child's
+   * stdio is piped either way. Ignored elsewhere.
+   */
+  windowsHide?: boolean
+  /**
+   * Where the child's stderr goes: `"inherit"` (the default) shares this
+   * process's stderr, `"ignore"` discards it, and a function is called with
+   * each line the child writes, without its line terminator.
+   *
+   * Lines are delivered asynchronously through the event loop, so they will
+   * not be seen while a synchronous request is still in progress.
+   */
+  stderr?: 'inherit' | 'ignore' | ((line: string) => void)
+  /**
+   * Whether any output from the child on its stderr fails requests, for
+   * children that should never write to it. Lines are still passed on as
+   * configured by `stderr`, which can't be `"ignore"`. Defaults to `false`.
+   *
+   * Before a request is sent, and again once the child has responded to it,
+   * the request throws with the lines written since the last request that
+   * threw for them, if any. The channel itself stays usable. Since stderr
+   * and stdout are separate pipes, a line written just before a response
+   * can arrive after it, in which case the request succeeds, and the next
+   * request throws before it is sent.
+   */
+  stderrIsFatal?: boolean
+  /**
+   * A file to append a machine-readable trace of every message sent to or
+   * received from the child to, one JSON object per line. Each object has
+   * `ts` (microseconds since the Unix epoch), `dir` (`"send"` or `"recv"`),
+   * `type` and `typeName` (the `MessageType`), `name`, and `len` (the
+   * payload's length in bytes) properties.
+   *
+   * Defaults to the `LIBSYNCRPC_TRACE_FILE` environment variable, if set, so
+   * that the protocol can be traced without changing any code. See also
+   * `SyncRpcChannel#setTraceFile`.
+   */
+  traceFile?: string
+  /**
+   * Whether to add a `hash` property to each `traceFile` record: a hex
+   * string of the 64-bit FNV-1a hash of the payload, to tell payloads apart
+   * without logging their contents. Defaults to `false`.
+   */
+  tracePayloadHashes?: boolean
+  /**
+   * How many bytes at the start of each payload to add to each `traceFile`
+   * record as a `preview` property, holding them as text if they are valid
+   * UTF-8, or as a `previewHex` property, holding them as a hex string,
+   * otherwise. Nothing is redacted from previews, so they may expose
+   * whatever the payloads contain. Defaults to 0, for no previews.
+   */
+  tracePreviewBytes?: number
+  /**
+   * Whether to negotiate a protocol version with the child before the
+   * channel is used (see `MessageType.Request` for how). With `"required"`,
+   * the constructor throws if the child does not complete the handshake or
+   * speaks an incompatible version. With `"optional"`, a child that responds
+   * to the handshake with a `MessageType.Error` is used in legacy mode, as if
+   * no handshake had taken place. By default, no handshake is performed.
+   *
+   * See `SyncRpcChannel#protocolVersion` for the negotiated version.
+   */
+  handshake?: 'required' | 'optional'
+  /**
+   * A codec to compress payloads with, if the child supports it (see
+   * `MessageType.Request` for how this is negotiated). Only `"zstd"` is
+   * supported, and only if libsyncrpc was built with its `zstd` feature (on
+   * by default). By default, payloads are not compressed.
+   *
+   * See `SyncRpcChannel#compression` for whether the child agreed.
+   */
+  compression?: 'zstd'
+  /**
+   * The minimum length, in bytes, of payloads this channel compresses, once
+   * compression has been negotiated. Defaults to 1024.
+   */
+  compressionThreshold?: number
+  /**
+   * A checksum to add to every message, if the child supports it (see
+   * `MessageType.Request` for how this is negotiated), so that a message
+   * corrupted on the way is rejected rather than misinterpreted. Only
+   * `"crc32"` is supported. By default, messages have no checksum.
+   *
+   * A message whose checksum doesn't match poisons the channel, as with a
+   * timeout. See `SyncRpcChannel#checksum` for whether the child agreed.
+   */
+  checksum?: 'crc32'
+  /**
+   * A function called with the `<payload>` of each `MessageType.Log` message
+   * the child sends, as a string. Like `stderr` lines, these are delivered
+   * asynchronously through the event loop. By default, they are discarded.
+   */
+  log?: (message: string) => void
+  /**
+   * The maximum length, in bytes, of the name or payload of a message from
+   * the child. Longer messages are rejected before room is made for them,
+   * which poisons the channel as with a timeout. Defaults to 256 MiB.
+   */
+  maxPayloadLength?: number
+  /**
+   * The size, in bytes, of the chunks read from the child's stdout at a
+   * time. Must be greater than 0. Defaults to 64 KiB.
+   */
+  readBufferSize?: number
+  /**
+   * The capacity, in bytes, of the buffer messages are written to the
+   * child's stdin through. Defaults to 8 KiB.
+   */
+  writeBufferSize?: number
+  /**
+   * Whether, and how often, to restart the child if it exits unexpectedly.
+   * By default, a child that exits is not restarted, and every subsequent
+   * request throws.
+   */
+  restartPolicy?: RestartPolicy
+  /**
+   * Whether to send every request with an `<id>` that the child must echo
+   * in its response (see `MessageType.Request`). Defaults to `false`.
+   *
+   * A response whose `<id>` is not that of the request in progress is
+   * discarded as a late response to an earlier request, and a
+   * `MessageType.Call` made on behalf of an earlier request is answered
+   * with a `MessageType.CallError`. A request that times out while waiting
+   * for the child then no longer poisons the channel (see
+   * `requestSyncTimeout`): the next request can be made, such as to retry,
+   * without its late response being mistaken for the new one's.
+   */
+  requestIds?: boolean
+  /**
+   * Whether the `<name>` of each `MessageType.Response` and
+   * `MessageType.Error` (and `MessageType.ResponseChunk`) must match that
+   * of the request it is for. Defaults to `true`, with a mismatch failing
+   * the request as a protocol violation.
+   *
+   * With `false`, the first response to arrive is taken as the response to
+   * the request in progress, whatever its name, for children that respond
+   * under names of their own. Batched requests (see `requestBatchSync`)
+   * are then matched by `<id>` alone.
+   */
+  strictResponseNames?: boolean
+}
+
+/**
+ * A snapshot of a channel's counters, as returned by
+ * `SyncRpcChannel#stats`.
+ */
+export interface ChannelStats {
+  /**
+   * Number of messages sent to the child, indexed by `MessageType` value.
+   * Index `0` is unused.
+   */
+  messagesSent: Array<number>
+  /**
+   * Number of messages received from the child, indexed by `MessageType`
+   * value. Index `0` is unused.
+   */
+  messagesReceived: Array<number>
+  /**
+   * Number of requests made, whether they succeeded or not. Each request in
+   * a batch counts as one. Reserved requests, such as pings, don't count.
+   */
+  requests: number
+  /** Total length, in bytes, of the payloads of successful responses. */
+  responseBytes: number
+  /** Number of times the child invoked a callback, including unknown ones. */
+  callbacks: number
+  /**
+   * Number of requests that failed, whether the child reported an error or
+   * the channel itself failed.
+   */
+  errors: number
+}
+
 /**
  * Messages types exchanged between the channel and its child. All messages
  * have an associated `<name>` and `<payload>`, which will both be arrays of
@@ -65,6 +886,50 @@ export declare enum MessageType {
    * `<name>` as the method name. The child may send back any number of
    * `MessageType.Call` messages and must then close the request with either a
    * `MessageType.Response`, or a `MessageType.Error`.  message.
+   *
+   * Requests sent by `SyncRpcChannel#requestSyncWithDeadline` have a 4th
+   * `<deadline>` item: an unsigned integer number of milliseconds, from when
+   * the request was sent, that the child has to respond.
+   *
+   * Requests sent by `SyncRpcChannel#requestBatchSync`, as well as all
+   * requests when `ChannelOptions.requestIds` is set, have a 5th `<id>`
+   * item: an unsigned integer identifying the request, with a `nil`
+   * `<deadline>` if there is none. The child must echo the `<id>` as the 5th
+   * item of its `MessageType.Response` or `MessageType.Error`, and should
+   * also send it with any `MessageType.Call` made while working on the
+   * request, in which case the channel echoes it in its
+   * `MessageType.CallResponse` or `MessageType.CallError`. Such responses
+   * may arrive in any order.
+   *
+   * The `$/ping` method name is reserved for `SyncRpcChannel#ping`: the child
+   * should respond to it straight away with an empty `MessageType.Response`.
+   *
+   * The `$/handshake` method name is reserved for the protocol handshake (see
+   * `ChannelOptions.handshake`). Its `<payload>` is the channel's protocol
+   * version, as a decimal UTF-8 string. The child should respond with a
+   * `MessageType.Response` whose `<payload>` is the version it speaks, in the
+   * same format, or with a `MessageType.Error` if it does not support the
+   * handshake.
+   *
+   * The `$/compression` method name is reserved for negotiating payload
+   * compression (see `ChannelOptions.compression`), right after any
+   * handshake. Its `<payload>` is the name of the codec, `zstd`. The child
+   * should respond with an empty `MessageType.Response` to accept, or with a
+   * `MessageType.Error` to decline. Once accepted, every `<payload>` in
+   * either direction, starting with the next message, begins with a flag
+   * byte: `0` if the rest of it is uncompressed, or `1` if it is compressed
+   * with zstd. Each end decides for itself which payloads to compress.
+   *
+   * The `$/checksum` method name is reserved for negotiating message
+   * checksums (see `ChannelOptions.checksum`), right after any compression
+   * negotiation. Its `<payload>` is the name of the algorithm, `crc32`. The
+   * child should respond with an empty `MessageType.Response` to accept, or
+   * with a `MessageType.Error` to decline. Once accepted, every `<payload>`
+   * in either direction, starting with the next message, ends with 4 more
+   * bytes: the big-endian CRC-32 (as computed by zlib) of the message's
+   * `<type>` byte, `<name>`, and the rest of the `<payload>`, in that order.
+   * With compression on, the checksum covers the compression flag and the
+   * possibly compressed payload, as sent.
    */
   Request = 1,
   /**
@@ -75,11 +940,12 @@ export declare enum MessageType {
    */
   CallResponse = 2,
   /**
-   * Informs the child that an error occurred. The `<payload>` will be the
-   * binary representation of the stringified error, as UTF-8 bytes, not
-   * necessarily in JSON format. The method linked to this message will also
-   * throw an error after sending this message to its child and terminate the
-   * request call.
+   * Informs the child that invoking a callback failed, either because the
+   * callback threw or because there is no such callback. The `<payload>` will
+   * be the binary representation of the stringified error, as UTF-8 bytes, not
+   * necessarily in JSON format. The request stays open: the child may recover
+   * and carry on towards a `MessageType.Response`, or give up on the request
+   * with a `MessageType.Error`, which the request then throws.
    */
   CallError = 3,
   /**
@@ -93,6 +959,17 @@ export declare enum MessageType {
    * representation of the stringified error, as UTF-8 bytes, not necessarily
    * in JSON format. The method associated with this call will also throw an
    * error after receiving this message from the child.
+   *
+   * If the `<payload>` is a JSON object with string `code` and `message`
+   * properties, the thrown error has that `message`, and `code` and `data`
+   * properties taken from the object's `code` and (optional) `data`, so
+   * callers can tell kinds of errors apart. Otherwise, the whole payload is
+   * the message, after how long the request took to fail, as in
+   * "request `compile` failed after 4213ms: <payload>".
+   *
+   * The thrown error also has an `elapsedMs` property: the number of
+   * milliseconds the request took to fail, to tell a child that failed
+   * straight away apart from one that was slow to.
    */
   Error = 5,
   /**
@@ -101,7 +978,116 @@ export declare enum MessageType {
    * and `<payload>` is an encoded UTF-8 string that the callback will be
    * called with. The child should then listen for `MessageType.CallResponse`
    * and `MessageType.CallError` messages.
+   *
+   * The child may send several `MessageType.Call` messages before reading
+   * any of their responses. Calls are handled, and responded to, in the order
+   * they were received.
    */
   Call = 6,
-  _UnusedPlaceholderVariant = 7
+  /**
+   * Asks the child to exit gracefully (see `SyncRpcChannel#closeGraceful`).
+   * Both `<name>` and `<payload>` are empty. The child should release any
+   * resources it holds and exit without sending a response.
+   */
+  Shutdown = 7,
+  /**
+   * A piece of a streamed response to a request made with
+   * `SyncRpcChannel#requestStreamSync`. `<name>` MUST match the
+   * `MessageType.Request` message's `<name>`, and `<payload>` is the piece
+   * of the response. The child may send any number of these, and must then
+   * close the request with a `MessageType.Response` or `MessageType.Error`
+   * as usual. Sending one in response to any other kind of request is an
+   * error.
+   */
+  ResponseChunk = 8,
+  /**
+   * Asks the child to abandon a request in progress (see
+   * `CancellationHandle#cancel`). `<name>` is the request's `<name>`, and
+   * `<payload>` is empty. For requests sent with an `<id>`, the message
+   * carries the same `<id>` as its 5th item. The child should stop working
+   * on the request and close it with a `MessageType.Error` whose
+   * `<payload>` is a structured error with code `"ECANCELED"`, such as
+   * `{"code":"ECANCELED","message":"request was cancelled"}`. A child that
+   * has already responded, or that doesn't support cancellation, can
+   * ignore it.
+   */
+  Cancel = 9,
+  /**
+   * A diagnostic message, which the child may send at any time, including
+   * in the middle of a request. `<name>` is empty, and `<payload>` is the
+   * message as UTF-8 text. The channel never responds to it: it is passed
+   * to `ChannelOptions.log`, if set, and otherwise discarded. This lets the
+   * child log human-readable output without corrupting the protocol on its
+   * stdout.
+   */
+  Log = 10,
+  /**
+   * An unsolicited notification, such as an event the child is watching
+   * for, which the child may send at any time, including when no request is
+   * in progress. `<name>` names the notification, and `<payload>` is its
+   * UTF-8 encoded content. The channel never responds to it: it is passed
+   * to the handler set with `SyncRpcChannel#onNotify`, if any, and
+   * otherwise discarded.
+   *
+   * The channel only reads from the child while it is busy with something,
+   * so notifications sent between requests are only picked up by the next
+   * request, or by `SyncRpcChannel#pumpNotifications`.
+   */
+  Notify = 11,
+  _UnusedPlaceholderVariant = 12
+}
+
+/**
+ * Parses a `MessageType` from the `<type>` byte of a message, throwing if it
+ * isn't the value of a known message type. Child implementations written in
+ * JavaScript can use this instead of hardcoding the values.
+ */
+export declare function messageTypeFromU8(n: number): MessageType
+
+/**
+ * Spawns `exe` with `args` for a single request, for children that are
+ * one-shot tools rather than long-lived servers. The request is written to
+ * the child's stdin, which is then closed so that the child sees EOF once it
+ * has read it. Returns the payload of the child's `MessageType.Response`,
+ * after reaping the child: a child still running a second after responding
+ * is killed.
+ *
+ * There is no handshake, and since the child's stdin is closed, it can't
+ * invoke callbacks: a `MessageType.Call` fails the request.
+ * `MessageType.Log`, `MessageType.Notify` and `MessageType.ResponseChunk`
+ * messages are discarded. The child's stderr is collected rather than
+ * inherited, and any error thrown, including a `MessageType.Error` from the
+ * child, includes it along with the child's exit status.
+ */
+export declare function requestOneshot(exe: string, args: Array<string>, method: string, payload: Uint8Array): Uint8Array
+
+/**
+ * How a channel restarts a child that exits unexpectedly, see
+ * `ChannelOptions.restartPolicy`.
+ *
+ * When a synchronous request fails because the child exited (whether
+ * before or during the request), the child is respawned with the original
+ * `exe` and `args`, keeping any registered callbacks, and the request is
+ * retried once. Any state the child held in memory is lost, and callbacks
+ * it invoked before exiting may be invoked again by the retry. A child that
+ * exits again during the retry is only restarted by the next request.
+ */
+export interface RestartPolicy {
+  /**
+   * The maximum number of restarts over the channel's lifetime. Once they
+   * are used up, a request that finds the child gone throws instead.
+   */
+  maxRestarts: number
+  /** How long to wait before each restart, in milliseconds. Defaults to 0. */
+  backoffMs?: number
+}
+
+/** The outcome of `SyncRpcChannel#tryRequestSync`. */
+export interface TryRequestResult {
+  /** Whether the child responded successfully. */
+  ok: boolean
+  /** The child's response, if `ok` is `true`. */
+  value?: string
+  /** The error reported by the child, if `ok` is `false`. */
+  error?: string
 }
@@ -508,5 +508,9 @@ if (!nativeBinding) {
 }
 
 module.exports = nativeBinding
+module.exports.CancellationHandle = nativeBinding.CancellationHandle
 module.exports.SyncRpcChannel = nativeBinding.SyncRpcChannel
+module.exports.SyncRpcPool = nativeBinding.SyncRpcPool
 module.exports.MessageType = nativeBinding.MessageType
+module.exports.messageTypeFromU8 = nativeBinding.messageTypeFromU8
+module.exports.requestOneshot = nativeBinding.requestOneshot
@@ -0,0 +1,25 @@
+import { buffer } from "node:stream/consumers";
+import { pack, unpack } from "msgpackr";
+import { MessageType } from './index.js';
+
+// A one-shot child for `requestOneshot`: reads a single request, waits for
+// EOF on stdin, responds and exits.
+const [ty, binName, payload] = unpack(await buffer(process.stdin));
+const name = new TextDecoder().decode(binName);
+if (ty != MessageType.Request) {
+    throw new Error(`Unexpected message: (${ty}) ${name}`);
+}
+switch (name) {
+    case "echo":
+        process.stdout.write(pack([MessageType.Log, Buffer.from(""), Buffer.from("echoing")]));
+        process.stdout.write(pack([MessageType.Response, binName, payload]));
+        break;
+    case "fail":
+        process.stderr.write("something went wrong\n");
+        process.stdout.write(pack([MessageType.Error, binName, Buffer.from("failed")]));
+        process.exitCode = 2;
+        break;
+    case "silent":
+        process.exitCode = 3;
+        break;
+}
@@ -0,0 +1,138 @@
+use std::{
+  collections::HashMap,
+  future::Future,
+  pin::pin,
+  sync::{Arc, Mutex, PoisonError},
+  task::{Context, Poll, Wake, Waker},
+  thread::Thread,
+  time::{Duration, Instant},
+};
+
+use napi::{
+  bindgen_prelude::{Either, FnArgs, Promise, Result},
+  threadsafe_function::ThreadsafeFunction,
+  Env, Error, Status, Task,
+};
+
+use crate::{
+  idle::IdleReaper,
+  remote_error, request_error, response_to_string,
+  wire::{RemoteResult, RequestOptions, Wire, WireResult},
+};
+
+/// A JavaScript callback registered with `registerAsyncCallback`, which can be
+/// called from the worker thread running an asynchronous request.
+pub type AsyncCallback = ThreadsafeFunction<
+  FnArgs<(String, String)>,
+  Either<String, Promise<String>>,
+  FnArgs<(String, String)>,
+  Status,
+  false,
+  true,
+>;
+
+/// A request made by `SyncRpcChannel#requestAsync`, run on the libuv thread
+/// pool.
+///
+/// The worker thread owns the channel's wire for the whole request. Callbacks
+/// the child invokes are marshalled to the JavaScript thread through their
+/// `ThreadsafeFunction`, and the worker blocks until the promise they return
+/// settles.
+pub struct AsyncRequest {
+  pub(crate) wire: Arc<Mutex<Wire>>,
+  pub(crate) method: String,
+  pub(crate) payload: Vec<u8>,
+  // A snapshot of the callbacks registered when the request was made, where
+  // `None` marks a synchronous callback, which can't be invoked from here.
+  pub(crate) callbacks: HashMap<String, Option<Arc<AsyncCallback>>>,
+  pub(crate) idle: Option<Arc<IdleReaper>>,
+  // How long the request took, once it is done.
+  pub(crate) elapsed: Duration,
+}
+
+impl Task for AsyncRequest {
+  type Output = WireResult<RemoteResult>;
+  type JsValue = String;
+
+  fn compute(&mut self) -> Result<WireResult<RemoteResult>> {
+    let start = Instant::now();
+    let mut wire = self.wire.lock().unwrap_or_else(PoisonError::into_inner);
+    let callbacks = &self.callbacks;
+    let res = wire.request(
+      &self.method,
+      &self.payload,
+      RequestOptions::default(),
+      &mut |name, payload| {
+        callbacks
+          .get(name)
+          .map(|cb| call_async_callback(name, payload, cb.as_deref()))
+      },
+    );
+    wire.busy = false;
+    self.elapsed = start.elapsed();
+    Ok(res)
+  }
+
+  fn resolve(&mut self, env: Env, output: WireResult<RemoteResult>) -> Result<String> {
+    output
+      .map_err(|e| request_error(&env, &self.method, e, self.elapsed))?
+      .map_err(|message| remote_error(&env, &self.method, message, self.elapsed))
+      .and_then(response_to_string)
+  }
+
+  fn finally(self, _env: Env) -> Result<()> {
+    if let Some(idle) = &self.idle {
+      idle.end();
+    }
+    Ok(())
+  }
+}
+
+// Helper function to invoke an asynchronous callback from the worker thread
+// and wait for the promise it returns, if any, to settle.
+fn call_async_callback(
+  name: &str,
+  payload: Vec<u8>,
+  cb: Option<&AsyncCallback>,
+) -> Result<Vec<u8>> {
+  let Some(cb) = cb else {
+    return Err(Error::from_reason(format!(
+      "callback `{name}` is synchronous and cannot be invoked during `requestAsync`"
+    )));
+  };
+  let payload = String::from_utf8(payload).map_err(|e| {
+    Error::from_reason(format!(
+      "Failed to deserialize callback payload into a string: {e}"
+    ))
+  })?;
+  let res = block_on(async {
+    match cb.call_async_catch((name.into(), payload).into()).await? {
+      Either::A(res) => Ok(res),
+      Either::B(promise) => promise.await,
+    }
+  })?;
+  Ok(res.into_bytes())
+}
+
+// Helper function to run a future to completion on the current thread,
+// parking it while the future is pending. The futures awaited here are woken
+// from the JavaScript thread, so this must never run on it.
+fn block_on<F: Future>(future: F) -> F::Output {
+  struct ThreadWaker(Thread);
+
+  impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+      self.0.unpark();
+    }
+  }
+
+  let waker = Waker::from(Arc::new(ThreadWaker(std::thread::current())));
+  let mut cx = Context::from_waker(&waker);
+  let mut future = pin!(future);
+  loop {
+    if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+      return output;
+    }
+    std::thread::park();
+  }
+}
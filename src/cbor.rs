@@ -0,0 +1,243 @@
+use std::fmt;
+
+use serde_json::{Map, Number, Value};
+
+/// How deeply arrays and maps may nest in a decoded value, so that hostile
+/// input can't overflow the stack.
+const MAX_DEPTH: usize = 512;
+
+/// A CBOR (RFC 8949) decoding failure, with the offset of the byte it was
+/// detected at.
+pub(crate) struct DecodeError {
+  pub offset: usize,
+  pub reason: String,
+}
+
+impl fmt::Display for DecodeError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{} at byte offset {}", self.reason, self.offset)
+  }
+}
+
+/// Encodes a JSON value as CBOR. Integers are encoded as CBOR integers, other
+/// numbers as 64-bit floats, and objects as maps with text keys.
+pub(crate) fn encode(value: &Value) -> Vec<u8> {
+  let mut out = Vec::new();
+  encode_into(&mut out, value);
+  out
+}
+
+fn encode_into(out: &mut Vec<u8>, value: &Value) {
+  match value {
+    Value::Null => out.push(0xf6),
+    Value::Bool(false) => out.push(0xf4),
+    Value::Bool(true) => out.push(0xf5),
+    Value::Number(n) => {
+      if let Some(n) = n.as_u64() {
+        write_head(out, 0, n);
+      } else if let Some(n) = n.as_i64() {
+        // Negative integers are encoded as `-1 - n`.
+        write_head(out, 1, !(n as u64));
+      } else {
+        out.push(0xfb);
+        out.extend_from_slice(&n.as_f64().unwrap_or(f64::NAN).to_be_bytes());
+      }
+    }
+    Value::String(s) => {
+      write_head(out, 3, s.len() as u64);
+      out.extend_from_slice(s.as_bytes());
+    }
+    Value::Array(items) => {
+      write_head(out, 4, items.len() as u64);
+      for item in items {
+        encode_into(out, item);
+      }
+    }
+    Value::Object(fields) => {
+      write_head(out, 5, fields.len() as u64);
+      for (key, value) in fields {
+        write_head(out, 3, key.len() as u64);
+        out.extend_from_slice(key.as_bytes());
+        encode_into(out, value);
+      }
+    }
+  }
+}
+
+// Helper function to write the initial byte of an item, and its argument in
+// as few bytes as possible.
+fn write_head(out: &mut Vec<u8>, major: u8, arg: u64) {
+  let major = major << 5;
+  match arg {
+    0..=23 => out.push(major | arg as u8),
+    24..=0xff => out.extend_from_slice(&[major | 24, arg as u8]),
+    0x100..=0xffff => {
+      out.push(major | 25);
+      out.extend_from_slice(&(arg as u16).to_be_bytes());
+    }
+    0x1_0000..=0xffff_ffff => {
+      out.push(major | 26);
+      out.extend_from_slice(&(arg as u32).to_be_bytes());
+    }
+    _ => {
+      out.push(major | 27);
+      out.extend_from_slice(&arg.to_be_bytes());
+    }
+  }
+}
+
+/// Decodes a single CBOR item, which must span all of `bytes`, into a JSON
+/// value.
+///
+/// Byte strings become arrays of numbers, tags are ignored in favor of the
+/// item they tag, and `undefined` as well as non-finite floats become `null`.
+/// Map keys must be text strings, and indefinite-length items are not
+/// supported.
+pub(crate) fn decode(bytes: &[u8]) -> Result<Value, DecodeError> {
+  let mut decoder = Decoder { bytes, pos: 0 };
+  let value = decoder.item(0)?;
+  if decoder.pos < bytes.len() {
+    return Err(error_at(decoder.pos, "unexpected trailing bytes"));
+  }
+  Ok(value)
+}
+
+struct Decoder<'a> {
+  bytes: &'a [u8],
+  pos: usize,
+}
+
+impl Decoder<'_> {
+  fn item(&mut self, depth: usize) -> Result<Value, DecodeError> {
+    let start = self.pos;
+    let initial = self.take(1)?[0];
+    let (major, info) = (initial >> 5, initial & 0x1f);
+    if major == 7 {
+      return self.simple(start, info);
+    }
+    let arg = self.argument(start, info)?;
+    Ok(match major {
+      0 => Value::from(arg),
+      1 => match i64::try_from(arg) {
+        Ok(n) => Value::from(-1 - n),
+        Err(_) => Value::from(-1.0 - arg as f64),
+      },
+      2 => Value::from(self.take_len(start, arg)?.to_vec()),
+      3 => {
+        let bytes = self.take_len(start, arg)?;
+        let s = std::str::from_utf8(bytes)
+          .map_err(|e| error_at(start, format!("invalid UTF-8 in text string: {e}")))?;
+        Value::from(s)
+      }
+      4 => {
+        Self::check_depth(start, depth)?;
+        let mut items = Vec::new();
+        for _ in 0..arg {
+          items.push(self.item(depth + 1)?);
+        }
+        Value::Array(items)
+      }
+      5 => {
+        Self::check_depth(start, depth)?;
+        let mut fields = Map::new();
+        for _ in 0..arg {
+          let key_start = self.pos;
+          let Value::String(key) = self.item(depth + 1)? else {
+            return Err(error_at(key_start, "map keys must be text strings"));
+          };
+          let value = self.item(depth + 1)?;
+          fields.insert(key, value);
+        }
+        Value::Object(fields)
+      }
+      // Tags only add meaning to the item that follows.
+      6 => {
+        Self::check_depth(start, depth)?;
+        self.item(depth + 1)?
+      }
+      _ => unreachable!("the major type has 3 bits"),
+    })
+  }
+
+  // Helper method to decode the simple values and floats of major type 7.
+  fn simple(&mut self, start: usize, info: u8) -> Result<Value, DecodeError> {
+    let float = match info {
+      20 => return Ok(Value::Bool(false)),
+      21 => return Ok(Value::Bool(true)),
+      22 | 23 => return Ok(Value::Null),
+      25 => f16_to_f64(u16::from_be_bytes(self.take_array()?)),
+      26 => f32::from_be_bytes(self.take_array()?).into(),
+      27 => f64::from_be_bytes(self.take_array()?),
+      31 => return Err(error_at(start, "indefinite-length items are not supported")),
+      _ => return Err(error_at(start, format!("unsupported simple value {info}"))),
+    };
+    Ok(Number::from_f64(float).map_or(Value::Null, Value::Number))
+  }
+
+  // Helper method to read the argument that follows an item's initial byte.
+  fn argument(&mut self, start: usize, info: u8) -> Result<u64, DecodeError> {
+    Ok(match info {
+      0..=23 => info.into(),
+      24 => self.take(1)?[0].into(),
+      25 => u16::from_be_bytes(self.take_array()?).into(),
+      26 => u32::from_be_bytes(self.take_array()?).into(),
+      27 => u64::from_be_bytes(self.take_array()?),
+      31 => return Err(error_at(start, "indefinite-length items are not supported")),
+      _ => {
+        return Err(error_at(
+          start,
+          format!("invalid additional information {info}"),
+        ))
+      }
+    })
+  }
+
+  fn check_depth(start: usize, depth: usize) -> Result<(), DecodeError> {
+    if depth >= MAX_DEPTH {
+      return Err(error_at(
+        start,
+        format!("nesting exceeds {MAX_DEPTH} levels"),
+      ));
+    }
+    Ok(())
+  }
+
+  fn take_len(&mut self, start: usize, len: u64) -> Result<&[u8], DecodeError> {
+    let len = usize::try_from(len).map_err(|_| error_at(start, "length is too large"))?;
+    self.take(len)
+  }
+
+  fn take_array<const N: usize>(&mut self) -> Result<[u8; N], DecodeError> {
+    Ok(self.take(N)?.try_into().expect("took N bytes"))
+  }
+
+  fn take(&mut self, len: usize) -> Result<&[u8], DecodeError> {
+    if self.bytes.len() - self.pos < len {
+      return Err(error_at(self.bytes.len(), "unexpected end of input"));
+    }
+    let taken = &self.bytes[self.pos..self.pos + len];
+    self.pos += len;
+    Ok(taken)
+  }
+}
+
+fn error_at(offset: usize, reason: impl Into<String>) -> DecodeError {
+  DecodeError {
+    offset,
+    reason: reason.into(),
+  }
+}
+
+// Helper function to widen an IEEE 754 half-precision float.
+fn f16_to_f64(half: u16) -> f64 {
+  let sign = if half & 0x8000 != 0 { -1.0 } else { 1.0 };
+  let exponent = i32::from((half >> 10) & 0x1f);
+  let mantissa = f64::from(half & 0x3ff);
+  sign
+    * match exponent {
+      0 => mantissa * 2f64.powi(-24),
+      31 if mantissa == 0.0 => f64::INFINITY,
+      31 => f64::NAN,
+      _ => (1.0 + mantissa / 1024.0) * 2f64.powi(exponent - 15),
+    }
+}
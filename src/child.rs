@@ -0,0 +1,324 @@
+use std::{
+  fs::File,
+  io::{self, BufRead, BufReader, BufWriter, Read},
+  process::{Child, Command, ExitStatus, Stdio},
+  sync::{Arc, Mutex, PoisonError},
+};
+
+use napi::{
+  bindgen_prelude::{Either, Result},
+  threadsafe_function::{ThreadsafeFunction, ThreadsafeFunctionCallMode},
+  Error, Status,
+};
+
+use libsyncrpc_connection::{RpcConnection, DEFAULT_MAX_PAYLOAD_LEN};
+
+use crate::{
+  deadline::{DeadlineReader, DeadlineWriter, DEFAULT_CHUNK_SIZE},
+  spawn_thread,
+  wire::WireResult,
+  ChannelOptions,
+};
+
+/// The environment variable telling the child which descriptors it inherited
+/// through `ChannelOptions.inheritFds`.
+#[cfg(unix)]
+const INHERITED_FDS_VAR: &str = "LIBSYNCRPC_INHERITED_FDS";
+
+/// Default capacity of the buffer requests are written to the child through.
+const DEFAULT_WRITE_BUFFER_SIZE: usize = 8 * 1024;
+
+/// How many stderr lines a request failed by `ChannelOptions.stderrIsFatal`
+/// includes in its error. Any further lines are only counted.
+const MAX_FATAL_STDERR_LINES: usize = 20;
+
+pub(crate) type ChildConnection = RpcConnection<DeadlineReader, BufWriter<DeadlineWriter>>;
+
+/// A JavaScript function that receives the child's stderr, one line at a time.
+pub type StderrCallback = ThreadsafeFunction<String, (), String, Status, false, true>;
+
+/// Where a child's stderr goes.
+#[derive(Clone)]
+pub(crate) enum StderrSink {
+  Inherit,
+  Ignore,
+  Callback(Arc<StderrCallback>),
+}
+
+impl StderrSink {
+  pub fn from_option(option: Option<Either<String, StderrCallback>>) -> Result<Self> {
+    match option {
+      None => Ok(StderrSink::Inherit),
+      Some(Either::A(s)) if s == "inherit" => Ok(StderrSink::Inherit),
+      Some(Either::A(s)) if s == "ignore" => Ok(StderrSink::Ignore),
+      Some(Either::A(s)) => Err(Error::from_reason(format!(
+        "invalid `stderr` option `{s}`: expected \"inherit\", \"ignore\" or a function"
+      ))),
+      Some(Either::B(cb)) => Ok(StderrSink::Callback(Arc::new(cb))),
+    }
+  }
+}
+
+/// The lines a child wrote to its stderr that have yet to fail a request, see
+/// `ChannelOptions.stderrIsFatal`. Shared between the thread draining the
+/// child's stderr and the channel, and kept across respawns.
+#[derive(Default)]
+pub(crate) struct FatalStderr {
+  // The first lines written, and the number of lines left out.
+  lines: Mutex<(Vec<String>, usize)>,
+}
+
+impl FatalStderr {
+  fn push(&self, line: &str) {
+    let mut lines = self.lines.lock().unwrap_or_else(PoisonError::into_inner);
+    if lines.0.len() < MAX_FATAL_STDERR_LINES {
+      lines.0.push(line.into());
+    } else {
+      lines.1 += 1;
+    }
+  }
+
+  /// Returns an error including the lines written since the last call, if
+  /// any, and forgets about them.
+  pub fn take_error(&self) -> Result<()> {
+    let (lines, omitted) =
+      std::mem::take(&mut *self.lines.lock().unwrap_or_else(PoisonError::into_inner));
+    if lines.is_empty() {
+      return Ok(());
+    }
+    let mut message = format!("child process wrote to stderr:\n{}", lines.join("\n"));
+    if omitted > 0 {
+      message.push_str(&format!("\n(and {omitted} more lines)"));
+    }
+    Err(Error::from_reason(message))
+  }
+}
+
+/// The process on the other end of a channel's connection: either a child
+/// the channel spawned, or, for a channel created with
+/// `SyncRpcChannel.fromRaw`, a process the channel knows nothing about and
+/// doesn't own, which killing and waiting for leave alone.
+pub(crate) enum ChildProcess {
+  Spawned(Child),
+  Attached,
+}
+
+impl ChildProcess {
+  pub fn is_attached(&self) -> bool {
+    matches!(self, ChildProcess::Attached)
+  }
+
+  pub fn id(&self) -> Option<u32> {
+    match self {
+      ChildProcess::Spawned(child) => Some(child.id()),
+      ChildProcess::Attached => None,
+    }
+  }
+
+  pub fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+    match self {
+      ChildProcess::Spawned(child) => child.try_wait(),
+      ChildProcess::Attached => Ok(None),
+    }
+  }
+
+  pub fn kill(&mut self) -> io::Result<()> {
+    match self {
+      ChildProcess::Spawned(child) => child.kill(),
+      ChildProcess::Attached => Ok(()),
+    }
+  }
+
+  pub fn wait(&mut self) -> io::Result<()> {
+    match self {
+      ChildProcess::Spawned(child) => child.wait().map(drop),
+      ChildProcess::Attached => Ok(()),
+    }
+  }
+}
+
+/// Everything needed to spawn (and respawn) a channel's child.
+pub(crate) struct ChildSpec {
+  pub exe: String,
+  pub args: Vec<String>,
+  pub options: ChannelOptions,
+  pub stderr: StderrSink,
+  pub fatal_stderr: Option<Arc<FatalStderr>>,
+}
+
+impl ChildSpec {
+  /// Spawns the child process and connects to its stdio.
+  pub fn spawn(&self) -> WireResult<(ChildProcess, ChildConnection)> {
+    let mut cmd = Command::new(&self.exe);
+    cmd
+      .stdin(Stdio::piped())
+      .stdout(Stdio::piped())
+      .stderr(match self.stderr {
+        _ if self.fatal_stderr.is_some() => Stdio::piped(),
+        StderrSink::Inherit => Stdio::inherit(),
+        StderrSink::Ignore => Stdio::null(),
+        StderrSink::Callback(_) => Stdio::piped(),
+      })
+      .args(&self.args);
+    if let Some(cwd) = &self.options.cwd {
+      cmd.current_dir(cwd);
+    }
+    if self.options.clear_env.unwrap_or(false) {
+      cmd.env_clear();
+    }
+    if let Some(env) = &self.options.env {
+      cmd.envs(env);
+    }
+    #[cfg(windows)]
+    if self.options.windows_hide.unwrap_or(true) {
+      hide_window(&mut cmd);
+    }
+    #[cfg(unix)]
+    if let Some(fds) = self
+      .options
+      .inherit_fds
+      .clone()
+      .filter(|fds| !fds.is_empty())
+    {
+      inherit_fds(&mut cmd, fds);
+    }
+    let mut child = cmd
+      .spawn()
+      .map_err(|e| spawn_error(&self.exe, &self.args, e))?;
+    if let Some(stderr) = child.stderr.take() {
+      drain_lines(stderr, self.stderr.clone(), self.fatal_stderr.clone());
+    }
+    let stdin = DeadlineWriter::new(child.stdin.take().expect("Where did ChildStdin go?"))?;
+    let stdout = child.stdout.take().expect("Where did ChildStdout go?");
+    let conn = self.connect(stdin, stdout)?;
+    Ok((ChildProcess::Spawned(child), conn))
+  }
+
+  /// Connects to a process that was started by someone else, through the
+  /// given ends of its stdin and stdout (see `SyncRpcChannel.fromRaw`).
+  pub fn attach(&self, stdin: File, stdout: File) -> WireResult<(ChildProcess, ChildConnection)> {
+    let conn = self.connect(DeadlineWriter::from_file(stdin)?, stdout)?;
+    Ok((ChildProcess::Attached, conn))
+  }
+
+  // Helper method to set up the connection over the child's stdio, as
+  // configured.
+  fn connect<R: Read + Send + 'static>(
+    &self,
+    mut stdin: DeadlineWriter,
+    stdout: R,
+  ) -> WireResult<ChildConnection> {
+    let read_buffer_size = self
+      .options
+      .read_buffer_size
+      .map_or(DEFAULT_CHUNK_SIZE, |size| size as usize);
+    let write_buffer_size = self
+      .options
+      .write_buffer_size
+      .map_or(DEFAULT_WRITE_BUFFER_SIZE, |size| size as usize);
+    let stdout = DeadlineReader::new(stdout, read_buffer_size);
+    stdin.set_read_ahead(stdout.read_ahead());
+    let conn = RpcConnection::with_max_payload_len(
+      stdout,
+      BufWriter::with_capacity(write_buffer_size, stdin),
+      self
+        .options
+        .max_payload_length
+        .map_or(DEFAULT_MAX_PAYLOAD_LEN, |len| len as usize),
+    )?;
+    Ok(conn)
+  }
+}
+
+/// Keeps a console window from popping up for the child of a GUI process.
+#[cfg(windows)]
+pub(crate) fn hide_window(cmd: &mut Command) {
+  use std::os::windows::process::CommandExt;
+
+  const CREATE_NO_WINDOW: u32 = 0x0800_0000;
+  cmd.creation_flags(CREATE_NO_WINDOW);
+}
+
+/// Describes the failure to spawn `exe` with `args`.
+pub(crate) fn spawn_error(exe: &str, args: &[String], e: io::Error) -> Error {
+  let command = std::iter::once(exe)
+    .chain(args.iter().map(String::as_str))
+    .collect::<Vec<_>>()
+    .join(" ");
+  Error::from_reason(format!("failed to spawn `{command}`: {e}"))
+}
+
+// Helper function to have the child inherit `fds` as descriptors 3, 4, and so
+// on, in order, and tell it so through `INHERITED_FDS_VAR`.
+#[cfg(unix)]
+fn inherit_fds(cmd: &mut Command, fds: Vec<i32>) {
+  use std::os::unix::process::CommandExt;
+
+  let targets: Vec<_> = (3..3 + fds.len() as i32).collect();
+  let var = targets
+    .iter()
+    .map(i32::to_string)
+    .collect::<Vec<_>>()
+    .join(",");
+  cmd.env(INHERITED_FDS_VAR, var);
+  // Allocated up front, since the closure runs in the forked child, where
+  // allocating isn't safe.
+  let mut copies = vec![0; fds.len()];
+  let above_targets = 3 + fds.len() as i32;
+  // SAFETY: The closure only makes async-signal-safe calls (`fcntl` and
+  // `dup2`) and doesn't allocate.
+  unsafe {
+    cmd.pre_exec(move || {
+      // Copy every descriptor out of the way first, so that moving one into
+      // place can't clobber another that is yet to be moved. The copies are
+      // closed on exec.
+      for (copy, &fd) in copies.iter_mut().zip(&fds) {
+        *copy = libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, above_targets);
+        if *copy < 0 {
+          return Err(io::Error::last_os_error());
+        }
+      }
+      // `dup2` clears close-on-exec on the new descriptor.
+      for (&copy, &target) in copies.iter().zip(&targets) {
+        if libc::dup2(copy, target) < 0 {
+          return Err(io::Error::last_os_error());
+        }
+      }
+      Ok(())
+    });
+  }
+}
+
+// Helper function to forward each line read from `reader` to `sink`, and to
+// `fatal`, if any, on a background thread, which exits once the reader reaches
+// EOF (i.e. when the child exits or is killed).
+fn drain_lines<R: Read + Send + 'static>(
+  reader: R,
+  sink: StderrSink,
+  fatal: Option<Arc<FatalStderr>>,
+) {
+  spawn_thread("libsyncrpc-stderr", move || {
+    let mut reader = BufReader::new(reader);
+    let mut line = Vec::new();
+    loop {
+      line.clear();
+      match reader.read_until(b'\n', &mut line) {
+        Ok(0) | Err(_) => break,
+        Ok(_) => {
+          let line = String::from_utf8_lossy(&line);
+          let line = line.trim_end_matches(['\r', '\n']);
+          match &sink {
+            StderrSink::Inherit => eprintln!("{line}"),
+            StderrSink::Ignore => {}
+            StderrSink::Callback(cb) => {
+              cb.call(line.into(), ThreadsafeFunctionCallMode::NonBlocking);
+            }
+          }
+          if let Some(fatal) = &fatal {
+            fatal.push(line);
+          }
+        }
+      }
+    }
+  });
+}
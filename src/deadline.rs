@@ -0,0 +1,338 @@
+#[cfg(unix)]
+use std::os::fd::AsRawFd;
+use std::{
+  fs::File,
+  io::{self, BufRead, Read, Write},
+  process::ChildStdin,
+  sync::{
+    mpsc::{self, Receiver, RecvTimeoutError},
+    Arc, Condvar, Mutex, MutexGuard, PoisonError,
+  },
+  time::Instant,
+};
+
+use crate::spawn_thread;
+
+/// Default size of the chunks the background thread reads from the
+/// underlying reader.
+pub(crate) const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// How many chunks the background thread of a `DeadlineReader` reads ahead
+/// of the reader at most, outside of writes (see `ReadAhead`).
+const MAX_QUEUED_CHUNKS: usize = 16;
+
+/// A buffered reader that drains an underlying (blocking) reader on a
+/// background thread, so that reads can give up once a deadline has passed
+/// instead of blocking forever.
+///
+/// Draining the child's stdout as it is written also means that a child can't
+/// block on a full stdout pipe while this process is blocked writing to its
+/// stdin, say writing a large request to a child that is busy writing a large
+/// response: each would otherwise wait for the other to read, forever. So
+/// that a child flooding its stdout can't take up unbounded memory, only up
+/// to `MAX_QUEUED_CHUNKS` chunks are read ahead, except while the
+/// `DeadlineWriter` sharing its `ReadAhead` is writing.
+///
+/// Waiting on the background thread blocks on a channel rather than polling,
+/// so an idle reader doesn't spin the CPU. EOF is only reported once every
+/// chunk read before it has been consumed, so nothing the child wrote before
+/// exiting is lost.
+pub(crate) struct DeadlineReader {
+  rx: Receiver<io::Result<Vec<u8>>>,
+  read_ahead: Arc<ReadAhead>,
+  chunk: Vec<u8>,
+  pos: usize,
+  deadline: Option<Instant>,
+  // Total number of bytes consumed so far.
+  consumed: u64,
+}
+
+impl DeadlineReader {
+  /// Starts draining `inner` in chunks of up to `chunk_size` bytes.
+  pub fn new<R: Read + Send + 'static>(mut inner: R, chunk_size: usize) -> Self {
+    let (tx, rx) = mpsc::channel();
+    let read_ahead = Arc::new(ReadAhead::default());
+    let limit = read_ahead.clone();
+    spawn_thread("libsyncrpc-stdout", move || {
+      // Chunks are sent as copies of what was read, sized to fit, rather
+      // than as whole buffers of `chunk_size` bytes.
+      let mut buf = vec![0u8; chunk_size];
+      loop {
+        limit.wait_for_room();
+        match inner.read(&mut buf) {
+          // Dropping the sender is how EOF gets reported.
+          Ok(0) => break,
+          Ok(n) => {
+            limit.lock().queued += 1;
+            if tx.send(Ok(buf[..n].to_vec())).is_err() {
+              break;
+            }
+          }
+          Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+          Err(e) => {
+            let _ = tx.send(Err(e));
+            break;
+          }
+        }
+      }
+    });
+    Self {
+      rx,
+      read_ahead,
+      chunk: Vec::new(),
+      pos: 0,
+      deadline: None,
+      consumed: 0,
+    }
+  }
+
+  /// Sets the point in time after which reads will fail with
+  /// `io::ErrorKind::TimedOut`. `None` means reads block indefinitely.
+  pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+    self.deadline = deadline;
+  }
+
+  /// The limit on how far the reader reads ahead, for the `DeadlineWriter`
+  /// to the same child to lift while it writes.
+  pub fn read_ahead(&self) -> Arc<ReadAhead> {
+    self.read_ahead.clone()
+  }
+
+  /// The total number of bytes consumed from the reader so far.
+  pub fn consumed(&self) -> u64 {
+    self.consumed
+  }
+
+  /// Whether the current deadline, if any, has already passed.
+  pub fn expired(&self) -> bool {
+    self.deadline.is_some_and(|d| Instant::now() >= d)
+  }
+}
+
+impl Read for DeadlineReader {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let available = self.fill_buf()?;
+    let n = available.len().min(buf.len());
+    buf[..n].copy_from_slice(&available[..n]);
+    self.consume(n);
+    Ok(n)
+  }
+}
+
+impl BufRead for DeadlineReader {
+  fn fill_buf(&mut self) -> io::Result<&[u8]> {
+    if self.pos == self.chunk.len() {
+      let next = match self.deadline {
+        None => self.rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        Some(deadline) => self
+          .rx
+          .recv_timeout(deadline.saturating_duration_since(Instant::now())),
+      };
+      match next {
+        Ok(chunk) => {
+          if chunk.is_ok() {
+            self.read_ahead.consumed_chunk();
+          }
+          self.chunk = chunk?;
+          self.pos = 0;
+        }
+        Err(RecvTimeoutError::Timeout) => return Err(io::ErrorKind::TimedOut.into()),
+        Err(RecvTimeoutError::Disconnected) => return Ok(&[]),
+      }
+    }
+    Ok(&self.chunk[self.pos..])
+  }
+
+  fn consume(&mut self, amt: usize) {
+    let amt = amt.min(self.chunk.len() - self.pos);
+    self.pos += amt;
+    self.consumed += amt as u64;
+  }
+}
+
+/// The number of chunks a `DeadlineReader` has read ahead, and whether the
+/// `DeadlineWriter` to the same child is writing, during which there is no
+/// limit: the child may be blocked writing its stdout until this process
+/// reads it, and unable to read the rest of what is being written until then.
+#[derive(Default)]
+pub(crate) struct ReadAhead {
+  state: Mutex<ReadAheadState>,
+  changed: Condvar,
+}
+
+#[derive(Default)]
+struct ReadAheadState {
+  queued: usize,
+  writing: bool,
+}
+
+impl ReadAhead {
+  fn lock(&self) -> MutexGuard<'_, ReadAheadState> {
+    self.state.lock().unwrap_or_else(PoisonError::into_inner)
+  }
+
+  // Helper method to block the background thread of a `DeadlineReader`
+  // until it may read another chunk.
+  fn wait_for_room(&self) {
+    let mut state = self.lock();
+    while state.queued >= MAX_QUEUED_CHUNKS && !state.writing {
+      state = self
+        .changed
+        .wait(state)
+        .unwrap_or_else(PoisonError::into_inner);
+    }
+  }
+
+  fn consumed_chunk(&self) {
+    self.lock().queued -= 1;
+    self.changed.notify_one();
+  }
+
+  fn set_writing(&self, writing: bool) {
+    self.lock().writing = writing;
+    self.changed.notify_one();
+  }
+}
+
+/// A writer to the child's stdin that gives up once a deadline has passed
+/// instead of blocking forever, should the child stop reading its stdin.
+///
+/// On Unix, the pipe is put in non-blocking mode, and writes that can't make
+/// progress wait for it to become writable until the deadline, then fail
+/// with `io::ErrorKind::TimedOut`. Elsewhere, writes block as usual and the
+/// deadline is ignored.
+pub(crate) struct DeadlineWriter {
+  // `None` once closed, after which writes fail with
+  // `io::ErrorKind::BrokenPipe`.
+  inner: Option<File>,
+  deadline: Option<Instant>,
+  read_ahead: Option<Arc<ReadAhead>>,
+}
+
+impl DeadlineWriter {
+  pub fn new(inner: ChildStdin) -> io::Result<Self> {
+    #[cfg(unix)]
+    let inner = File::from(std::os::fd::OwnedFd::from(inner));
+    #[cfg(windows)]
+    let inner = File::from(std::os::windows::io::OwnedHandle::from(inner));
+    Self::from_file(inner)
+  }
+
+  /// Like `new`, for the write end of a pipe to a process the channel didn't
+  /// spawn.
+  pub fn from_file(inner: File) -> io::Result<Self> {
+    #[cfg(unix)]
+    unsafe {
+      let fd = inner.as_raw_fd();
+      let flags = libc::fcntl(fd, libc::F_GETFL);
+      if flags == -1 || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) == -1 {
+        return Err(io::Error::last_os_error());
+      }
+    }
+    Ok(Self {
+      inner: Some(inner),
+      deadline: None,
+      read_ahead: None,
+    })
+  }
+
+  /// Lifts the limit on how far the `DeadlineReader` from the same child
+  /// reads ahead for the duration of each write, see `ReadAhead`.
+  pub fn set_read_ahead(&mut self, read_ahead: Arc<ReadAhead>) {
+    self.read_ahead = Some(read_ahead);
+  }
+
+  /// Closes the pipe, so that the other end sees EOF.
+  pub fn close(&mut self) {
+    self.inner = None;
+  }
+
+  // Helper method to get the pipe, unless it was closed.
+  fn inner(&mut self) -> io::Result<&mut File> {
+    self
+      .inner
+      .as_mut()
+      .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "the connection was closed"))
+  }
+
+  /// Sets the point in time after which writes will fail with
+  /// `io::ErrorKind::TimedOut`. `None` means writes block indefinitely.
+  pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+    self.deadline = deadline;
+  }
+
+  // Helper method to wait for the pipe to become writable, up to the
+  // deadline.
+  #[cfg(unix)]
+  fn wait_writable(&self) -> io::Result<()> {
+    let timeout_ms = match self.deadline {
+      None => -1,
+      Some(deadline) => {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+          return Err(write_timed_out());
+        }
+        remaining.as_millis().clamp(1, i32::MAX as u128) as i32
+      }
+    };
+    let Some(inner) = &self.inner else {
+      return Ok(());
+    };
+    let mut pollfd = libc::pollfd {
+      fd: inner.as_raw_fd(),
+      events: libc::POLLOUT,
+      revents: 0,
+    };
+    match unsafe { libc::poll(&mut pollfd, 1, timeout_ms) } {
+      -1 => {
+        let e = io::Error::last_os_error();
+        if e.kind() == io::ErrorKind::Interrupted {
+          return Ok(());
+        }
+        Err(e)
+      }
+      0 => Err(write_timed_out()),
+      // The next write finds out whether the child closed the pipe.
+      _ => Ok(()),
+    }
+  }
+
+  #[cfg(not(unix))]
+  fn wait_writable(&self) -> io::Result<()> {
+    Ok(())
+  }
+}
+
+impl Write for DeadlineWriter {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    if let Some(read_ahead) = &self.read_ahead {
+      read_ahead.set_writing(true);
+    }
+    let res = loop {
+      match self.inner().and_then(|inner| inner.write(buf)) {
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+          if let Err(e) = self.wait_writable() {
+            break Err(e);
+          }
+        }
+        res => break res,
+      }
+    };
+    if let Some(read_ahead) = &self.read_ahead {
+      read_ahead.set_writing(false);
+    }
+    res
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.inner()?.flush()
+  }
+}
+
+#[cfg(unix)]
+fn write_timed_out() -> io::Error {
+  io::Error::new(
+    io::ErrorKind::TimedOut,
+    "timed out waiting for the child to read its stdin",
+  )
+}
@@ -0,0 +1,105 @@
+use std::{
+  sync::{Arc, Condvar, Mutex, PoisonError},
+  time::{Duration, Instant},
+};
+
+use crate::{child::ChildProcess, spawn_thread};
+
+/// Kills a channel's child on a background thread once no request has been
+/// issued for a given amount of time, so idle children don't hold on to
+/// resources. The channel is expected to respawn the child on its next
+/// request.
+pub(crate) struct IdleReaper {
+  shared: Arc<Shared>,
+}
+
+struct Shared {
+  state: Mutex<State>,
+  cvar: Condvar,
+}
+
+struct State {
+  last_activity: Instant,
+  busy: bool,
+  reaped: bool,
+  shutdown: bool,
+}
+
+impl IdleReaper {
+  pub fn new(timeout: Duration, child: Arc<Mutex<ChildProcess>>) -> Self {
+    let shared = Arc::new(Shared {
+      state: Mutex::new(State {
+        last_activity: Instant::now(),
+        busy: false,
+        reaped: false,
+        shutdown: false,
+      }),
+      cvar: Condvar::new(),
+    });
+    let thread_shared = shared.clone();
+    spawn_thread("libsyncrpc-idle", move || {
+      run(&thread_shared, timeout, &child)
+    });
+    Self { shared }
+  }
+
+  /// Marks the start of a request, during which the child will not be
+  /// reaped. Returns whether the child was reaped while idle, in which case
+  /// the caller must respawn it.
+  pub fn begin(&self) -> bool {
+    let mut state = self.shared.lock();
+    state.busy = true;
+    std::mem::take(&mut state.reaped)
+  }
+
+  /// Marks the end of a request, restarting the idle countdown.
+  pub fn end(&self) {
+    let mut state = self.shared.lock();
+    state.busy = false;
+    state.last_activity = Instant::now();
+    self.shared.cvar.notify_one();
+  }
+}
+
+impl Drop for IdleReaper {
+  fn drop(&mut self) {
+    self.shared.lock().shutdown = true;
+    self.shared.cvar.notify_one();
+  }
+}
+
+impl Shared {
+  fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+    self.state.lock().unwrap_or_else(PoisonError::into_inner)
+  }
+}
+
+fn run(shared: &Shared, timeout: Duration, child: &Mutex<ChildProcess>) {
+  let mut state = shared.lock();
+  loop {
+    if state.shutdown {
+      return;
+    }
+    if state.busy || state.reaped {
+      state = shared
+        .cvar
+        .wait(state)
+        .unwrap_or_else(PoisonError::into_inner);
+      continue;
+    }
+    let idle = state.last_activity.elapsed();
+    if idle >= timeout {
+      let mut child = child.lock().unwrap_or_else(PoisonError::into_inner);
+      // Errors here mean the child is already gone, which is what we want.
+      let _ = child.kill();
+      let _ = child.wait();
+      state.reaped = true;
+      continue;
+    }
+    state = shared
+      .cvar
+      .wait_timeout(state, timeout - idle)
+      .unwrap_or_else(PoisonError::into_inner)
+      .0;
+  }
+}
@@ -1,194 +1,2354 @@
 use std::{
   collections::HashMap,
-  io::{BufReader, BufWriter},
-  process::{Child, ChildStdin, ChildStdout},
+  fs::File,
+  io,
+  path::Path,
+  sync::{
+    atomic::{AtomicBool, AtomicI64, Ordering},
+    Arc, Mutex, MutexGuard, PoisonError, TryLockError,
+  },
+  time::{Duration, Instant},
 };
 
 use napi::{
-  bindgen_prelude::{FnArgs, Function, FunctionRef, Result, Uint8Array},
-  Env, Error,
+  bindgen_prelude::{
+    AsyncTask, Buffer, Either, FnArgs, FromNapiValue, Function, FunctionRef, JsObjectValue, Result,
+    Uint8Array, Unknown,
+  },
+  threadsafe_function::ThreadsafeFunction,
+  Env, Error, JsValue, Status, ValueType,
 };
 
-use libsyncrpc_connection::RpcConnection;
+use libsyncrpc_connection::{MessageBuffers, RpcError, PROTOCOL_VERSION};
+
+pub use async_request::{AsyncCallback, AsyncRequest};
+pub use child::StderrCallback;
+use child::{ChildProcess, ChildSpec, FatalStderr, StderrSink};
+use idle::IdleReaper;
+pub use oneshot::request_oneshot;
+pub use pool::SyncRpcPool;
+use trace::Tracer;
+use wire::{wait_for_exit, Metrics, RemoteResult, RequestOptions, Wire, WireError, WireResult};
+
+mod async_request;
+mod cbor;
+mod child;
+mod deadline;
+mod idle;
+mod oneshot;
+mod pool;
+mod trace;
+mod wire;
 
 #[macro_use]
 extern crate napi_derive;
 
-pub type Callback = Function<'static, FnArgs<(String, String)>, String>;
+// Callbacks that return strings are typed to return anything, so that other
+// return values can be reported as such, see `callback_string`.
+pub type Callback = Function<'static, FnArgs<(String, String)>, Unknown<'static>>;
+pub type BinaryCallback = Function<'static, FnArgs<(String, Uint8Array)>, Uint8Array>;
+pub type HeaderCallback = Function<'static, FnArgs<(String, String, String)>, Unknown<'static>>;
+pub type DefaultCallback = Function<'static, FnArgs<(String, String)>, Unknown<'static>>;
+pub type ChunkCallback = Function<'static, Uint8Array, ()>;
+pub type ObserverCallback = Function<'static, FnArgs<(String, String, i64)>, ()>;
+/// A JavaScript function that receives the child's notifications, see
+/// `MessageType.Notify`.
+pub type NotifyCallback =
+  ThreadsafeFunction<FnArgs<(String, String)>, (), FnArgs<(String, String)>, Status, false, true>;
+
+/// A JavaScript callback registered on a channel, by payload kind.
+enum RegisteredCallback {
+  String(FunctionRef<FnArgs<(String, String)>, Unknown<'static>>),
+  Binary(FunctionRef<FnArgs<(String, Uint8Array)>, Uint8Array>),
+  Header(FunctionRef<FnArgs<(String, String, String)>, Unknown<'static>>),
+  Async(Arc<AsyncCallback>),
+}
+
+/// The callback registered with `SyncRpcChannel#registerDefaultCallback`.
+type DefaultCallbackRef = FunctionRef<FnArgs<(String, String)>, Unknown<'static>>;
+
+/// The function set with `SyncRpcChannel#setObserver`.
+type ObserverRef = FunctionRef<FnArgs<(String, String, i64)>, ()>;
+
+/// A synchronous RPC channel that allows JavaScript to synchronously call out
+/// to a child process and get a response over a line-based protocol,
+/// including handling of JavaScript-side callbacks before the call completes.
+///
+/// #### Protocol
+///
+/// Requests follow a MessagePack-based "tuple"/array protocol with 3 items:
+/// `(<type>, <name>, <payload>)`. All items are binary arrays of 8-bit
+/// integers, including the `<type>` and `<name>`, to avoid unnecessary
+/// encoding/decoding at the protocol level.
+///
+/// Some messages carry optional 4th and 5th items, see `MessageType.Request`.
+///
+/// For specific message types and their corresponding protocol behavior, please
+/// see `MessageType` below.
+#[napi]
+pub struct SyncRpcChannel {
+  spec: ChildSpec,
+  child: Arc<Mutex<ChildProcess>>,
+  wire: Arc<Mutex<Wire>>,
+  callbacks: HashMap<String, RegisteredCallback>,
+  default_callback: Option<DefaultCallbackRef>,
+  observer: Option<ObserverRef>,
+  metrics: Arc<Metrics>,
+  cancel_requested: Arc<AtomicBool>,
+  idle: Option<Arc<IdleReaper>>,
+  // The timeout of synchronous requests that don't set their own, see
+  // `setDefaultTimeout`.
+  default_timeout: Option<Duration>,
+  handshake_mode: Option<HandshakeMode>,
+  protocol_version: Option<u32>,
+  // The payload compression threshold to request from the child, if any, and
+  // whether the child agreed to it.
+  compression_threshold: Option<usize>,
+  compressed: bool,
+  // Whether to ask the child for payload checksums, and whether it agreed.
+  checksum_requested: bool,
+  checksummed: bool,
+  // Number of times the child was restarted after exiting unexpectedly (see
+  // `ChannelOptions.restartPolicy`), and whether the channel was closed, so
+  // that it stays closed.
+  restarts: u32,
+  closed: bool,
+}
+
+/// Cancels the requests of the `SyncRpcChannel` it was obtained from, see
+/// `SyncRpcChannel#cancellationHandle`.
+#[napi]
+pub struct CancellationHandle {
+  requested: Arc<AtomicBool>,
+}
+
+#[napi]
+impl CancellationHandle {
+  /// Asks the child to cancel the channel's request in progress by sending
+  /// it a `MessageType.Cancel` (or one per outstanding request, for
+  /// `requestBatchSync`). A child that abandons the request responds with a
+  /// structured `MessageType.Error` with code `"ECANCELED"`, which the
+  /// request throws (or rejects with) like any structured error, so
+  /// cancellation can be told apart by the error's `code`. A child that
+  /// ignores the cancellation responds as usual.
+  ///
+  /// The channel only gets to send the cancellation once the child invokes
+  /// a callback: it is sent as soon as that callback returns. Calling this
+  /// from within a callback invoked during the request, whether synchronous
+  /// or made with `requestAsync`, is therefore the supported way to cancel a
+  /// request. Calling it from elsewhere while a `requestAsync` is in progress
+  /// only takes effect if the child invokes a callback later on, and calling
+  /// it when no request is in progress does nothing.
+  #[napi]
+  pub fn cancel(&self) {
+    self.requested.store(true, Ordering::Relaxed);
+  }
+}
+
+/// Whether a channel requires its child to complete the protocol handshake.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum HandshakeMode {
+  Required,
+  Optional,
+}
+
+/// How long past a deadline sent to the child to wait for its response before
+/// timing out the request.
+const DEADLINE_GRACE_PERIOD: Duration = Duration::from_millis(250);
+
+/// The environment variable `ChannelOptions.traceFile` defaults to.
+const TRACE_FILE_VAR: &str = "LIBSYNCRPC_TRACE_FILE";
+
+/// How often to check for a child's readiness file in `wait_ready_file`.
+const READY_FILE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The reserved method name of the no-op requests sent by `ping`.
+const PING_METHOD: &str = "$/ping";
+
+/// The reserved method name of the protocol handshake request.
+const HANDSHAKE_METHOD: &str = "$/handshake";
+
+/// The reserved method name of the compression negotiation request.
+const COMPRESSION_METHOD: &str = "$/compression";
+
+/// The name of the compression codec offered to the child.
+const COMPRESSION_ZSTD: &str = "zstd";
+
+/// The reserved method name of the checksum negotiation request.
+const CHECKSUM_METHOD: &str = "$/checksum";
+
+/// The name of the checksum algorithm offered to the child.
+const CHECKSUM_CRC32: &str = "crc32";
+
+/// Payloads at least this long are compressed by default, once compression
+/// has been negotiated.
+const DEFAULT_COMPRESSION_THRESHOLD: usize = 1024;
+
+/// How long to wait for the child to respond to each of the reserved requests
+/// made when it is started.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The oldest protocol version a child may speak to complete the handshake.
+const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Number of counter slots needed to index by `MessageType` byte value.
+const MESSAGE_TYPE_SLOTS: usize = MessageType::_UnusedPlaceholderVariant as usize;
+
+/// Optional settings for constructing a `SyncRpcChannel`.
+#[napi(object, object_to_js = false)]
+#[derive(Default)]
+pub struct ChannelOptions {
+  /// The working directory to spawn the child in. Defaults to the current
+  /// working directory.
+  pub cwd: Option<String>,
+  /// Environment variables to set for the child, in addition to the
+  /// inherited environment (unless `clearEnv` is set).
+  pub env: Option<HashMap<String, String>>,
+  /// Open file descriptors of this process for the child to inherit, such as
+  /// a pre-bound socket, in addition to its stdio. The child receives them as
+  /// descriptors 3, 4, and so on, in the order given, and the comma-separated
+  /// list of their numbers in the child is set in its
+  /// `LIBSYNCRPC_INHERITED_FDS` environment variable. Only supported on Unix.
+  pub inherit_fds: Option<Vec<i32>>,
+  /// Whether to start the child with an empty environment, rather than
+  /// inheriting this process's environment. Only variables in `env` will be
+  /// set.
+  pub clear_env: Option<bool>,
+  /// Whether to hide the child's console window on Windows, by spawning it
+  /// with the `CREATE_NO_WINDOW` flag. Defaults to `true`, so that apps without
+  /// a console, such as Electron apps, don't flash a window for each child.
+  /// Set it to `false` for a child that needs a console of its own. The child's
+  /// stdio is piped either way. Ignored elsewhere.
+  pub windows_hide: Option<bool>,
+  /// Where the child's stderr goes: `"inherit"` (the default) shares this
+  /// process's stderr, `"ignore"` discards it, and a function is called with
+  /// each line the child writes, without its line terminator.
+  ///
+  /// Lines are delivered asynchronously through the event loop, so they will
+  /// not be seen while a synchronous request is still in progress.
+  #[napi(ts_type = "'inherit' | 'ignore' | ((line: string) => void)")]
+  pub stderr: Option<Either<String, StderrCallback>>,
+  /// Whether any output from the child on its stderr fails requests, for
+  /// children that should never write to it. Lines are still passed on as
+  /// configured by `stderr`, which can't be `"ignore"`. Defaults to `false`.
+  ///
+  /// Before a request is sent, and again once the child has responded to it,
+  /// the request throws with the lines written since the last request that
+  /// threw for them, if any. The channel itself stays usable. Since stderr
+  /// and stdout are separate pipes, a line written just before a response
+  /// can arrive after it, in which case the request succeeds, and the next
+  /// request throws before it is sent.
+  pub stderr_is_fatal: Option<bool>,
+  /// A file to append a machine-readable trace of every message sent to or
+  /// received from the child to, one JSON object per line. Each object has
+  /// `ts` (microseconds since the Unix epoch), `dir` (`"send"` or `"recv"`),
+  /// `type` and `typeName` (the `MessageType`), `name`, and `len` (the
+  /// payload's length in bytes) properties.
+  ///
+  /// Defaults to the `LIBSYNCRPC_TRACE_FILE` environment variable, if set, so
+  /// that the protocol can be traced without changing any code. See also
+  /// `SyncRpcChannel#setTraceFile`.
+  pub trace_file: Option<String>,
+  /// Whether to add a `hash` property to each `traceFile` record: a hex
+  /// string of the 64-bit FNV-1a hash of the payload, to tell payloads apart
+  /// without logging their contents. Defaults to `false`.
+  pub trace_payload_hashes: Option<bool>,
+  /// How many bytes at the start of each payload to add to each `traceFile`
+  /// record as a `preview` property, holding them as text if they are valid
+  /// UTF-8, or as a `previewHex` property, holding them as a hex string,
+  /// otherwise. Nothing is redacted from previews, so they may expose
+  /// whatever the payloads contain. Defaults to 0, for no previews.
+  pub trace_preview_bytes: Option<u32>,
+  /// Whether to negotiate a protocol version with the child before the
+  /// channel is used (see `MessageType.Request` for how). With `"required"`,
+  /// the constructor throws if the child does not complete the handshake or
+  /// speaks an incompatible version. With `"optional"`, a child that responds
+  /// to the handshake with a `MessageType.Error` is used in legacy mode, as if
+  /// no handshake had taken place. By default, no handshake is performed.
+  ///
+  /// See `SyncRpcChannel#protocolVersion` for the negotiated version.
+  #[napi(ts_type = "'required' | 'optional'")]
+  pub handshake: Option<String>,
+  /// A codec to compress payloads with, if the child supports it (see
+  /// `MessageType.Request` for how this is negotiated). Only `"zstd"` is
+  /// supported, and only if libsyncrpc was built with its `zstd` feature (on
+  /// by default). By default, payloads are not compressed.
+  ///
+  /// See `SyncRpcChannel#compression` for whether the child agreed.
+  #[napi(ts_type = "'zstd'")]
+  pub compression: Option<String>,
+  /// The minimum length, in bytes, of payloads this channel compresses, once
+  /// compression has been negotiated. Defaults to 1024.
+  pub compression_threshold: Option<u32>,
+  /// A checksum to add to every message, if the child supports it (see
+  /// `MessageType.Request` for how this is negotiated), so that a message
+  /// corrupted on the way is rejected rather than misinterpreted. Only
+  /// `"crc32"` is supported. By default, messages have no checksum.
+  ///
+  /// A message whose checksum doesn't match poisons the channel, as with a
+  /// timeout. See `SyncRpcChannel#checksum` for whether the child agreed.
+  #[napi(ts_type = "'crc32'")]
+  pub checksum: Option<String>,
+  /// A function called with the `<payload>` of each `MessageType.Log` message
+  /// the child sends, as a string. Like `stderr` lines, these are delivered
+  /// asynchronously through the event loop. By default, they are discarded.
+  #[napi(ts_type = "(message: string) => void")]
+  pub log: Option<StderrCallback>,
+  /// The maximum length, in bytes, of the name or payload of a message from
+  /// the child. Longer messages are rejected before room is made for them,
+  /// which poisons the channel as with a timeout. Defaults to 256 MiB.
+  pub max_payload_length: Option<u32>,
+  /// The size, in bytes, of the chunks read from the child's stdout at a
+  /// time. Must be greater than 0. Defaults to 64 KiB.
+  pub read_buffer_size: Option<u32>,
+  /// The capacity, in bytes, of the buffer messages are written to the
+  /// child's stdin through. Defaults to 8 KiB.
+  pub write_buffer_size: Option<u32>,
+  /// Whether, and how often, to restart the child if it exits unexpectedly.
+  /// By default, a child that exits is not restarted, and every subsequent
+  /// request throws.
+  pub restart_policy: Option<RestartPolicy>,
+  /// Whether to send every request with an `<id>` that the child must echo
+  /// in its response (see `MessageType.Request`). Defaults to `false`.
+  ///
+  /// A response whose `<id>` is not that of the request in progress is
+  /// discarded as a late response to an earlier request, and a
+  /// `MessageType.Call` made on behalf of an earlier request is answered
+  /// with a `MessageType.CallError`. A request that times out while waiting
+  /// for the child then no longer poisons the channel (see
+  /// `requestSyncTimeout`): the next request can be made, such as to retry,
+  /// without its late response being mistaken for the new one's.
+  pub request_ids: Option<bool>,
+  /// Whether the `<name>` of each `MessageType.Response` and
+  /// `MessageType.Error` (and `MessageType.ResponseChunk`) must match that
+  /// of the request it is for. Defaults to `true`, with a mismatch failing
+  /// the request as a protocol violation.
+  ///
+  /// With `false`, the first response to arrive is taken as the response to
+  /// the request in progress, whatever its name, for children that respond
+  /// under names of their own. Batched requests (see `requestBatchSync`)
+  /// are then matched by `<id>` alone.
+  pub strict_response_names: Option<bool>,
+}
+
+/// How a channel restarts a child that exits unexpectedly, see
+/// `ChannelOptions.restartPolicy`.
+///
+/// When a synchronous request fails because the child exited (whether
+/// before or during the request), the child is respawned with the original
+/// `exe` and `args`, keeping any registered callbacks, and the request is
+/// retried once. Any state the child held in memory is lost, and callbacks
+/// it invoked before exiting may be invoked again by the retry. A child that
+/// exits again during the retry is only restarted by the next request.
+#[napi(object, object_to_js = false)]
+pub struct RestartPolicy {
+  /// The maximum number of restarts over the channel's lifetime. Once they
+  /// are used up, a request that finds the child gone throws instead.
+  pub max_restarts: u32,
+  /// How long to wait before each restart, in milliseconds. Defaults to 0.
+  pub backoff_ms: Option<u32>,
+}
+
+/// A snapshot of a channel's counters, as returned by
+/// `SyncRpcChannel#stats`.
+#[napi(object)]
+pub struct ChannelStats {
+  /// Number of messages sent to the child, indexed by `MessageType` value.
+  /// Index `0` is unused.
+  pub messages_sent: Vec<i64>,
+  /// Number of messages received from the child, indexed by `MessageType`
+  /// value. Index `0` is unused.
+  pub messages_received: Vec<i64>,
+  /// Number of requests made, whether they succeeded or not. Each request in
+  /// a batch counts as one. Reserved requests, such as pings, don't count.
+  pub requests: i64,
+  /// Total length, in bytes, of the payloads of successful responses.
+  pub response_bytes: i64,
+  /// Number of times the child invoked a callback, including unknown ones.
+  pub callbacks: i64,
+  /// Number of requests that failed, whether the child reported an error or
+  /// the channel itself failed.
+  pub errors: i64,
+}
+
+/// A request in a batch made with `SyncRpcChannel#requestBatchSync`.
+#[napi(object, object_to_js = false)]
+pub struct BatchRequest {
+  /// The method name of the request.
+  pub method: String,
+  /// The raw payload of the request.
+  pub payload: Uint8Array,
+}
+
+/// The outcome of a request in a batch made with
+/// `SyncRpcChannel#requestBatchSync`.
+#[napi(object)]
+pub struct BatchResult {
+  /// Whether the child responded successfully.
+  pub ok: bool,
+  /// The child's raw response, if `ok` is `true`.
+  pub value: Option<Uint8Array>,
+  /// The error reported by the child, if `ok` is `false`.
+  pub error: Option<String>,
+}
+
+/// The response returned by `SyncRpcChannel#requestAutoSync`, tagged with
+/// how it was decoded.
+#[napi(object)]
+pub struct AutoResponse {
+  /// `"text"` if the response is valid UTF-8, `"binary"` otherwise.
+  #[napi(js_name = "type", ts_type = "'text' | 'binary'")]
+  pub kind: String,
+  /// The response, as a string if `type` is `"text"`, or as is if it is
+  /// `"binary"`.
+  pub value: Either<String, Uint8Array>,
+}
+
+/// The outcome of `SyncRpcChannel#tryRequestSync`.
+#[napi(object)]
+pub struct TryRequestResult {
+  /// Whether the child responded successfully.
+  pub ok: bool,
+  /// The child's response, if `ok` is `true`.
+  pub value: Option<String>,
+  /// The error reported by the child, if `ok` is `false`.
+  pub error: Option<String>,
+}
+
+#[napi]
+impl SyncRpcChannel {
+  /// Constructs a new `SyncRpcChannel` by spawning a child process with the
+  /// given `exe` executable, and a given set of `args`. See `ChannelOptions`
+  /// for further settings.
+  #[napi(constructor)]
+  pub fn new(
+    env: Env,
+    exe: String,
+    args: Vec<String>,
+    options: Option<ChannelOptions>,
+  ) -> Result<Self> {
+    let options = options.unwrap_or_default();
+    if exe.contains('\0') {
+      return Err(Error::from_reason(
+        "executable path contains an invalid NUL byte",
+      ));
+    }
+    if let Some(i) = args.iter().position(|arg| arg.contains('\0')) {
+      return Err(Error::from_reason(format!(
+        "argument {i} contains an invalid NUL byte"
+      )));
+    }
+    if let Some(cwd) = &options.cwd {
+      if !Path::new(cwd).is_dir() {
+        return Err(Error::from_reason(format!(
+          "working directory `{cwd}` does not exist or is not a directory"
+        )));
+      }
+    }
+    if let Some(fds) = &options.inherit_fds {
+      if !cfg!(unix) && !fds.is_empty() {
+        return Err(Error::from_reason(
+          "the `inheritFds` option is only supported on Unix",
+        ));
+      }
+      if let Some(fd) = fds.iter().find(|&&fd| fd < 3) {
+        return Err(Error::from_reason(format!(
+          "invalid `inheritFds` option: cannot pass descriptor {fd}, since the child's stdio is used for the protocol"
+        )));
+      }
+    }
+    Self::connect(&env, exe, args, options, None)
+  }
+
+  /// Constructs a new `SyncRpcChannel` that talks to a process started by
+  /// someone else, such as a process manager that supervises it, rather than
+  /// spawning one. `stdinFd` is the descriptor to write requests to, as the
+  /// process's stdin would be, and `stdoutFd` the one to read its responses
+  /// from. On Windows, these are handles rather than file descriptors.
+  ///
+  /// The channel takes ownership of both, which must be separate pipes (or
+  /// other files) rather than a single socket, and puts `stdinFd` in
+  /// non-blocking mode. Options that concern spawning the process, such as
+  /// `cwd`, `env`, `stderr` and `restartPolicy`, are ignored.
+  ///
+  /// The channel doesn't own the process, so `pid`, `exitStatus`, `signal`,
+  /// `isAlive`, `terminate`, `setIdleTimeout` and `respawn` throw, and
+  /// `close()` only closes the connection, leaving the process running.
+  #[napi(
+    factory,
+    ts_args_type = "stdinFd: number, stdoutFd: number, options?: ChannelOptions | undefined | null"
+  )]
+  pub fn from_raw(
+    env: Env,
+    stdin_fd: i64,
+    stdout_fd: i64,
+    options: Option<ChannelOptions>,
+  ) -> Result<Self> {
+    if stdin_fd == stdout_fd {
+      return Err(Error::from_reason(
+        "`stdinFd` and `stdoutFd` must be different descriptors",
+      ));
+    }
+    let (stdin, stdout) = raw_files(stdin_fd, stdout_fd)?;
+    let options = options.unwrap_or_default();
+    Self::connect(
+      &env,
+      String::new(),
+      Vec::new(),
+      options,
+      Some((stdin, stdout)),
+    )
+  }
+
+  // Helper function for the constructors, to check the options that don't
+  // concern spawning, then connect to the child: either one spawned from
+  // `exe` and `args`, or the one on the other end of `raw`.
+  fn connect(
+    env: &Env,
+    exe: String,
+    args: Vec<String>,
+    mut options: ChannelOptions,
+    raw: Option<(File, File)>,
+  ) -> Result<Self> {
+    if options.read_buffer_size == Some(0) {
+      return Err(Error::from_reason(
+        "invalid `readBufferSize` option: must be greater than 0",
+      ));
+    }
+    let handshake_mode = match options.handshake.as_deref() {
+      None => None,
+      Some("required") => Some(HandshakeMode::Required),
+      Some("optional") => Some(HandshakeMode::Optional),
+      Some(s) => {
+        return Err(Error::from_reason(format!(
+          "invalid `handshake` option `{s}`: expected \"required\" or \"optional\""
+        )))
+      }
+    };
+    let compression_threshold = match options.compression.as_deref() {
+      None => None,
+      Some(COMPRESSION_ZSTD) if cfg!(feature = "zstd") => Some(
+        options
+          .compression_threshold
+          .map_or(DEFAULT_COMPRESSION_THRESHOLD, |threshold| {
+            threshold as usize
+          }),
+      ),
+      Some(COMPRESSION_ZSTD) => {
+        return Err(Error::from_reason(format!(
+          "`compression` option \"{COMPRESSION_ZSTD}\" is not supported: libsyncrpc was built without its `zstd` feature"
+        )))
+      }
+      Some(s) => {
+        return Err(Error::from_reason(format!(
+          "invalid `compression` option `{s}`: expected \"{COMPRESSION_ZSTD}\""
+        )))
+      }
+    };
+    let checksum_requested = match options.checksum.as_deref() {
+      None => false,
+      Some(CHECKSUM_CRC32) => true,
+      Some(s) => {
+        return Err(Error::from_reason(format!(
+          "invalid `checksum` option `{s}`: expected \"{CHECKSUM_CRC32}\""
+        )))
+      }
+    };
+    let stderr = StderrSink::from_option(options.stderr.take())?;
+    let fatal_stderr = options
+      .stderr_is_fatal
+      .unwrap_or(false)
+      .then(|| Arc::new(FatalStderr::default()));
+    if fatal_stderr.is_some() && matches!(stderr, StderrSink::Ignore) {
+      return Err(Error::from_reason(
+        "the `stderrIsFatal` option can't be combined with `stderr: \"ignore\"`",
+      ));
+    }
+    let log = options.log.take().map(Arc::new);
+    if options.trace_file.is_none() {
+      options.trace_file = std::env::var(TRACE_FILE_VAR).ok();
+    }
+    let tracer = options
+      .trace_file
+      .as_deref()
+      .map(|path| open_tracer(path, &options))
+      .transpose()?;
+    let spec = ChildSpec {
+      exe,
+      args,
+      options,
+      stderr,
+      fatal_stderr,
+    };
+    let (child, conn) = match raw {
+      None => spec.spawn(),
+      Some((stdin, stdout)) => spec.attach(stdin, stdout),
+    }
+    .map_err(|e| wire_error(env, e))?;
+    let child = Arc::new(Mutex::new(child));
+    let metrics = Arc::new(Metrics::default());
+    let cancel_requested = Arc::new(AtomicBool::new(false));
+    let wire = Wire {
+      child: child.clone(),
+      conn,
+      metrics: metrics.clone(),
+      tracer,
+      log,
+      notify: None,
+      fatal_stderr: spec.fatal_stderr.clone(),
+      poisoned: None,
+      abandoned: None,
+      unanswered_pings: 0,
+      busy: false,
+      next_request_id: 0,
+      request_ids: spec.options.request_ids.unwrap_or(false),
+      strict_response_names: spec.options.strict_response_names.unwrap_or(true),
+      current_request_id: None,
+      callback_payload_limit: None,
+      cancel_requested: cancel_requested.clone(),
+      read_bufs: MessageBuffers::default(),
+    };
+    let mut channel = Self {
+      spec,
+      child,
+      wire: Arc::new(Mutex::new(wire)),
+      callbacks: HashMap::new(),
+      default_callback: None,
+      observer: None,
+      metrics,
+      cancel_requested,
+      idle: None,
+      default_timeout: None,
+      handshake_mode,
+      protocol_version: None,
+      compression_threshold,
+      compressed: false,
+      checksum_requested,
+      checksummed: false,
+      restarts: 0,
+      closed: false,
+    };
+    let wire = channel.wire.clone();
+    let res = try_lock_wire(&wire)
+      .and_then(|mut wire| channel.start(&mut wire).map_err(|e| wire_error(env, e)));
+    if let Err(e) = res {
+      let _ = channel.close();
+      return Err(e);
+    }
+    Ok(channel)
+  }
+
+  /// Send a request to the child process and wait for a response. The method
+  /// will not return, synchronously, until a response is received or an error
+  /// occurs.
+  ///
+  /// This method will take care of encoding and decoding the binary payload to
+  /// and from a JS string automatically and suitable for smaller payloads.
+  ///
+  /// If the connection to the child breaks, the thrown error's `code` says
+  /// how: `ERR_FRAMING` for a malformed message, `ERR_PAYLOAD_TOO_LARGE` for
+  /// one over `ChannelOptions.maxPayloadLength`, `ERR_CHECKSUM` for a corrupted
+  /// one, `ERR_NAME_MISMATCH` for a response to something else,
+  /// `ERR_CHILD_DISCONNECTED` if the child closed the connection in the middle
+  /// of a message, and `ERR_IO` if reading or writing failed.
+  ///
+  /// Errors other than structured ones reported by the child (see
+  /// `MessageType.Error`) say how long the request took to fail, as in
+  /// "request `compile` failed after 4213ms: ...".
+  #[napi]
+  pub fn request_sync(&mut self, env: Env, method: String, payload: String) -> Result<String> {
+    self
+      .request_bytes_sync(env, method, payload.as_bytes(), RequestOptions::default())
+      .and_then(response_to_string)
+  }
+
+  /// Like `requestSync`, but throws if the request, including any callbacks
+  /// it invokes, does not complete within `timeoutMs` milliseconds.
+  ///
+  /// This includes sending the request, so a child that stopped reading its
+  /// stdin can't block the channel forever either, except on Windows, where
+  /// sending blocks until the child has read the request.
+  ///
+  /// Because the child may still send its response after the deadline, a
+  /// timed-out channel cannot tell a late response apart from the next one.
+  /// The channel is therefore poisoned after a timeout: all subsequent
+  /// requests will throw immediately, and it should be closed and replaced,
+  /// or waited out with `resync`.
+  /// With `ChannelOptions.requestIds` set, the late response can be told apart,
+  /// so the channel stays usable, unless the timeout hit while only part of a
+  /// message had arrived.
+  #[napi]
+  pub fn request_sync_timeout(
+    &mut self,
+    env: Env,
+    method: String,
+    payload: String,
+    timeout_ms: u32,
+  ) -> Result<String> {
+    let opts = RequestOptions {
+      timeout: Some(Duration::from_millis(timeout_ms.into())),
+      ..Default::default()
+    };
+    self
+      .request_bytes_sync(env, method, payload.as_bytes(), opts)
+      .and_then(response_to_string)
+  }
+
+  /// Like `requestSync`, but also tells the child that it has `deadlineMs`
+  /// milliseconds to respond, so that a cooperating child can abandon the
+  /// work itself (typically responding with a `MessageType.Error`) rather than
+  /// having the channel give up on it. See `MessageType.Request` for how the
+  /// deadline is sent.
+  ///
+  /// As a backstop, the request times out as with `requestSyncTimeout` if the
+  /// child has not responded a short grace period after the deadline.
+  #[napi]
+  pub fn request_sync_with_deadline(
+    &mut self,
+    env: Env,
+    method: String,
+    payload: String,
+    deadline_ms: u32,
+  ) -> Result<String> {
+    let opts = RequestOptions {
+      timeout: Some(Duration::from_millis(deadline_ms.into()) + DEADLINE_GRACE_PERIOD),
+      child_deadline_ms: Some(deadline_ms),
+      ..Default::default()
+    };
+    self
+      .request_bytes_sync(env, method, payload.as_bytes(), opts)
+      .and_then(response_to_string)
+  }
+
+  /// Like `requestSync`, but only lets the child invoke the callbacks named
+  /// in `allowedCallbacks` during this request. Invoking any other callback,
+  /// registered or not, sends the child a `MessageType.CallError` without
+  /// calling anything on the JavaScript side, including the default callback
+  /// (see `registerDefaultCallback`).
+  #[napi]
+  pub fn request_sync_scoped(
+    &mut self,
+    env: Env,
+    method: String,
+    payload: String,
+    allowed_callbacks: Vec<String>,
+  ) -> Result<String> {
+    let opts = RequestOptions {
+      allowed_callbacks: Some(&allowed_callbacks),
+      ..Default::default()
+    };
+    self
+      .request_bytes_sync(env, method, payload.as_bytes(), opts)
+      .and_then(response_to_string)
+  }
+
+  /// Like `requestSync`, but an error reported by the child is returned as
+  /// `{ ok: false, error }` instead of being thrown, which is cheaper for
+  /// requests where such errors are routine. A successful response is
+  /// returned as `{ ok: true, value }`.
+  ///
+  /// Failures of the channel itself, such as a framing error or the child
+  /// exiting, are still thrown.
+  #[napi]
+  pub fn try_request_sync(
+    &mut self,
+    env: Env,
+    method: String,
+    payload: String,
+  ) -> Result<TryRequestResult> {
+    Ok(
+      match self.try_request_bytes_sync(
+        env,
+        &method,
+        payload.as_bytes(),
+        RequestOptions::default(),
+      )? {
+        Ok(value) => TryRequestResult {
+          ok: true,
+          value: Some(response_to_string(value)?),
+          error: None,
+        },
+        Err(error) => TryRequestResult {
+          ok: false,
+          value: None,
+          error: Some(error),
+        },
+      },
+    )
+  }
+
+  /// Send a request to the child process and wait for a response. The method
+  /// will not return, synchronously, until a response is received or an error
+  /// occurs.
+  ///
+  /// Unlike `requestSync`, this method will not do any of its own encoding or
+  /// decoding of payload data. Everything will be as sent/received through the
+  /// underlying protocol.
+  ///
+  /// Neither direction copies the payload on the native side: the request is
+  /// written straight out of the given array, and the returned array takes
+  /// ownership of the received bytes. Runtimes that disallow external array
+  /// buffers (such as Electron's V8 sandbox) will still copy the response.
+  #[napi]
+  pub fn request_binary_sync(
+    &mut self,
+    env: Env,
+    method: String,
+    payload: Uint8Array,
+  ) -> Result<Uint8Array> {
+    self
+      .request_bytes_sync(env, method, &payload, RequestOptions::default())
+      .map(Uint8Array::from)
+  }
+
+  /// Like `requestBinarySync`, but takes and returns a Node.js `Buffer`. The
+  /// response is no more copied than there: the returned `Buffer` is created
+  /// over the bytes read from the child, which are freed once it is garbage
+  /// collected.
+  #[napi]
+  pub fn request_buffer_sync(
+    &mut self,
+    env: Env,
+    method: String,
+    payload: Buffer,
+  ) -> Result<Buffer> {
+    self
+      .request_bytes_sync(env, method, &payload, RequestOptions::default())
+      .map(Buffer::from)
+  }
+
+  /// Like `requestBinarySync`, but for responses that may be either text or
+  /// binary: a response that is valid UTF-8 is returned as a string, with
+  /// `type: "text"`, and any other as a `Uint8Array`, with `type: "binary"`.
+  /// Neither is decoded lossily, and an invalid UTF-8 response doesn't throw
+  /// as it would with `requestSync`.
+  ///
+  /// Note that binary responses that happen to be valid UTF-8, such as empty
+  /// ones, are returned as text.
+  #[napi]
+  pub fn request_auto_sync(
+    &mut self,
+    env: Env,
+    method: String,
+    payload: Uint8Array,
+  ) -> Result<AutoResponse> {
+    let res = self.request_bytes_sync(env, method, &payload, RequestOptions::default())?;
+    Ok(match String::from_utf8(res) {
+      Ok(text) => AutoResponse {
+        kind: "text".into(),
+        value: Either::A(text),
+      },
+      Err(e) => AutoResponse {
+        kind: "binary".into(),
+        value: Either::B(e.into_bytes().into()),
+      },
+    })
+  }
+
+  /// Sends all of `requests` to the child before waiting for any of their
+  /// responses, to save a round trip per request when making several
+  /// independent requests. Payloads are passed as is, as with
+  /// `requestBinarySync`.
+  ///
+  /// Results are returned in the order of `requests`, whatever order the
+  /// child responds in. As with `tryRequestSync`, an error reported by the
+  /// child for a request is returned as `{ ok: false, error }` rather than
+  /// thrown, while failures of the channel itself are thrown.
+  ///
+  /// Each request carries an `<id>` that the child must echo in its
+  /// response (see `MessageType.Request`). Callbacks invoked by the child
+  /// while it works on the batch are handled as usual. Since every request is
+  /// written before any response is read, the child must keep reading
+  /// requests while it responds to earlier ones, or a large enough batch can
+  /// deadlock once the pipes between them fill up.
+  #[napi]
+  pub fn request_batch_sync(
+    &mut self,
+    env: Env,
+    requests: Vec<BatchRequest>,
+  ) -> Result<Vec<BatchResult>> {
+    let requests: Vec<_> = requests
+      .iter()
+      .map(|req| (req.method.as_str(), &req.payload[..]))
+      .collect();
+    for (method, payload) in &requests {
+      self.observe(&env, "request", method, payload.len());
+    }
+    let results = self
+      .with_activity(|this, wire| {
+        wire.request_batch(&requests, &mut |name, payload| {
+          this.call_sync(&env, name, payload)
+        })
+      })
+      .map_err(|e| wire_error(&env, e));
+    for (i, (method, _)) in requests.iter().enumerate() {
+      self.observe_result(&env, method, results.as_ref().map(|results| &results[i]));
+    }
+    let results = results?;
+    Ok(
+      results
+        .into_iter()
+        .map(|res| match res {
+          Ok(value) => BatchResult {
+            ok: true,
+            value: Some(value.into()),
+            error: None,
+          },
+          Err(error) => BatchResult {
+            ok: false,
+            value: None,
+            error: Some(error),
+          },
+        })
+        .collect(),
+    )
+  }
+
+  /// Like `requestBinarySync`, but lets the child stream its response as any
+  /// number of `MessageType.ResponseChunk` messages before the final
+  /// `MessageType.Response`, to avoid buffering very large responses in full.
+  ///
+  /// `onChunk` is called with the `<payload>` of each chunk, synchronously
+  /// and in the order the child sent them, before this method returns the
+  /// `<payload>` of the final response (which is often empty). Callbacks
+  /// invoked by the child may be interleaved with the chunks.
+  ///
+  /// If `onChunk` throws, the remaining chunks are discarded and the error is
+  /// thrown once the child has finished responding.
+  #[napi(
+    ts_args_type = "method: string, payload: Uint8Array, onChunk: (chunk: Uint8Array) => void"
+  )]
+  pub fn request_stream_sync(
+    &mut self,
+    env: Env,
+    method: String,
+    payload: Uint8Array,
+    on_chunk: ChunkCallback,
+  ) -> Result<Uint8Array> {
+    let opts = RequestOptions {
+      on_chunk: Some(&on_chunk),
+      ..Default::default()
+    };
+    self
+      .request_bytes_sync(env, method, &payload, opts)
+      .map(Uint8Array::from)
+  }
+
+  /// Like `requestSync`, but takes care of JSON encoding the `payload` and
+  /// decoding the response, for children that speak JSON.
+  ///
+  /// Throws if the response is not valid JSON, including the start of the
+  /// offending response in the error message.
+  #[napi]
+  pub fn request_json(
+    &mut self,
+    env: Env,
+    method: String,
+    payload: serde_json::Value,
+  ) -> Result<serde_json::Value> {
+    let payload = serde_json::to_vec(&payload)
+      .map_err(|e| Error::from_reason(format!("Error while encoding request as JSON: {e}")))?;
+    let res = self.request_bytes_sync(env, method, &payload, RequestOptions::default())?;
+    serde_json::from_slice(&res).map_err(|e| {
+      Error::from_reason(format!(
+        "Error while decoding response as JSON: {e} (response starts with `{}`)",
+        json_snippet(&res)
+      ))
+    })
+  }
+
+  /// Like `requestJson`, but encodes the `payload` and decodes the response as
+  /// CBOR (RFC 8949), which is usually smaller and faster to parse for deeply
+  /// nested data. Integers are sent as CBOR integers, other numbers as 64-bit
+  /// floats, and objects as maps with string keys.
+  ///
+  /// In the response, byte strings are decoded as arrays of numbers, tags are
+  /// ignored, and `undefined` and non-finite floats are decoded as `null`.
+  /// Map keys must be strings, and indefinite-length items are not
+  /// supported. Throws if the response is not valid CBOR, including the byte
+  /// offset of the failure in the error message.
+  #[napi]
+  pub fn request_cbor(
+    &mut self,
+    env: Env,
+    method: String,
+    payload: serde_json::Value,
+  ) -> Result<serde_json::Value> {
+    let payload = cbor::encode(&payload);
+    let res = self.request_bytes_sync(env, method, &payload, RequestOptions::default())?;
+    cbor::decode(&res)
+      .map_err(|e| Error::from_reason(format!("Error while decoding response as CBOR: {e}")))
+  }
+
+  /// Sends a request with an empty payload to the child process and waits for
+  /// it to acknowledge it with an empty response, for requests that are only
+  /// made for their side effects.
+  ///
+  /// Throws if the child responds with an error, or with a non-empty payload.
+  #[napi]
+  pub fn request_ack_sync(&mut self, env: Env, method: String) -> Result<()> {
+    let res = self.request_bytes_sync(env, method.clone(), b"", RequestOptions::default())?;
+    if !res.is_empty() {
+      return Err(Error::from_reason(format!(
+        "expected an empty response to `{method}`, got {} bytes",
+        res.len()
+      )));
+    }
+    Ok(())
+  }
+
+  /// Like `requestSync`, but makes the request from a worker thread and
+  /// returns a promise for the response, leaving the JavaScript thread free
+  /// while the child works. Only during these requests can the child invoke
+  /// callbacks registered with `registerAsyncCallback`; it is sent a
+  /// `MessageType.CallError` if it invokes any other kind of callback.
+  ///
+  /// The channel can only do one thing at a time: until the promise settles,
+  /// its other methods that talk to the child (including `requestAsync`
+  /// itself) throw instead of waiting for it. Each request in progress also
+  /// occupies a thread of libuv's thread pool.
+  #[napi]
+  pub fn request_async(
+    &mut self,
+    env: Env,
+    method: String,
+    payload: String,
+  ) -> Result<AsyncTask<AsyncRequest>> {
+    let wire = self.wire.clone();
+    let mut guard = try_lock_wire(&wire)?;
+    if let Err(e) = self.begin_activity(&mut guard) {
+      self.end_activity();
+      return Err(wire_error(&env, e));
+    }
+    guard.busy = true;
+    drop(guard);
+    let callbacks = self
+      .callbacks
+      .iter()
+      .map(|(name, cb)| {
+        let cb = match cb {
+          RegisteredCallback::Async(cb) => Some(cb.clone()),
+          _ => None,
+        };
+        (name.clone(), cb)
+      })
+      .collect();
+    // The activity ends, and the wire is released, once the request is done.
+    Ok(AsyncTask::new(AsyncRequest {
+      wire,
+      method,
+      payload: payload.into_bytes(),
+      callbacks,
+      idle: self.idle.clone(),
+      elapsed: Duration::ZERO,
+    }))
+  }
+
+  fn request_bytes_sync(
+    &mut self,
+    env: Env,
+    method: String,
+    payload: &[u8],
+    opts: RequestOptions<'_>,
+  ) -> Result<Vec<u8>> {
+    let start = Instant::now();
+    self
+      .try_request_bytes_sync(env, &method, payload, opts)?
+      .map_err(|message| remote_error(&env, &method, message, start.elapsed()))
+  }
+
+  // Like `request_bytes_sync`, but returns errors reported by the child as
+  // `Ok(Err(message))`, reserving `Err` for failures of the channel itself.
+  fn try_request_bytes_sync(
+    &mut self,
+    env: Env,
+    method: &str,
+    payload: &[u8],
+    opts: RequestOptions<'_>,
+  ) -> Result<RemoteResult> {
+    let start = Instant::now();
+    let opts = RequestOptions {
+      timeout: opts.timeout.or(self.default_timeout),
+      ..opts
+    };
+    self.observe(&env, "request", method, payload.len());
+    let res = self.with_activity(|this, wire| {
+      let res = wire.request(method, payload, opts, &mut |name, payload| {
+        this.call_sync(&env, name, payload)
+      });
+      if res.is_ok() || !this.restart_exited_child(wire)? {
+        return res;
+      }
+      wire.request(method, payload, opts, &mut |name, payload| {
+        this.call_sync(&env, name, payload)
+      })
+    });
+    let res = res.map_err(|e| request_error(&env, method, e, start.elapsed()));
+    self.observe_result(&env, method, res.as_ref());
+    res
+  }
+
+  /// Returns a handle that can cancel the channel's requests while they are
+  /// in progress, see `CancellationHandle#cancel`. The channel itself can't
+  /// be used from within a callback, but the handle can.
+  #[napi]
+  pub fn cancellation_handle(&self) -> CancellationHandle {
+    CancellationHandle {
+      requested: self.cancel_requested.clone(),
+    }
+  }
+
+  /// Registers a JavaScript callback that the child can invoke before
+  /// completing a request. The callback will receive a string name and a string
+  /// payload as its arguments and should return a string as its result.
+  ///
+  /// See `registerBinaryCallback` for a `Uint8Array`-only equivalent to this
+  /// functionality. Registering a callback replaces any callback of either
+  /// kind previously registered under the same name.
+  ///
+  /// If the callback throws, an it will be handled appropriately by
+  /// `requestSync` and the child will be notified. The same goes for a
+  /// callback that returns anything but a string, which fails with an error
+  /// naming the type of what it returned.
+  #[napi(ts_args_type = "name: string, callback: (name: string, payload: string) => string")]
+  pub fn register_callback(&mut self, name: String, cb: Callback) -> Result<()> {
+    self
+      .callbacks
+      .insert(name, RegisteredCallback::String(cb.create_ref()?));
+    Ok(())
+  }
+
+  /// Registers a JavaScript callback that the child can invoke before
+  /// completing a request. Unlike `registerCallback`, the payload is passed
+  /// to the callback as a `Uint8Array` without any decoding, so it need not be
+  /// valid UTF-8, and the callback must return a `Uint8Array`.
+  ///
+  /// Registering a callback replaces any callback of either kind previously
+  /// registered under the same name.
+  #[napi(
+    ts_args_type = "name: string, callback: (name: string, payload: Uint8Array) => Uint8Array"
+  )]
+  pub fn register_binary_callback(&mut self, name: String, cb: BinaryCallback) -> Result<()> {
+    self
+      .callbacks
+      .insert(name, RegisteredCallback::Binary(cb.create_ref()?));
+    Ok(())
+  }
+
+  /// Registers a JavaScript callback that the child can invoke before
+  /// completing a request, with a payload made of two fields: a header (such
+  /// as a request ID) and a body. The child separates them with the first tab
+  /// character in the payload, so the header cannot contain tabs but the body
+  /// can. The callback receives the name, header and body as strings, and
+  /// should return a string.
+  ///
+  /// If the payload has no tab, the child is sent a `MessageType.CallError`
+  /// without invoking the callback.
+  ///
+  /// Registering a callback replaces any callback of either kind previously
+  /// registered under the same name.
+  #[napi(
+    ts_args_type = "name: string, callback: (name: string, header: string, body: string) => string"
+  )]
+  pub fn register_header_callback(&mut self, name: String, cb: HeaderCallback) -> Result<()> {
+    self
+      .callbacks
+      .insert(name, RegisteredCallback::Header(cb.create_ref()?));
+    Ok(())
+  }
+
+  /// Registers a JavaScript callback that the child can invoke during a
+  /// request made with `requestAsync`. Like `registerCallback`, the callback
+  /// receives a string name and a string payload, but it may return either a
+  /// string or a promise for one, which is awaited before the child is sent
+  /// the result.
+  ///
+  /// #### Threading model
+  ///
+  /// The callback always runs on the JavaScript thread. While the child
+  /// works on an asynchronous request, the worker thread making it sends each
+  /// call over to the JavaScript thread and blocks until the callback's
+  /// promise settles. This is why the callback can only be invoked during
+  /// `requestAsync`: a synchronous request blocks the JavaScript thread
+  /// itself, so the callback could never run. The child invoking it during a
+  /// synchronous request is sent a `MessageType.CallError`.
+  ///
+  /// The request cannot complete until the callback's promise settles, so
+  /// the promise must not wait on the request (directly or otherwise), or on
+  /// anything else the channel is busy with: that deadlocks the request. It
+  /// also must not block the JavaScript thread waiting for the worker, which
+  /// is why the channel's methods throw, rather than wait, while it is busy.
+  ///
+  /// Registering a callback replaces any callback of either kind previously
+  /// registered under the same name.
+  #[napi(
+    ts_args_type = "name: string, callback: (name: string, payload: string) => string | Promise<string>"
+  )]
+  pub fn register_async_callback(&mut self, name: String, cb: AsyncCallback) {
+    self
+      .callbacks
+      .insert(name, RegisteredCallback::Async(Arc::new(cb)));
+  }
+
+  /// Registers a JavaScript callback that is invoked in place of any callback
+  /// the child invokes during a synchronous request that isn't registered
+  /// under its name, such as to proxy calls elsewhere or to handle callbacks
+  /// whose names are only known at runtime. Like `registerCallback`, it
+  /// receives the name and payload as strings.
+  ///
+  /// The callback can decline the call by returning `null` or `undefined`,
+  /// in which case the child is sent the same `MessageType.CallError` as for
+  /// an unknown callback without a default. Throwing sends the child a
+  /// `MessageType.CallError` with the error message, as with any callback.
+  ///
+  /// Callbacks registered under a name always take precedence, even
+  /// asynchronous ones. Registering a default callback replaces the previous
+  /// one, if any.
+  #[napi(ts_args_type = "callback: (name: string, payload: string) => string | null | undefined")]
+  pub fn register_default_callback(&mut self, cb: DefaultCallback) -> Result<()> {
+    self.default_callback = Some(cb.create_ref()?);
+    Ok(())
+  }
+
+  /// Removes the callback registered with `registerDefaultCallback`, if any.
+  /// Returns whether a callback was removed.
+  #[napi]
+  pub fn unregister_default_callback(&mut self) -> bool {
+    self.default_callback.take().is_some()
+  }
+
+  /// Removes the callback registered under `name`, if any, so it can be
+  /// garbage collected. Returns whether a callback was removed.
+  ///
+  /// The child invoking a removed callback is handled like any other unknown
+  /// callback: the child is sent a `MessageType.CallError`.
+  #[napi]
+  pub fn unregister_callback(&mut self, name: String) -> bool {
+    // Dropping the `FunctionRef` releases its reference to the function.
+    self.callbacks.remove(&name).is_some()
+  }
+
+  /// Removes all registered callbacks, as with `unregisterCallback`,
+  /// including the default callback.
+  #[napi]
+  pub fn clear_callbacks(&mut self) {
+    self.callbacks.clear();
+    self.default_callback = None;
+  }
+
+  /// Returns the names of the callbacks currently registered, of any kind,
+  /// in sorted order, for checking which callbacks the child can invoke.
+  /// The default callback (see `registerDefaultCallback`) has no name, and
+  /// isn't included.
+  #[napi]
+  pub fn registered_callbacks(&self) -> Vec<String> {
+    let mut names: Vec<_> = self.callbacks.keys().cloned().collect();
+    names.sort_unstable();
+    names
+  }
+
+  /// Sets a function to be called for every synchronous request the channel
+  /// makes and every callback the child invokes during one, for auditing or
+  /// logging individual calls rather than the aggregates of `stats`, or
+  /// removes it if `null`.
+  ///
+  /// The observer is called with an `event`, the name of the method or
+  /// callback involved, and the size in bytes of the payload:
+  /// - `"request"` before a request is sent, with the request's payload.
+  /// - `"response"` once the child has responded, with the response's payload.
+  /// - `"error"` instead of `"response"` if the request failed, with the
+  ///   size of the error reported by the child, or 0 for failures of the
+  ///   channel itself.
+  /// - `"callback"` before a callback is invoked, with the call's payload.
+  ///
+  /// Since synchronous requests block the JavaScript thread, the observer is
+  /// called inline, on that same thread, and holds up the request while it
+  /// runs. An observer that throws has its error discarded: it can't fail
+  /// the request or affect the child. Requests made with `requestAsync` are
+  /// not observed.
+  #[napi(
+    ts_args_type = "observer: ((event: 'request' | 'response' | 'error' | 'callback', name: string, bytes: number) => void) | null | undefined"
+  )]
+  pub fn set_observer(&mut self, observer: Option<ObserverCallback>) -> Result<()> {
+    self.observer = observer.map(|cb| cb.create_ref()).transpose()?;
+    Ok(())
+  }
+
+  /// Returns a snapshot of the channel's counters: the number of messages of
+  /// each `MessageType` that have crossed the wire in either direction, and
+  /// totals of requests, response bytes, callback invocations and errors,
+  /// since the channel was created or `resetStats` was last called.
+  #[napi]
+  pub fn stats(&self) -> ChannelStats {
+    let total = |count: &AtomicI64| count.load(Ordering::Relaxed);
+    ChannelStats {
+      messages_sent: Metrics::snapshot(&self.metrics.sent),
+      messages_received: Metrics::snapshot(&self.metrics.received),
+      requests: total(&self.metrics.requests),
+      response_bytes: total(&self.metrics.response_bytes),
+      callbacks: total(&self.metrics.callbacks),
+      errors: total(&self.metrics.errors),
+    }
+  }
+
+  /// Sets all of the counters returned by `stats` back to zero, e.g. to
+  /// sample them over fixed intervals.
+  #[napi]
+  pub fn reset_stats(&self) {
+    self.metrics.reset();
+  }
+
+  /// Blocks until the child creates a file at `path`, for children that
+  /// signal their readiness that way rather than by being able to respond to
+  /// requests straight away. Throws if the file does not appear within
+  /// `timeoutMs` milliseconds, or if the child exits first.
+  #[napi]
+  pub fn wait_ready_file(&mut self, path: String, timeout_ms: u32) -> Result<()> {
+    let deadline = Instant::now() + Duration::from_millis(timeout_ms.into());
+    loop {
+      if Path::new(&path).exists() {
+        return Ok(());
+      }
+      if let Some(status) = self.child().try_wait()? {
+        return Err(Error::from_reason(format!(
+          "child process exited before creating readiness file `{path}` ({status})"
+        )));
+      }
+      if Instant::now() >= deadline {
+        return Err(Error::from_reason(format!(
+          "child process did not create readiness file `{path}` within {timeout_ms}ms"
+        )));
+      }
+      std::thread::sleep(READY_FILE_POLL_INTERVAL);
+    }
+  }
+
+  /// Returns whether the child process is still running. This is cheap, but
+  /// does not tell whether the child is responsive; see `ping` for that.
+  ///
+  /// Returns `false` after the child has been reaped by the idle timeout (see
+  /// `setIdleTimeout`), even though the next request will respawn it.
+  #[napi]
+  pub fn is_alive(&self) -> Result<bool> {
+    self.check_spawned("isAlive")?;
+    Ok(self.child().try_wait()?.is_none())
+  }
+
+  /// Checks that the child is responsive by sending it a no-op
+  /// `MessageType.Request` with the reserved `$/ping` method name and an empty
+  /// payload, to which it must respond with an empty `MessageType.Response`.
+  /// Returns whether the response arrived within `timeoutMs` milliseconds.
+  ///
+  /// Unlike a timed out request, a timed out ping does not poison the
+  /// channel: its response is skipped if it arrives later.
+  #[napi]
+  pub fn ping(&mut self, env: Env, timeout_ms: u32) -> Result<bool> {
+    self
+      .with_activity(|_, wire| {
+        if wire.poisoned.is_some() {
+          return Ok(false);
+        }
+        let deadline = Instant::now() + Duration::from_millis(timeout_ms.into());
+        wire.set_deadline(Some(deadline));
+        let res = Self::run_ping(wire);
+        wire.set_deadline(None);
+        res
+      })
+      .map_err(|e| wire_error(&env, e))
+  }
+
+  fn run_ping(wire: &mut Wire) -> WireResult<bool> {
+    match wire.write(MessageType::Request, PING_METHOD.as_bytes(), b"") {
+      // Let the read below find out whether the child is gone.
+      Err(RpcError::Io(e)) if e.kind() == io::ErrorKind::BrokenPipe => {}
+      res => res?,
+    }
+    loop {
+      let (ty, _) = match wire.read() {
+        Err(RpcError::Io(e)) if e.kind() == io::ErrorKind::TimedOut => {
+          wire.unanswered_pings += 1;
+          return Ok(false);
+        }
+        Ok(None) => return Ok(false),
+        msg => msg?.expect("EOF was handled above"),
+      };
+      let name = wire.received_name();
+      if ty != MessageType::Response as u8 || name != PING_METHOD.as_bytes() {
+        let name = String::from_utf8_lossy(name);
+        return Err(
+          Error::from_reason(format!(
+            "unexpected message in response to ping: ({ty}) `{name}`"
+          ))
+          .into(),
+        );
+      }
+      if wire.unanswered_pings == 0 {
+        return Ok(true);
+      }
+      wire.unanswered_pings -= 1;
+    }
+  }
+
+  /// Sets the function that notifications from the child (see
+  /// `MessageType.Notify`) are passed to, with their name and payload, or
+  /// removes it if `null`. Without one, notifications are discarded.
+  ///
+  /// Like `ChannelOptions.log`, the handler is called asynchronously through
+  /// the event loop, after the request (or `pumpNotifications` call) that
+  /// received the notification returns. Since the channel only reads from
+  /// the child while it is busy, notifications the child sends between
+  /// requests wait until the next request, unless `pumpNotifications` is
+  /// called to pick them up.
+  #[napi(ts_args_type = "handler: ((name: string, payload: string) => void) | null | undefined")]
+  pub fn on_notify(&mut self, handler: Option<NotifyCallback>) -> Result<()> {
+    try_lock_wire(&self.wire)?.notify = handler.map(Arc::new);
+    Ok(())
+  }
+
+  /// Waits up to `timeoutMs` milliseconds for notifications from the child
+  /// while no request is in progress, passing each to the handler set with
+  /// `onNotify`. Returns the number of notifications received.
+  ///
+  /// A `timeoutMs` of 0 doesn't even pick up notifications that have
+  /// already arrived, so it should leave the child some time to send them.
+  /// Throws if the child sends anything other than notifications and
+  /// `MessageType.Log` messages, or if the channel is poisoned.
+  #[napi]
+  pub fn pump_notifications(&mut self, env: Env, timeout_ms: u32) -> Result<u32> {
+    self
+      .with_activity(|_, wire| {
+        let metrics = wire.metrics.clone();
+        let notifications =
+          || metrics.received[MessageType::Notify as usize].load(Ordering::Relaxed);
+        let before = notifications();
+        let deadline = Instant::now() + Duration::from_millis(timeout_ms.into());
+        wire.set_deadline(Some(deadline));
+        let res = wire.pump_notifications();
+        wire.set_deadline(None);
+        res?;
+        Ok((notifications() - before) as u32)
+      })
+      .map_err(|e| wire_error(&env, e))
+  }
+
+  /// Tries to make a channel poisoned by a timeout (see
+  /// `requestSyncTimeout`) usable again, by waiting up to `timeoutMs`
+  /// milliseconds for the child to finish the request that timed out. Its
+  /// late response, along with anything else the child sends for that
+  /// request, is read and discarded, and any callback it invokes is answered
+  /// with a `MessageType.CallError`. Returns `true` once the channel is usable
+  /// again, including when it wasn't poisoned, or `false` if the child is
+  /// still working on the request, in which case `resync` can be called
+  /// again later.
+  ///
+  /// This is best-effort: messages are length-prefixed rather than
+  /// delimited, so there is no way to find the start of the next message in
+  /// a stream whose position was lost. A channel poisoned by anything else,
+  /// such as a framing error, a checksum mismatch, a timeout in the middle of
+  /// a message or a timeout while sending, can't be resynchronized, and this
+  /// throws: close it, or `respawn` the child, instead. The same goes for a
+  /// child that never finishes the request, or that keeps state that the
+  /// abandoned request may have left inconsistent.
+  #[napi]
+  pub fn resync(&mut self, env: Env, timeout_ms: u32) -> Result<bool> {
+    self
+      .with_activity(|_, wire| {
+        let deadline = Instant::now() + Duration::from_millis(timeout_ms.into());
+        wire.set_deadline(Some(deadline));
+        let res = wire.resync();
+        wire.set_deadline(None);
+        res
+      })
+      .map_err(|e| wire_error(&env, e))
+  }
+
+  /// Returns the child's exit code once it has exited, or `null` if it is
+  /// still running or was terminated by a signal (see `signal`).
+  #[napi]
+  pub fn exit_status(&self) -> Result<Option<i32>> {
+    self.check_spawned("exitStatus")?;
+    Ok(self.child().try_wait()?.and_then(|status| status.code()))
+  }
+
+  /// Returns the number of the signal that terminated the child, or `null` if
+  /// it is still running or exited on its own. A child terminated by `close()`
+  /// reports `SIGKILL`. Always returns `null` on Windows.
+  #[napi]
+  pub fn signal(&self) -> Result<Option<i32>> {
+    self.check_spawned("signal")?;
+    let status = self.child().try_wait()?;
+    #[cfg(unix)]
+    {
+      use std::os::unix::process::ExitStatusExt;
+      Ok(status.and_then(|status| status.signal()))
+    }
+    #[cfg(not(unix))]
+    {
+      let _ = status;
+      Ok(None)
+    }
+  }
+
+  /// Returns the protocol version negotiated with the child during the
+  /// handshake (see `ChannelOptions.handshake`): the lower of the versions
+  /// spoken by the channel and the child. Returns `null` if no handshake was
+  /// performed, or if the child is running in legacy mode.
+  #[napi]
+  pub fn protocol_version(&self) -> Option<u32> {
+    self.protocol_version
+  }
+
+  /// Returns the payload compression codec negotiated with the child (see
+  /// `ChannelOptions.compression`), or `null` if payloads are not compressed.
+  #[napi]
+  pub fn compression(&self) -> Option<String> {
+    self.compressed.then(|| COMPRESSION_ZSTD.into())
+  }
+
+  /// Returns the checksum algorithm negotiated with the child (see
+  /// `ChannelOptions.checksum`), or `null` if messages have no checksum.
+  #[napi]
+  pub fn checksum(&self) -> Option<String> {
+    self.checksummed.then(|| CHECKSUM_CRC32.into())
+  }
 
-/// A synchronous RPC channel that allows JavaScript to synchronously call out
-/// to a child process and get a response over a line-based protocol,
-/// including handling of JavaScript-side callbacks before the call completes.
-///
-/// #### Protocol
-///
-/// Requests follow a MessagePack-based "tuple"/array protocol with 3 items:
-/// `(<type>, <name>, <payload>)`. All items are binary arrays of 8-bit
-/// integers, including the `<type>` and `<name>`, to avoid unnecessary
-/// encoding/decoding at the protocol level.
-///
-/// For specific message types and their corresponding protocol behavior, please
-/// see `MessageType` below.
-#[napi]
-pub struct SyncRpcChannel {
-  child: Child,
-  conn: RpcConnection<BufReader<ChildStdout>, BufWriter<ChildStdin>>,
-  callbacks: HashMap<String, FunctionRef<FnArgs<(String, String)>, String>>,
-}
+  /// Returns the number of times the child was restarted after exiting
+  /// unexpectedly, see `ChannelOptions.restartPolicy`. Respawns after the
+  /// idle timeout reaped the child (see `setIdleTimeout`) don't count.
+  #[napi]
+  pub fn restart_count(&self) -> u32 {
+    self.restarts
+  }
 
-#[napi]
-impl SyncRpcChannel {
-  /// Constructs a new `SyncRpcChannel` by spawning a child process with the
-  /// given `exe` executable, and a given set of `args`.
-  #[napi(constructor)]
-  pub fn new(exe: String, args: Vec<String>) -> Result<Self> {
-    let mut child = std::process::Command::new(exe)
-      .stdin(std::process::Stdio::piped())
-      .stdout(std::process::Stdio::piped())
-      .stderr(std::process::Stdio::inherit())
-      .args(args)
-      .spawn()?;
-    Ok(Self {
-      conn: RpcConnection::new(
-        BufReader::new(child.stdout.take().expect("Where did ChildStdout go?")),
-        BufWriter::new(child.stdin.take().expect("Where did ChildStdin go?")),
-      )?,
-      callbacks: HashMap::new(),
-      child,
-    })
+  /// Returns the OS process ID of the child. This keeps returning the same
+  /// value after `close()`, even though the process no longer exists. Throws
+  /// for a channel created with `fromRaw`.
+  #[napi]
+  pub fn pid(&self) -> Result<u32> {
+    self.child().id().ok_or_else(|| not_spawned("pid"))
   }
 
-  /// Send a request to the child process and wait for a response. The method
-  /// will not return, synchronously, until a response is received or an error
-  /// occurs.
+  /// Automatically kills the child once no request has been issued for
+  /// `timeoutMs` milliseconds, to reclaim the resources of idle children. The
+  /// next request transparently respawns it with the original `exe` and
+  /// `args`, keeping any registered callbacks. Any state the child held in
+  /// memory is lost when it is reaped.
   ///
-  /// This method will take care of encoding and decoding the binary payload to
-  /// and from a JS string automatically and suitable for smaller payloads.
+  /// Passing `null` or `undefined` disables the idle timeout.
   #[napi]
-  pub fn request_sync(&mut self, env: Env, method: String, payload: String) -> Result<String> {
-    self
-      .request_bytes_sync(env, method, payload.as_bytes())
-      .and_then(|arr| {
-        String::from_utf8((&arr[..]).into()).map_err(|e| {
-          Error::from_reason(format!("Error while encoding response as a string: {e}"))
-        })
-      })
+  pub fn set_idle_timeout(&mut self, timeout_ms: Option<u32>) -> Result<()> {
+    self.check_spawned("setIdleTimeout")?;
+    // A new reaper wouldn't know about a request in progress.
+    let _wire = try_lock_wire(&self.wire)?;
+    // Dropping the previous reaper, if any, shuts it down.
+    self.idle = timeout_ms.map(|timeout_ms| {
+      Arc::new(IdleReaper::new(
+        Duration::from_millis(timeout_ms.into()),
+        self.child.clone(),
+      ))
+    });
+    Ok(())
   }
 
-  /// Send a request to the child process and wait for a response. The method
-  /// will not return, synchronously, until a response is received or an error
-  /// occurs.
+  /// Applies a timeout of `timeoutMs` milliseconds to every subsequent
+  /// synchronous request that doesn't set its own, such as with
+  /// `requestSyncTimeout`, so it doesn't need repeating at each call site.
+  /// Timing out has the same consequences as with `requestSyncTimeout`,
+  /// including poisoning the channel unless `ChannelOptions.requestIds` is
+  /// set. Batches, pools and `requestAsync` aren't affected.
   ///
-  /// Unlike `requestSync`, this method will not do any of its own encoding or
-  /// decoding of payload data. Everything will be as sent/received through the
-  /// underlying protocol.
+  /// Passing `null` or `undefined` restores the default of waiting for
+  /// responses indefinitely.
   #[napi]
-  pub fn request_binary_sync(
+  pub fn set_default_timeout(&mut self, timeout_ms: Option<u32>) {
+    self.default_timeout = timeout_ms.map(|timeout_ms| Duration::from_millis(timeout_ms.into()));
+  }
+
+  /// Starts appending a trace of every message to `path`, as with
+  /// `ChannelOptions.traceFile`, replacing any trace in progress, or stops
+  /// tracing if `path` is `null` or `undefined`. This allows tracing a
+  /// channel only while reproducing a problem.
+  #[napi]
+  pub fn set_trace_file(&mut self, path: Option<String>) -> Result<()> {
+    let mut wire = try_lock_wire(&self.wire)?;
+    wire.tracer = path
+      .as_deref()
+      .map(|path| open_tracer(path, &self.spec.options))
+      .transpose()?;
+    Ok(())
+  }
+
+  /// Limits the payload of each `MessageType.Call` the child makes to
+  /// `maxBytes` bytes, checked before it is decoded and passed to the
+  /// callback, so a misbehaving child can't make the channel decode huge
+  /// strings. A call over the limit is answered with a `MessageType.CallError`
+  /// without invoking the callback, and the request it was made during
+  /// throws once the child finishes it.
+  ///
+  /// Unlike `ChannelOptions.maxPayloadLength`, which poisons the channel on any
+  /// oversized message, this leaves the channel usable. Passing `null` or
+  /// `undefined` removes the limit.
+  #[napi]
+  pub fn set_callback_payload_limit(&mut self, max_bytes: Option<u32>) -> Result<()> {
+    try_lock_wire(&self.wire)?.callback_payload_limit = max_bytes.map(|max| max as usize);
+    Ok(())
+  }
+
+  /// Replaces the child with a freshly spawned one, such as after rebuilding
+  /// its executable, while keeping the channel itself along with its
+  /// registered callbacks, options and stats. The current child is killed
+  /// first, and the new one goes through the same handshake and negotiation
+  /// as the first one did.
+  ///
+  /// `exe` and `args` default to those of the current child. When given,
+  /// they are also used for any later restarts (see
+  /// `ChannelOptions.restartPolicy`) and respawns after the idle timeout.
+  ///
+  /// This throws while a `requestAsync` is in progress, as do the channel's
+  /// other methods, so a request is never cut short. A channel that was
+  /// closed is reopened. If the new child fails to start, the channel can't
+  /// make requests until `respawn` succeeds.
+  #[napi]
+  pub fn respawn(
     &mut self,
     env: Env,
-    method: String,
-    payload: Uint8Array,
-  ) -> Result<Uint8Array> {
-    self.request_bytes_sync(env, method, &payload)
+    exe: Option<String>,
+    args: Option<Vec<String>>,
+  ) -> Result<()> {
+    self.check_spawned("respawn")?;
+    let wire = self.wire.clone();
+    let mut wire = try_lock_wire(&wire)?;
+    // The child is replaced either way, so whether the idle timeout reaped
+    // it doesn't matter.
+    if let Some(idle) = &self.idle {
+      idle.begin();
+    }
+    let res = self.replace_child(&mut wire, exe, args);
+    self.end_activity();
+    res.map_err(|e| wire_error(&env, e))
   }
 
-  fn request_bytes_sync(&mut self, env: Env, method: String, payload: &[u8]) -> Result<Uint8Array> {
-    let method_bytes = method.as_bytes();
-    self
-      .conn
-      .write(MessageType::Request as u8, method_bytes, payload)?;
-    loop {
-      let (ty, name, payload) = self.conn.read()?;
-      match ty.try_into().map_err(Error::from_reason)? {
-        MessageType::Response => {
-          if name == method_bytes {
-            return Ok(payload.into());
-          } else {
-            let name = String::from_utf8_lossy(&name);
-            return Err(Error::from_reason(format!(
-              "name mismatch for response: expected `{method}`, got `{name}`"
-            )));
-          }
-        }
-        MessageType::Error => {
-          return Err(
-            self
-              .conn
-              .create_error(&String::from_utf8_lossy(&name), payload, &method)
-              .into(),
-          );
-        }
-        MessageType::Call => {
-          self.handle_call(&env, &String::from_utf8_lossy(&name), payload)?;
-        }
-        _ => {
-          return Err(Error::from_reason(format!(
-            "Invalid message type from child: {ty:?}"
-          )))
-        }
-      }
+  /// Closes the channel by asking the child to exit on its own (see
+  /// `MessageType.Shutdown`), giving it up to `timeoutMs` milliseconds to do
+  /// so before terminating it as `close()` does.
+  ///
+  /// Returns `true` if the child exited on its own, or `false` if it had to be
+  /// terminated. For a channel created with `fromRaw`, the connection is
+  /// closed right after asking, and this returns whether the request to exit
+  /// was sent.
+  #[napi]
+  pub fn close_graceful(&mut self, timeout_ms: u32) -> Result<bool> {
+    let wire = self.wire.clone();
+    let mut wire = try_lock_wire(&wire)?;
+    self.idle = None;
+    self.closed = true;
+    // If the child is already gone there's nobody to tell, which is fine.
+    let sent = wire.write(MessageType::Shutdown, b"", b"").is_ok();
+    if wire.is_attached() {
+      detach(&mut wire);
+      return Ok(sent);
+    }
+    if wire
+      .wait_for_exit(Duration::from_millis(timeout_ms.into()))?
+      .is_some()
+    {
+      return Ok(true);
     }
+    let mut child = self.child();
+    child.kill()?;
+    child.wait()?;
+    Ok(false)
   }
 
-  /// Registers a JavaScript callback that the child can invoke before
-  /// completing a request. The callback will receive a string name and a string
-  /// payload as its arguments and should return a string as its result.
-  ///
-  /// There is currently no `Uint8Array`-only equivalent to this functionality.
+  /// Closes the channel by asking the OS to terminate the child (`SIGTERM`
+  /// on Unix), giving it up to `graceMs` milliseconds to exit before killing
+  /// it as `close()` does. Unlike `closeGraceful`, this doesn't rely on the
+  /// child reading the protocol, so it also works for a child that is stuck.
   ///
-  /// If the callback throws, an it will be handled appropriately by
-  /// `requestSync` and the child will be notified.
-  #[napi(ts_args_type = "name: string, callback: (name: string, payload: string) => string")]
-  pub fn register_callback(&mut self, name: String, cb: Callback) -> Result<()> {
-    self.callbacks.insert(name, cb.create_ref()?);
-    Ok(())
+  /// Returns `true` if the child exited within the grace period, or `false`
+  /// if it had to be killed. Windows has no equivalent of `SIGTERM` for
+  /// console processes, so there the child is killed straight away, and this
+  /// only returns `true` if it had already exited.
+  #[napi]
+  pub fn terminate(&mut self, grace_ms: u32) -> Result<bool> {
+    self.check_spawned("terminate")?;
+    self.idle = None;
+    self.closed = true;
+    if self.child().try_wait()?.is_some() {
+      return Ok(true);
+    }
+    #[cfg(unix)]
+    {
+      let pid = self.pid()? as libc::pid_t;
+      // SAFETY: `kill` has no memory safety requirements. The child hasn't
+      // been reaped (see above), so `pid` can't have been reused.
+      if unsafe { libc::kill(pid, libc::SIGTERM) } != 0 {
+        return Err(io::Error::last_os_error().into());
+      }
+      if wait_for_exit(&self.child, Duration::from_millis(grace_ms.into()))?.is_some() {
+        return Ok(true);
+      }
+    }
+    #[cfg(not(unix))]
+    let _ = grace_ms;
+    let mut child = self.child();
+    child.kill()?;
+    child.wait()?;
+    Ok(false)
   }
 
   // Closes the channel, terminating its underlying process.
   #[napi]
   pub fn close(&mut self) -> Result<()> {
-    self.child.kill()?;
+    self.idle = None;
+    self.closed = true;
+    if self.child().is_attached() {
+      let mut wire = try_lock_wire(&self.wire)?;
+      detach(&mut wire);
+      return Ok(());
+    }
+    self.child().kill()?;
     Ok(())
   }
 
-  // Helper method to handle callback calls
-  fn handle_call(&mut self, env: &Env, name: &str, payload: Vec<u8>) -> Result<()> {
-    if let Some(cb) = self.callbacks.get(name) {
-      match cb.borrow_back(env)?.call((
-        name.into(),
-        String::from_utf8(payload).map_err(|e| {
+  // Helper method to invoke a registered callback during a synchronous
+  // request, falling back to the default callback, if any. Returns `None` if
+  // there is no callback named `name` and the default callback declined.
+  fn call_sync(&self, env: &Env, name: &str, payload: Vec<u8>) -> Option<Result<Vec<u8>>> {
+    self.observe(env, "callback", name, payload.len());
+    let Some(cb) = self.callbacks.get(name) else {
+      return self.call_default(env, name, payload);
+    };
+    let res = match cb {
+      RegisteredCallback::String(cb) => String::from_utf8(payload)
+        .map_err(|e| {
+          Error::from_reason(format!(
+            "Failed to deserialize callback payload into a string: {e}"
+          ))
+        })
+        .and_then(|payload| {
+          let res = cb.borrow_back(env)?.call((name.into(), payload).into())?;
+          callback_string(name, res).map(String::into_bytes)
+        }),
+      RegisteredCallback::Binary(cb) => cb.borrow_back(env).and_then(|cb| {
+        cb.call((name.into(), payload.into()).into())
+          .map(|res| res.to_vec())
+      }),
+      RegisteredCallback::Header(cb) => String::from_utf8(payload)
+        .map_err(|e| {
           Error::from_reason(format!(
             "Failed to deserialize callback payload into a string: {e}"
           ))
-        })?,
-      ).into()) {
-        Ok(res) => {
-          self.conn.write(
-            MessageType::CallResponse as u8,
-            name.as_bytes(),
-            res.as_bytes(),
-          )?;
+        })
+        .and_then(|payload| match payload.split_once('\t') {
+          Some((header, body)) => {
+            let res = cb
+              .borrow_back(env)?
+              .call((name.into(), header.into(), body.into()).into())?;
+            callback_string(name, res).map(String::into_bytes)
+          }
+          None => Err(Error::from_reason(
+            "expected a payload of two tab-separated fields (header and body), but found no tab",
+          )),
+        }),
+      RegisteredCallback::Async(_) => Err(Error::from_reason(format!(
+        "callback `{name}` is asynchronous and can only be invoked during `requestAsync`"
+      ))),
+    };
+    Some(res)
+  }
+
+  // Helper method to invoke the default callback, see `call_sync`.
+  fn call_default(&self, env: &Env, name: &str, payload: Vec<u8>) -> Option<Result<Vec<u8>>> {
+    let cb = self.default_callback.as_ref()?;
+    let res = String::from_utf8(payload)
+      .map_err(|e| {
+        Error::from_reason(format!(
+          "Failed to deserialize callback payload into a string: {e}"
+        ))
+      })
+      .and_then(|payload| {
+        let res = cb.borrow_back(env)?.call((name.into(), payload).into())?;
+        match res.get_type()? {
+          ValueType::Null | ValueType::Undefined => Ok(None),
+          _ => callback_string(name, res).map(Some),
         }
-        Err(e) => {
-          self.conn.write(
-            MessageType::CallError as u8,
-            name.as_bytes(),
-            format!("{e}").trim().as_bytes(),
-          )?;
-          return Err(Error::from_reason(format!(
-            "Error calling callback `{name}`: {}",
-            e
-          )));
+      });
+    match res {
+      Ok(res) => res.map(|res| Ok(res.into_bytes())),
+      Err(e) => Some(Err(e)),
+    }
+  }
+
+  // Helper method to report an event to the observer, if any, see
+  // `setObserver`. Whatever the observer throws is cleared and discarded.
+  fn observe(&self, env: &Env, event: &str, name: &str, bytes: usize) {
+    let Some(observer) = &self.observer else {
+      return;
+    };
+    let _ = observer
+      .borrow_back(env)
+      .and_then(|cb| cb.call((event.into(), name.into(), bytes as i64).into()));
+  }
+
+  // Helper method to report how a request ended to the observer, if any.
+  fn observe_result(
+    &self,
+    env: &Env,
+    method: &str,
+    res: std::result::Result<&RemoteResult, &Error>,
+  ) {
+    match res {
+      Ok(Ok(payload)) => self.observe(env, "response", method, payload.len()),
+      Ok(Err(message)) => self.observe(env, "error", method, message.len()),
+      Err(_) => self.observe(env, "error", method, 0),
+    }
+  }
+
+  // Helper method to run `f` as activity on the channel, during which the
+  // idle timeout (if any) can't reap the child.
+  fn with_activity<T>(
+    &mut self,
+    f: impl FnOnce(&mut Self, &mut Wire) -> WireResult<T>,
+  ) -> WireResult<T> {
+    let wire = self.wire.clone();
+    let mut wire = try_lock_wire(&wire)?;
+    let res = self
+      .begin_activity(&mut wire)
+      .and_then(|()| f(self, &mut wire));
+    self.end_activity();
+    res
+  }
+
+  // Helper method to mark the start of activity on the channel, respawning
+  // the child first if it was reaped while idle. Must be paired with
+  // `end_activity`, even if it fails.
+  fn begin_activity(&mut self, wire: &mut Wire) -> WireResult<()> {
+    self.cancel_requested.store(false, Ordering::Relaxed);
+    if self.idle.as_ref().is_some_and(|idle| idle.begin()) {
+      self.spawn_child(wire)?;
+    }
+    Ok(())
+  }
+
+  fn end_activity(&self) {
+    if let Some(idle) = &self.idle {
+      idle.end();
+    }
+  }
+
+  // Helper method to lock the current child process.
+  fn child(&self) -> MutexGuard<'_, ChildProcess> {
+    self.child.lock().unwrap_or_else(PoisonError::into_inner)
+  }
+
+  // Helper method to throw from `method` for a channel created with
+  // `fromRaw`, which doesn't own the process it talks to.
+  fn check_spawned(&self, method: &str) -> Result<()> {
+    if self.child().is_attached() {
+      return Err(not_spawned(method));
+    }
+    Ok(())
+  }
+
+  // Helper method to replace the child with a freshly spawned one, using the
+  // current `exe` and `args`.
+  fn spawn_child(&mut self, wire: &mut Wire) -> WireResult<()> {
+    let (child, conn) = self.spec.spawn()?;
+    *self.child() = child;
+    wire.conn = conn;
+    wire.poisoned = None;
+    wire.unanswered_pings = 0;
+    self.start(wire)
+  }
+
+  // Helper method for `respawn`, to kill the child and spawn a new one.
+  fn replace_child(
+    &mut self,
+    wire: &mut Wire,
+    exe: Option<String>,
+    args: Option<Vec<String>>,
+  ) -> WireResult<()> {
+    {
+      let mut child = self.child();
+      child.kill()?;
+      child.wait()?;
+    }
+    if let Some(exe) = exe {
+      self.spec.exe = exe;
+    }
+    if let Some(args) = args {
+      self.spec.args = args;
+    }
+    self.closed = false;
+    // Requests fail with the reason the new child didn't start, if any, not
+    // whatever was wrong with the old one.
+    wire.poisoned = None;
+    let res = self.spawn_child(wire);
+    if let Err(e) = &res {
+      wire
+        .poisoned
+        .get_or_insert_with(|| format!("failed to respawn child process: {e}"));
+    }
+    res
+  }
+
+  // Helper method to restart the child after a failed request if it has
+  // exited, as allowed by the restart policy. Returns whether it was
+  // restarted, or throws if the restart budget is used up.
+  fn restart_exited_child(&mut self, wire: &mut Wire) -> WireResult<bool> {
+    let Some(policy) = &self.spec.options.restart_policy else {
+      return Ok(false);
+    };
+    if self.closed {
+      return Ok(false);
+    }
+    let Some(status) = self.child().try_wait()? else {
+      return Ok(false);
+    };
+    if self.restarts >= policy.max_restarts {
+      return Err(
+        Error::from_reason(format!(
+          "child process exited ({status}) and cannot be restarted: all {} restarts allowed by the restart policy were used",
+          policy.max_restarts
+        ))
+        .into(),
+      );
+    }
+    if let Some(backoff_ms) = policy.backoff_ms {
+      std::thread::sleep(Duration::from_millis(backoff_ms.into()));
+    }
+    self.restarts += 1;
+    self.spawn_child(wire)?;
+    Ok(true)
+  }
+
+  // Helper method to set up a freshly spawned child as configured: perform
+  // the protocol handshake, then negotiate payload compression and
+  // checksums. The channel is poisoned if any of these fails.
+  fn start(&mut self, wire: &mut Wire) -> WireResult<()> {
+    self.protocol_version = None;
+    self.compressed = false;
+    self.checksummed = false;
+    let res = self
+      .handshake(wire)
+      .and_then(|()| self.negotiate_compression(wire))
+      .and_then(|()| self.negotiate_checksum(wire));
+    if let Err(e) = &res {
+      wire.poisoned = Some(format!("failed to start child process: {e}"));
+    }
+    res
+  }
+
+  fn handshake(&mut self, wire: &mut Wire) -> WireResult<()> {
+    let Some(mode) = self.handshake_mode else {
+      return Ok(());
+    };
+    let version = PROTOCOL_VERSION.to_string();
+    let (ty, payload) = Self::startup_request(
+      wire,
+      HANDSHAKE_METHOD,
+      "the protocol handshake",
+      version.as_bytes(),
+    )?;
+    self.protocol_version = match ty {
+      MessageType::Response => {
+        let Ok(version) = payload.parse::<u32>() else {
+          return Err(
+            Error::from_reason(format!("invalid protocol version from child: `{payload}`")).into(),
+          );
+        };
+        if version < MIN_PROTOCOL_VERSION {
+          return Err(
+            Error::from_reason(format!(
+              "child process speaks protocol version {version}, but at least version {MIN_PROTOCOL_VERSION} is required"
+            ))
+            .into(),
+          );
         }
+        Some(version.min(PROTOCOL_VERSION))
       }
-    } else {
-      self.conn.write(MessageType::CallError as u8, name.as_bytes(), format!("unknown callback: `{name}`. Please make sure to register it on the JavaScript side before invoking it.").as_bytes())?;
-      return Err(Error::from_reason(format!(
-        "no callback named `{name}` found"
-      )));
+      MessageType::Error if mode == HandshakeMode::Optional => None,
+      _ => {
+        return Err(
+          Error::from_reason(format!(
+            "child process does not support the protocol handshake: {payload}"
+          ))
+          .into(),
+        )
+      }
+    };
+    Ok(())
+  }
+
+  // Without the `zstd` feature, the constructor rejects `compression`, so
+  // there is never a threshold to negotiate.
+  #[cfg_attr(not(feature = "zstd"), allow(unused_variables))]
+  fn negotiate_compression(&mut self, wire: &mut Wire) -> WireResult<()> {
+    let Some(threshold) = self.compression_threshold else {
+      return Ok(());
+    };
+    let (ty, _) = Self::startup_request(
+      wire,
+      COMPRESSION_METHOD,
+      "compression negotiation",
+      COMPRESSION_ZSTD.as_bytes(),
+    )?;
+    // A child that doesn't support compression just declines it.
+    if matches!(ty, MessageType::Response) {
+      #[cfg(feature = "zstd")]
+      wire.conn.set_compression(Some(threshold));
+      self.compressed = true;
+    }
+    Ok(())
+  }
+
+  fn negotiate_checksum(&mut self, wire: &mut Wire) -> WireResult<()> {
+    if !self.checksum_requested {
+      return Ok(());
+    }
+    let (ty, _) = Self::startup_request(
+      wire,
+      CHECKSUM_METHOD,
+      "checksum negotiation",
+      CHECKSUM_CRC32.as_bytes(),
+    )?;
+    // A child that doesn't support checksums just declines them.
+    if matches!(ty, MessageType::Response) {
+      wire.conn.set_checksums(true);
+      self.checksummed = true;
     }
     Ok(())
   }
+
+  // Helper method to send one of the reserved requests made while starting a
+  // child, returning the type and payload of the child's response or error.
+  fn startup_request(
+    wire: &mut Wire,
+    method: &str,
+    what: &str,
+    payload: &[u8],
+  ) -> WireResult<(MessageType, String)> {
+    let deadline = Instant::now() + STARTUP_TIMEOUT;
+    wire.set_deadline(Some(deadline));
+    let res = Self::run_startup_request(wire, method, what, payload);
+    wire.set_deadline(None);
+    res
+  }
+
+  fn run_startup_request(
+    wire: &mut Wire,
+    method: &str,
+    what: &str,
+    payload: &[u8],
+  ) -> WireResult<(MessageType, String)> {
+    match wire.write_request(method.as_bytes(), payload, None) {
+      // Let the read below find out how the child exited.
+      Err(RpcError::Io(e)) if e.kind() == io::ErrorKind::BrokenPipe => {}
+      res => res?,
+    }
+    let (ty, payload) = match wire.read() {
+      Err(RpcError::Io(e)) if e.kind() == io::ErrorKind::TimedOut => {
+        return Err(
+          Error::from_reason(format!(
+            "child process did not respond to {what} within {}ms",
+            STARTUP_TIMEOUT.as_millis()
+          ))
+          .into(),
+        );
+      }
+      Ok(None) => {
+        let status = wire.describe_exit_status();
+        return Err(
+          Error::from_reason(format!(
+            "child process closed the connection during {what} ({status})"
+          ))
+          .into(),
+        );
+      }
+      msg => msg?.expect("EOF was handled above"),
+    };
+    let name = wire.received_name();
+    if name != method.as_bytes() {
+      let name = String::from_utf8_lossy(name);
+      return Err(
+        Error::from_reason(format!(
+          "name mismatch for {what}: expected `{method}`, got `{name}`"
+        ))
+        .into(),
+      );
+    }
+    match MessageType::try_from(ty).map_err(Error::from_reason)? {
+      ty @ (MessageType::Response | MessageType::Error) => {
+        Ok((ty, String::from_utf8_lossy(&payload).into_owned()))
+      }
+      _ => Err(
+        Error::from_reason(format!(
+          "Invalid message type from child during {what}: {ty:?}"
+        ))
+        .into(),
+      ),
+    }
+  }
+}
+
+impl Drop for SyncRpcChannel {
+  // A channel that is garbage collected without being closed would otherwise
+  // leave its child running. Finalizers run on the JavaScript thread, which
+  // is as safe a place to kill and reap the child as `close()`.
+  fn drop(&mut self) {
+    if self.closed {
+      return;
+    }
+    let mut child = self.child();
+    if child.kill().is_ok() {
+      let _ = child.wait();
+    }
+  }
+}
+
+// Helper function to open the trace file at `path`, as configured by
+// `options`.
+fn open_tracer(path: &str, options: &ChannelOptions) -> Result<Tracer> {
+  Tracer::open(
+    path,
+    options.trace_payload_hashes.unwrap_or(false),
+    options.trace_preview_bytes.unwrap_or(0) as usize,
+  )
+  .map_err(|e| Error::from_reason(format!("failed to open trace file `{path}`: {e}")))
+}
+
+fn not_spawned(method: &str) -> Error {
+  Error::from_reason(format!(
+    "`{method}` is unavailable for a channel created with `fromRaw`, which didn't spawn its child"
+  ))
+}
+
+// Helper function to close the connection of a channel created with
+// `fromRaw`, so that the process on the other end sees EOF on its stdin.
+// The process itself is left running.
+fn detach(wire: &mut Wire) {
+  wire.conn.writer_mut().get_mut().close();
+  wire.poisoned = Some("the channel was closed".into());
+}
+
+// Helper function to take ownership of the descriptors (or handles) passed to
+// `SyncRpcChannel.fromRaw`. Both are checked before either is taken, so that
+// one being invalid doesn't close the other, which the caller still owns.
+#[cfg(unix)]
+fn raw_files(stdin_fd: i64, stdout_fd: i64) -> Result<(File, File)> {
+  use std::os::fd::FromRawFd;
+
+  let stdin_fd = raw_fd(stdin_fd, "stdinFd")?;
+  let stdout_fd = raw_fd(stdout_fd, "stdoutFd")?;
+  // SAFETY: The descriptors are open (see `raw_fd`), and the caller hands over
+  // ownership of them.
+  Ok(unsafe { (File::from_raw_fd(stdin_fd), File::from_raw_fd(stdout_fd)) })
+}
+
+// Helper function to check that `fd`, passed as `name`, is an open
+// descriptor.
+#[cfg(unix)]
+fn raw_fd(fd: i64, name: &str) -> Result<i32> {
+  i32::try_from(fd)
+    .ok()
+    // SAFETY: `fcntl` has no memory safety requirements.
+    .filter(|&fd| fd >= 0 && unsafe { libc::fcntl(fd, libc::F_GETFD) } != -1)
+    .ok_or_else(|| Error::from_reason(format!("`{name}` is not an open file descriptor: {fd}")))
+}
+
+#[cfg(windows)]
+fn raw_files(stdin_handle: i64, stdout_handle: i64) -> Result<(File, File)> {
+  use std::os::windows::io::FromRawHandle;
+
+  let stdin_handle = raw_handle(stdin_handle, "stdinFd")?;
+  let stdout_handle = raw_handle(stdout_handle, "stdoutFd")?;
+  // SAFETY: The caller hands over ownership of the handles.
+  Ok(unsafe {
+    (
+      File::from_raw_handle(stdin_handle),
+      File::from_raw_handle(stdout_handle),
+    )
+  })
+}
+
+#[cfg(windows)]
+fn raw_handle(handle: i64, name: &str) -> Result<std::os::windows::io::RawHandle> {
+  if handle <= 0 {
+    return Err(Error::from_reason(format!(
+      "`{name}` is not a valid handle: {handle}"
+    )));
+  }
+  Ok(handle as isize as std::os::windows::io::RawHandle)
+}
+
+// Helper function to decode a response payload as a UTF-8 string, pointing
+// out where decoding failed, if it does.
+fn response_to_string(payload: Vec<u8>) -> Result<String> {
+  String::from_utf8(payload).map_err(|e| {
+    let offset = e.utf8_error().valid_up_to();
+    let problem = match e.utf8_error().error_len() {
+      Some(len) => format!("invalid UTF-8 sequence of {len} bytes at byte offset {offset}"),
+      None => format!("incomplete UTF-8 sequence at byte offset {offset}"),
+    };
+    Error::from_reason(format!(
+      "Error while encoding response as a string: {problem} of a {}-byte payload. Use `requestBinarySync` for responses that aren't text.",
+      e.as_bytes().len()
+    ))
+  })
+}
+
+// Helper function to take the string a callback returned, or fail with an
+// error naming the callback and the type of whatever else it returned, rather
+// than napi's generic conversion error.
+fn callback_string(name: &str, value: Unknown<'_>) -> Result<String> {
+  match value.get_type()? {
+    ValueType::String => String::from_unknown(value),
+    ty => Err(Error::from_reason(format!(
+      "callback `{name}` returned a non-string value of type {}",
+      ty.to_string().to_lowercase()
+    ))),
+  }
+}
+
+// Helper function to turn an error reported by the child for a request for
+// `method` into the error to throw. Structured errors (see
+// `MessageType.Error`) are thrown as JS errors with the given `code`,
+// `message` and `data`; anything else is thrown as a plain error with the
+// whole payload as its message, after how long the request took to fail.
+// Either way, that is also the error's `elapsedMs`.
+fn remote_error(env: &Env, method: &str, payload: String, elapsed: Duration) -> Error {
+  let plain = |payload| {
+    (
+      None,
+      format!("{}: {payload}", failed_after(method, elapsed)),
+      None,
+    )
+  };
+  let (code, message, data) = match serde_json::from_str(&payload) {
+    Ok(serde_json::Value::Object(mut fields)) => {
+      match (fields.remove("code"), fields.remove("message")) {
+        (Some(serde_json::Value::String(code)), Some(serde_json::Value::String(message))) => {
+          (Some(code), message, fields.remove("data"))
+        }
+        _ => plain(payload),
+      }
+    }
+    _ => plain(payload),
+  };
+  let err = env
+    .create_error(Error::from_reason(message))
+    .and_then(|mut err| {
+      if let Some(code) = code {
+        err.set_named_property("code", code)?;
+      }
+      if let Some(data) = data {
+        err.set_named_property("data", data)?;
+      }
+      err.set_named_property("elapsedMs", elapsed.as_millis() as f64)?;
+      Ok(err)
+    });
+  match err {
+    // Keeps a reference to the error object, which is thrown as is.
+    Ok(err) => Error::from(err.to_unknown()),
+    Err(e) => e,
+  }
+}
+
+// Helper function to turn a failure of the channel into the error to throw.
+fn wire_error(env: &Env, err: WireError) -> Error {
+  match err {
+    WireError::Rpc(err) => rpc_error(env, &err, err.to_string()),
+    WireError::Other(err) => err,
+  }
+}
+
+// Like `wire_error`, for the failure of a request for `method`, saying how
+// long it took to fail.
+fn request_error(env: &Env, method: &str, err: WireError, elapsed: Duration) -> Error {
+  let failed = failed_after(method, elapsed);
+  match err {
+    WireError::Rpc(err) => rpc_error(env, &err, format!("{failed}: {err}")),
+    WireError::Other(mut err) => {
+      err.reason = format!("{failed}: {}", err.reason);
+      err
+    }
+  }
+}
+
+fn failed_after(method: &str, elapsed: Duration) -> String {
+  format!("request `{method}` failed after {}ms", elapsed.as_millis())
+}
+
+// Helper function to throw `message` for a failure of the connection to the
+// child, as a JS error with a `code` saying what went wrong, so that it can be
+// told apart without looking at the message.
+fn rpc_error(env: &Env, err: &RpcError, message: String) -> Error {
+  let code = match err {
+    RpcError::NameMismatch { .. } => "ERR_NAME_MISMATCH",
+    RpcError::FramingError(_) => "ERR_FRAMING",
+    RpcError::PayloadTooLarge { .. } => "ERR_PAYLOAD_TOO_LARGE",
+    RpcError::ChecksumMismatch { .. } => "ERR_CHECKSUM",
+    RpcError::ChildDisconnected => "ERR_CHILD_DISCONNECTED",
+    RpcError::RemoteError(_) => "ERR_REMOTE",
+    RpcError::Io(_) => "ERR_IO",
+  };
+  let err = env
+    .create_error(Error::from_reason(message))
+    .and_then(|mut err| {
+      err.set_named_property("code", code)?;
+      Ok(err)
+    });
+  match err {
+    Ok(err) => Error::from(err.to_unknown()),
+    Err(e) => e,
+  }
+}
+
+// Helper function to lock a channel's wire from the JavaScript thread, which
+// must never wait for it: the asynchronous request holding it may itself be
+// waiting for the JavaScript thread to run a callback.
+fn try_lock_wire(wire: &Mutex<Wire>) -> Result<MutexGuard<'_, Wire>> {
+  let guard = match wire.try_lock() {
+    Ok(guard) => guard,
+    Err(TryLockError::Poisoned(e)) => e.into_inner(),
+    Err(TryLockError::WouldBlock) => return Err(busy_error()),
+  };
+  if guard.busy {
+    return Err(busy_error());
+  }
+  Ok(guard)
+}
+
+fn busy_error() -> Error {
+  Error::from_reason("channel is busy with an asynchronous request")
+}
+
+// Helper function to spawn a background thread, named so that it can be told
+// apart from the application's own threads in a profiler or crash dump.
+fn spawn_thread<F, T>(name: &str, f: F) -> std::thread::JoinHandle<T>
+where
+  F: FnOnce() -> T + Send + 'static,
+  T: Send + 'static,
+{
+  std::thread::Builder::new()
+    .name(name.into())
+    .spawn(f)
+    .expect("failed to spawn thread")
+}
+
+// Helper function to show the start of a (possibly invalid) JSON payload in
+// an error message.
+fn json_snippet(payload: &[u8]) -> String {
+  const MAX_SNIPPET_CHARS: usize = 64;
+  let payload = String::from_utf8_lossy(payload);
+  let mut chars = payload.chars();
+  let snippet: String = chars.by_ref().take(MAX_SNIPPET_CHARS).collect();
+  if chars.next().is_some() {
+    format!("{snippet}...")
+  } else {
+    snippet
+  }
 }
 
 /// Messages types exchanged between the channel and its child. All messages
@@ -202,17 +2362,62 @@ pub enum MessageType {
   /// `<name>` as the method name. The child may send back any number of
   /// `MessageType.Call` messages and must then close the request with either a
   /// `MessageType.Response`, or a `MessageType.Error`.  message.
+  ///
+  /// Requests sent by `SyncRpcChannel#requestSyncWithDeadline` have a 4th
+  /// `<deadline>` item: an unsigned integer number of milliseconds, from when
+  /// the request was sent, that the child has to respond.
+  ///
+  /// Requests sent by `SyncRpcChannel#requestBatchSync`, as well as all
+  /// requests when `ChannelOptions.requestIds` is set, have a 5th `<id>`
+  /// item: an unsigned integer identifying the request, with a `nil`
+  /// `<deadline>` if there is none. The child must echo the `<id>` as the 5th
+  /// item of its `MessageType.Response` or `MessageType.Error`, and should
+  /// also send it with any `MessageType.Call` made while working on the
+  /// request, in which case the channel echoes it in its
+  /// `MessageType.CallResponse` or `MessageType.CallError`. Such responses
+  /// may arrive in any order.
+  ///
+  /// The `$/ping` method name is reserved for `SyncRpcChannel#ping`: the child
+  /// should respond to it straight away with an empty `MessageType.Response`.
+  ///
+  /// The `$/handshake` method name is reserved for the protocol handshake (see
+  /// `ChannelOptions.handshake`). Its `<payload>` is the channel's protocol
+  /// version, as a decimal UTF-8 string. The child should respond with a
+  /// `MessageType.Response` whose `<payload>` is the version it speaks, in the
+  /// same format, or with a `MessageType.Error` if it does not support the
+  /// handshake.
+  ///
+  /// The `$/compression` method name is reserved for negotiating payload
+  /// compression (see `ChannelOptions.compression`), right after any
+  /// handshake. Its `<payload>` is the name of the codec, `zstd`. The child
+  /// should respond with an empty `MessageType.Response` to accept, or with a
+  /// `MessageType.Error` to decline. Once accepted, every `<payload>` in
+  /// either direction, starting with the next message, begins with a flag
+  /// byte: `0` if the rest of it is uncompressed, or `1` if it is compressed
+  /// with zstd. Each end decides for itself which payloads to compress.
+  ///
+  /// The `$/checksum` method name is reserved for negotiating message
+  /// checksums (see `ChannelOptions.checksum`), right after any compression
+  /// negotiation. Its `<payload>` is the name of the algorithm, `crc32`. The
+  /// child should respond with an empty `MessageType.Response` to accept, or
+  /// with a `MessageType.Error` to decline. Once accepted, every `<payload>`
+  /// in either direction, starting with the next message, ends with 4 more
+  /// bytes: the big-endian CRC-32 (as computed by zlib) of the message's
+  /// `<type>` byte, `<name>`, and the rest of the `<payload>`, in that order.
+  /// With compression on, the checksum covers the compression flag and the
+  /// possibly compressed payload, as sent.
   Request = 1,
   /// A response to a `MessageType.Call` message that the child previously sent.
   /// The `<payload>` is the return value from invoking the JavaScript callback
   /// associated with it. If the callback errors, `MessageType.CallError` will
   /// be sent to the child.
   CallResponse,
-  /// Informs the child that an error occurred. The `<payload>` will be the
-  /// binary representation of the stringified error, as UTF-8 bytes, not
-  /// necessarily in JSON format. The method linked to this message will also
-  /// throw an error after sending this message to its child and terminate the
-  /// request call.
+  /// Informs the child that invoking a callback failed, either because the
+  /// callback threw or because there is no such callback. The `<payload>` will
+  /// be the binary representation of the stringified error, as UTF-8 bytes, not
+  /// necessarily in JSON format. The request stays open: the child may recover
+  /// and carry on towards a `MessageType.Response`, or give up on the request
+  /// with a `MessageType.Error`, which the request then throws.
   CallError,
 
   // --- Sent by child ---
@@ -224,33 +2429,127 @@ pub enum MessageType {
   /// representation of the stringified error, as UTF-8 bytes, not necessarily
   /// in JSON format. The method associated with this call will also throw an
   /// error after receiving this message from the child.
+  ///
+  /// If the `<payload>` is a JSON object with string `code` and `message`
+  /// properties, the thrown error has that `message`, and `code` and `data`
+  /// properties taken from the object's `code` and (optional) `data`, so
+  /// callers can tell kinds of errors apart. Otherwise, the whole payload is
+  /// the message, after how long the request took to fail, as in
+  /// "request `compile` failed after 4213ms: <payload>".
+  ///
+  /// The thrown error also has an `elapsedMs` property: the number of
+  /// milliseconds the request took to fail, to tell a child that failed
+  /// straight away apart from one that was slow to.
   Error,
   /// A request to invoke a pre-registered JavaScript callback (see
   /// `SyncRpcChannel#registerCallback`). `<name>` is the name of the callback,
   /// and `<payload>` is an encoded UTF-8 string that the callback will be
   /// called with. The child should then listen for `MessageType.CallResponse`
   /// and `MessageType.CallError` messages.
+  ///
+  /// The child may send several `MessageType.Call` messages before reading
+  /// any of their responses. Calls are handled, and responded to, in the order
+  /// they were received.
   Call,
+
+  // --- Sent by channel ---
+  /// Asks the child to exit gracefully (see `SyncRpcChannel#closeGraceful`).
+  /// Both `<name>` and `<payload>` are empty. The child should release any
+  /// resources it holds and exit without sending a response.
+  Shutdown,
+
+  // --- Sent by child ---
+  /// A piece of a streamed response to a request made with
+  /// `SyncRpcChannel#requestStreamSync`. `<name>` MUST match the
+  /// `MessageType.Request` message's `<name>`, and `<payload>` is the piece
+  /// of the response. The child may send any number of these, and must then
+  /// close the request with a `MessageType.Response` or `MessageType.Error`
+  /// as usual. Sending one in response to any other kind of request is an
+  /// error.
+  ResponseChunk,
+
+  // --- Sent by channel ---
+  /// Asks the child to abandon a request in progress (see
+  /// `CancellationHandle#cancel`). `<name>` is the request's `<name>`, and
+  /// `<payload>` is empty. For requests sent with an `<id>`, the message
+  /// carries the same `<id>` as its 5th item. The child should stop working
+  /// on the request and close it with a `MessageType.Error` whose
+  /// `<payload>` is a structured error with code `"ECANCELED"`, such as
+  /// `{"code":"ECANCELED","message":"request was cancelled"}`. A child that
+  /// has already responded, or that doesn't support cancellation, can
+  /// ignore it.
+  Cancel,
+
+  // --- Sent by child ---
+  /// A diagnostic message, which the child may send at any time, including
+  /// in the middle of a request. `<name>` is empty, and `<payload>` is the
+  /// message as UTF-8 text. The channel never responds to it: it is passed
+  /// to `ChannelOptions.log`, if set, and otherwise discarded. This lets the
+  /// child log human-readable output without corrupting the protocol on its
+  /// stdout.
+  Log,
+  /// An unsolicited notification, such as an event the child is watching
+  /// for, which the child may send at any time, including when no request is
+  /// in progress. `<name>` names the notification, and `<payload>` is its
+  /// UTF-8 encoded content. The channel never responds to it: it is passed
+  /// to the handler set with `SyncRpcChannel#onNotify`, if any, and
+  /// otherwise discarded.
+  ///
+  /// The channel only reads from the child while it is busy with something,
+  /// so notifications sent between requests are only picked up by the next
+  /// request, or by `SyncRpcChannel#pumpNotifications`.
+  Notify,
   // NOTE: Do NOT put any variants below this one, always add them _before_ it.
-  // See comment in TryFrom impl, and remove this when `variant_count` stabilizes.
+  // It marks the number of slots needed to index by message type (see
+  // `MESSAGE_TYPE_SLOTS`), and is checked against the protocol definition
+  // below.
   _UnusedPlaceholderVariant,
   // NOTHING SHOULD GO BELOW HERE
 }
 
+impl From<libsyncrpc_connection::MessageType> for MessageType {
+  fn from(ty: libsyncrpc_connection::MessageType) -> Self {
+    use libsyncrpc_connection::MessageType as Protocol;
+    match ty {
+      Protocol::Request => MessageType::Request,
+      Protocol::CallResponse => MessageType::CallResponse,
+      Protocol::CallError => MessageType::CallError,
+      Protocol::Response => MessageType::Response,
+      Protocol::Error => MessageType::Error,
+      Protocol::Call => MessageType::Call,
+      Protocol::Shutdown => MessageType::Shutdown,
+      Protocol::ResponseChunk => MessageType::ResponseChunk,
+      Protocol::Cancel => MessageType::Cancel,
+      Protocol::Log => MessageType::Log,
+      Protocol::Notify => MessageType::Notify,
+    }
+  }
+}
+
+// The protocol is defined by `libsyncrpc_connection`, which child
+// implementations written in Rust use directly. This enum only exists to
+// expose it to JavaScript, and must agree with it on every value.
+const _: () = {
+  use libsyncrpc_connection::MessageType as Protocol;
+  assert!(MessageType::Request as u8 == Protocol::Request as u8);
+  assert!(MessageType::Notify as u8 == Protocol::Notify as u8);
+  assert!(MessageType::_UnusedPlaceholderVariant as u8 == Protocol::Notify as u8 + 1);
+};
+
 impl TryFrom<u8> for MessageType {
   type Error = String;
 
   fn try_from(value: u8) -> std::result::Result<Self, <MessageType as TryFrom<u8>>::Error> {
-    // TODO: change to the following line when `variant_count` stabilizes
-    // (https://github.com/rust-lang/rust/issues/73662) and remove `_UnusedPlaceholderVariant`
-    //
-    // if (1..=std::mem::variant_count::<MessageType>()) {
-    if (1..(MessageType::_UnusedPlaceholderVariant as u8)).contains(&value) {
-      // SAFETY: This is safe as long as the above holds true. It'll be fully
-      // safe once `variant_count` stabilizes.
-      Ok(unsafe { std::mem::transmute::<u8, MessageType>(value) })
-    } else {
-      Err(format!("Invalid message type: {value}"))
-    }
+    libsyncrpc_connection::MessageType::try_from(value)
+      .map(MessageType::from)
+      .map_err(|e| e.to_string())
   }
 }
+
+/// Parses a `MessageType` from the `<type>` byte of a message, throwing if it
+/// isn't the value of a known message type. Child implementations written in
+/// JavaScript can use this instead of hardcoding the values.
+#[napi]
+pub fn message_type_from_u8(n: u8) -> Result<MessageType> {
+  MessageType::try_from(n).map_err(Error::from_reason)
+}
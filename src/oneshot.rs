@@ -0,0 +1,132 @@
+use std::{
+  io::{self, BufReader, Read},
+  process::{Command, Stdio},
+  sync::{Mutex, PoisonError},
+};
+
+use napi::{
+  bindgen_prelude::{Result, Uint8Array},
+  Error,
+};
+
+use libsyncrpc_connection::{MessageType, RpcConnection, RpcError};
+
+use crate::{
+  child::{spawn_error, ChildProcess},
+  spawn_thread,
+  wire::{wait_for_exit, EXIT_GRACE_PERIOD},
+};
+
+/// Spawns `exe` with `args` for a single request, for children that are
+/// one-shot tools rather than long-lived servers. The request is written to
+/// the child's stdin, which is then closed so that the child sees EOF once it
+/// has read it. Returns the payload of the child's `MessageType.Response`,
+/// after reaping the child: a child still running a second after responding
+/// is killed.
+///
+/// There is no handshake, and since the child's stdin is closed, it can't
+/// invoke callbacks: a `MessageType.Call` fails the request.
+/// `MessageType.Log`, `MessageType.Notify` and `MessageType.ResponseChunk`
+/// messages are discarded. The child's stderr is collected rather than
+/// inherited, and any error thrown, including a `MessageType.Error` from the
+/// child, includes it along with the child's exit status.
+#[napi]
+pub fn request_oneshot(
+  exe: String,
+  args: Vec<String>,
+  method: String,
+  payload: Uint8Array,
+) -> Result<Uint8Array> {
+  let mut cmd = Command::new(&exe);
+  cmd
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .args(&args);
+  #[cfg(windows)]
+  crate::child::hide_window(&mut cmd);
+  let mut child = cmd.spawn().map_err(|e| spawn_error(&exe, &args, e))?;
+  let stdin = child.stdin.take().expect("Where did ChildStdin go?");
+  let stdout = child.stdout.take().expect("Where did ChildStdout go?");
+  let mut stderr = child.stderr.take().expect("Where did ChildStderr go?");
+  // Drained in the background, so that a child writing a lot to stderr
+  // doesn't block before responding.
+  let stderr = spawn_thread("libsyncrpc-stderr", move || {
+    let mut buf = Vec::new();
+    let _ = stderr.read_to_end(&mut buf);
+    buf
+  });
+
+  let res = exchange(stdin, stdout, &method, &payload);
+  let child = Mutex::new(ChildProcess::Spawned(child));
+  let status = match wait_for_exit(&child, EXIT_GRACE_PERIOD) {
+    Ok(Some(status)) => status.to_string(),
+    Ok(None) => {
+      let mut child = child.lock().unwrap_or_else(PoisonError::into_inner);
+      let _ = child.kill();
+      let _ = child.wait();
+      "killed after not exiting on its own".into()
+    }
+    Err(e) => format!("failed to get child exit status: {e}"),
+  };
+  let stderr = stderr.join().unwrap_or_default();
+  res.map(Uint8Array::from).map_err(|message| {
+    let stderr = String::from_utf8_lossy(&stderr);
+    let stderr = stderr.trim_end();
+    if stderr.is_empty() {
+      Error::from_reason(format!("{message} ({status})"))
+    } else {
+      Error::from_reason(format!("{message} ({status}), stderr:\n{stderr}"))
+    }
+  })
+}
+
+// Helper function to send the request and read the response, closing the
+// child's stdin in between and its stdout afterwards.
+fn exchange(
+  stdin: impl io::Write,
+  stdout: impl Read,
+  method: &str,
+  payload: &[u8],
+) -> std::result::Result<Vec<u8>, String> {
+  let mut writer = RpcConnection::new(io::empty(), stdin).map_err(|e| e.to_string())?;
+  match writer.write(MessageType::Request as u8, method.as_bytes(), payload) {
+    Ok(()) => {}
+    // The child exited without reading the request, which is reported along
+    // with its exit status once reading the response fails.
+    Err(RpcError::Io(e)) if e.kind() == io::ErrorKind::BrokenPipe => {}
+    Err(e) => return Err(format!("failed to send request `{method}`: {e}")),
+  }
+  drop(writer);
+
+  let mut reader =
+    RpcConnection::new(BufReader::new(stdout), io::sink()).map_err(|e| e.to_string())?;
+  loop {
+    let Some((ty, name, payload)) = reader.read().map_err(|e| e.to_string())? else {
+      return Err(format!(
+        "child process closed the connection before responding to `{method}`"
+      ));
+    };
+    match MessageType::try_from(ty).map_err(|e| e.to_string())? {
+      MessageType::Response if name == method.as_bytes() => return Ok(payload),
+      MessageType::Response => {
+        let name = String::from_utf8_lossy(&name);
+        return Err(format!(
+          "name mismatch for response: expected `{method}`, got `{name}`"
+        ));
+      }
+      MessageType::Error => {
+        let name = String::from_utf8_lossy(&name);
+        return Err(reader.create_error(&name, payload, method).to_string());
+      }
+      MessageType::Call => {
+        let name = String::from_utf8_lossy(&name);
+        return Err(format!(
+          "child invoked callback `{name}`, but one-shot requests don't support callbacks"
+        ));
+      }
+      MessageType::Log | MessageType::Notify | MessageType::ResponseChunk => {}
+      ty => return Err(format!("Invalid message type from child: {ty:?}")),
+    }
+  }
+}
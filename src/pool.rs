@@ -0,0 +1,180 @@
+use napi::{
+  bindgen_prelude::{FromNapiValue, Result, Unknown},
+  Env, Error, JsValue,
+};
+
+use crate::{
+  response_to_string, wire::RequestOptions, Callback, ChannelOptions, RegisteredCallback,
+  SyncRpcChannel, TryRequestResult,
+};
+
+/// A fixed set of `SyncRpcChannel`s to identical children, for spreading
+/// requests across several processes or broadcasting them to all of them.
+///
+/// Callbacks are registered on the pool as a whole, and each child can
+/// invoke them as it would on its own channel.
+#[napi]
+pub struct SyncRpcPool {
+  channels: Vec<SyncRpcChannel>,
+  // The index of the channel `requestSyncAny` uses next.
+  next: usize,
+}
+
+#[napi]
+impl SyncRpcPool {
+  /// Constructs a new `SyncRpcPool` by spawning `size` child processes with
+  /// the given `exe` executable and `args`, each set up as a `SyncRpcChannel`
+  /// with the given `options`. Throws if any of them fails to start, after
+  /// terminating the others.
+  #[napi(
+    constructor,
+    ts_args_type = "exe: string, args: Array<string>, size: number, options?: ChannelOptions | undefined | null"
+  )]
+  pub fn new(
+    env: Env,
+    exe: String,
+    args: Vec<String>,
+    size: u32,
+    options: Option<Unknown<'_>>,
+  ) -> Result<Self> {
+    if size == 0 {
+      return Err(Error::from_reason("a pool needs at least one child"));
+    }
+    let channels = (0..size)
+      .map(|_| {
+        // The options are converted once per channel, since functions such
+        // as a `stderr` callback can't be shared between channels.
+        let options = options
+          .map(|options| unsafe { ChannelOptions::from_napi_value(env.raw(), options.raw()) })
+          .transpose()?;
+        SyncRpcChannel::new(env, exe.clone(), args.clone(), options)
+      })
+      .collect::<Result<_>>()?;
+    Ok(Self { channels, next: 0 })
+  }
+
+  /// Sends a request to one of the children, as with
+  /// `SyncRpcChannel#requestSync`. Children take turns handling requests, in
+  /// round-robin order.
+  #[napi]
+  pub fn request_sync_any(&mut self, env: Env, method: String, payload: String) -> Result<String> {
+    let i = self.next;
+    self.next = (i + 1) % self.channels.len();
+    self.channels[i].request_sync(env, method, payload)
+  }
+
+  /// Sends the same request to every child, then waits for all of them to
+  /// respond. The request is sent to all children before waiting for any of
+  /// them, so they handle it in parallel, while their callbacks are invoked
+  /// one child at a time.
+  ///
+  /// Returns one result per child, in order, as with
+  /// `SyncRpcChannel#tryRequestSync`. Unlike there, failures of a channel
+  /// itself, such as its child exiting mid-request, are also returned as
+  /// `{ ok: false, error }`, so that the other children's responses are not
+  /// lost.
+  #[napi]
+  pub fn request_sync_all(
+    &mut self,
+    env: Env,
+    method: String,
+    payload: String,
+  ) -> Vec<TryRequestResult> {
+    let payload = payload.as_bytes();
+    let sent: Vec<_> = self
+      .channels
+      .iter_mut()
+      .map(|channel| channel.with_activity(|_, wire| wire.send_request(&method, payload, None)))
+      .collect();
+    self
+      .channels
+      .iter_mut()
+      .zip(sent)
+      .map(|(channel, sent)| {
+        let res = channel.with_activity(|this, wire| {
+          let res = sent.and_then(|()| {
+            wire.receive_response(&method, RequestOptions::default(), &mut |name, payload| {
+              this.call_sync(&env, name, payload)
+            })
+          });
+          wire.metrics.record_request(res.as_ref());
+          if res.is_ok() || !this.restart_exited_child(wire)? {
+            return res;
+          }
+          wire.request(
+            &method,
+            payload,
+            RequestOptions::default(),
+            &mut |name, payload| this.call_sync(&env, name, payload),
+          )
+        });
+        let res = res.and_then(|res| match res {
+          Ok(value) => Ok(response_to_string(value).map(Ok)?),
+          Err(error) => Ok(Err(error)),
+        });
+        match res {
+          Ok(Ok(value)) => TryRequestResult {
+            ok: true,
+            value: Some(value),
+            error: None,
+          },
+          Ok(Err(error)) => TryRequestResult {
+            ok: false,
+            value: None,
+            error: Some(error),
+          },
+          Err(e) => TryRequestResult {
+            ok: false,
+            value: None,
+            error: Some(e.to_string()),
+          },
+        }
+      })
+      .collect()
+  }
+
+  /// Registers a JavaScript callback that any of the children can invoke
+  /// before completing a request, see `SyncRpcChannel#registerCallback`.
+  #[napi(ts_args_type = "name: string, callback: (name: string, payload: string) => string")]
+  pub fn register_callback(&mut self, name: String, cb: Callback) -> Result<()> {
+    for channel in &mut self.channels {
+      channel
+        .callbacks
+        .insert(name.clone(), RegisteredCallback::String(cb.create_ref()?));
+    }
+    Ok(())
+  }
+
+  /// Removes the callback registered under `name` from every child, as with
+  /// `SyncRpcChannel#unregisterCallback`. Returns whether there was one.
+  #[napi]
+  pub fn unregister_callback(&mut self, name: String) -> bool {
+    let mut found = false;
+    for channel in &mut self.channels {
+      found |= channel.unregister_callback(name.clone());
+    }
+    found
+  }
+
+  /// The number of children in the pool.
+  #[napi]
+  pub fn size(&self) -> u32 {
+    self.channels.len() as u32
+  }
+
+  /// The process IDs of the children, in order.
+  #[napi]
+  pub fn pids(&self) -> Result<Vec<u32>> {
+    self.channels.iter().map(SyncRpcChannel::pid).collect()
+  }
+
+  /// Closes the pool, terminating all of its children.
+  #[napi]
+  pub fn close(&mut self) -> Result<()> {
+    self
+      .channels
+      .iter_mut()
+      .map(SyncRpcChannel::close)
+      .fold(Ok(()), Result::and)
+  }
+}
@@ -0,0 +1,91 @@
+use std::{
+  fs::{File, OpenOptions},
+  io::{self, BufWriter, Write},
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+use libsyncrpc_connection::MessageType;
+
+/// Which way a traced message went.
+#[derive(Clone, Copy)]
+pub(crate) enum Direction {
+  Send,
+  Receive,
+}
+
+/// Appends a JSON Lines record of every message crossing the wire to a file,
+/// for offline analysis of the protocol. Each line is an object with:
+///
+/// - `ts`: microseconds since the Unix epoch.
+/// - `dir`: `"send"` (to the child) or `"recv"` (from the child).
+/// - `type`: the numeric `MessageType`.
+/// - `typeName`: the name of the `MessageType`, or `null` if unknown.
+/// - `name`: the message's `<name>`, lossily decoded as UTF-8.
+/// - `len`: the length of the message's `<payload>`, in bytes.
+/// - `hash`: the 64-bit FNV-1a hash of the `<payload>` as a hex string, if
+///   payload hashing is enabled.
+/// - `preview` or `previewHex`: up to the first `preview_len` bytes of the
+///   `<payload>`, as text if they are valid UTF-8 and as a hex string
+///   otherwise, if previews are enabled.
+pub(crate) struct Tracer {
+  file: BufWriter<File>,
+  hash_payloads: bool,
+  preview_len: usize,
+}
+
+impl Tracer {
+  pub fn open(path: &str, hash_payloads: bool, preview_len: usize) -> io::Result<Self> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    Ok(Self {
+      file: BufWriter::new(file),
+      hash_payloads,
+      preview_len,
+    })
+  }
+
+  /// Records a message. Tracing is best-effort: failing to write the trace
+  /// never fails the message itself.
+  pub fn record(&mut self, dir: Direction, ty: u8, name: &[u8], payload: &[u8]) {
+    let ts = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default()
+      .as_micros() as u64;
+    let mut record = serde_json::json!({
+      "ts": ts,
+      "dir": match dir {
+        Direction::Send => "send",
+        Direction::Receive => "recv",
+      },
+      "type": ty,
+      "typeName": MessageType::try_from(ty).ok().map(|ty| format!("{ty:?}")),
+      "name": String::from_utf8_lossy(name),
+      "len": payload.len(),
+    });
+    if self.hash_payloads {
+      record["hash"] = format!("{:016x}", fnv1a(payload)).into();
+    }
+    if self.preview_len > 0 {
+      let preview = &payload[..payload.len().min(self.preview_len)];
+      match std::str::from_utf8(preview) {
+        Ok(text) => record["preview"] = text.into(),
+        // The preview may end in the middle of a character.
+        Err(e) if e.error_len().is_none() => {
+          let text = std::str::from_utf8(&preview[..e.valid_up_to()]).expect("checked above");
+          record["preview"] = text.into();
+        }
+        Err(_) => {
+          let hex: String = preview.iter().map(|byte| format!("{byte:02x}")).collect();
+          record["previewHex"] = hex.into();
+        }
+      }
+    }
+    let _ = writeln!(self.file, "{record}").and_then(|()| self.file.flush());
+  }
+}
+
+// Helper function to compute the 64-bit FNV-1a hash of some bytes.
+fn fnv1a(bytes: &[u8]) -> u64 {
+  bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+    (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+  })
+}
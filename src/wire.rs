@@ -0,0 +1,998 @@
+use std::{
+  fmt, io,
+  process::ExitStatus,
+  sync::{
+    atomic::{AtomicBool, AtomicI64, Ordering},
+    Arc, Mutex, PoisonError,
+  },
+  time::{Duration, Instant},
+};
+
+use napi::{bindgen_prelude::Result, threadsafe_function::ThreadsafeFunctionCallMode, Error};
+
+use libsyncrpc_connection::{MessageBuffers, RpcError};
+
+use crate::{
+  child::{ChildConnection, ChildProcess, FatalStderr, StderrCallback},
+  trace::{Direction, Tracer},
+  ChunkCallback, MessageType, NotifyCallback, MESSAGE_TYPE_SLOTS, PING_METHOD,
+};
+
+/// How long to wait for a child to exit after it closed its stdout before
+/// reporting on its status.
+pub(crate) const EXIT_GRACE_PERIOD: Duration = Duration::from_secs(1);
+
+/// How often to check whether a child has exited while waiting for it.
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Running message counters for a `SyncRpcChannel`. These are atomics so they
+/// can be read while a request is in progress on another thread.
+#[derive(Default)]
+pub(crate) struct Metrics {
+  pub sent: [AtomicI64; MESSAGE_TYPE_SLOTS],
+  pub received: [AtomicI64; MESSAGE_TYPE_SLOTS],
+  pub requests: AtomicI64,
+  pub response_bytes: AtomicI64,
+  pub callbacks: AtomicI64,
+  pub errors: AtomicI64,
+}
+
+impl Metrics {
+  pub fn snapshot(counters: &[AtomicI64]) -> Vec<i64> {
+    counters
+      .iter()
+      .map(|count| count.load(Ordering::Relaxed))
+      .collect()
+  }
+
+  /// Sets every counter back to zero.
+  pub fn reset(&self) {
+    let totals = [
+      &self.requests,
+      &self.response_bytes,
+      &self.callbacks,
+      &self.errors,
+    ];
+    for count in self.sent.iter().chain(&self.received).chain(totals) {
+      count.store(0, Ordering::Relaxed);
+    }
+  }
+
+  // Helper method to count a finished request, failed or not.
+  pub fn record_request(&self, res: std::result::Result<&RemoteResult, &WireError>) {
+    self.requests.fetch_add(1, Ordering::Relaxed);
+    match res {
+      Ok(Ok(payload)) => {
+        self
+          .response_bytes
+          .fetch_add(payload.len() as i64, Ordering::Relaxed);
+      }
+      _ => {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+      }
+    }
+  }
+}
+
+/// Per-request settings threaded through the request loop.
+#[derive(Default, Clone, Copy)]
+pub(crate) struct RequestOptions<'a> {
+  /// How long the whole request, including callbacks, may take.
+  pub timeout: Option<Duration>,
+  /// A deadline to send to the child along with the request.
+  pub child_deadline_ms: Option<u32>,
+  /// Where to send `MessageType.ResponseChunk` messages, for requests that
+  /// accept streamed responses.
+  pub on_chunk: Option<&'a ChunkCallback>,
+  /// The only callbacks the child may invoke, if restricted.
+  pub allowed_callbacks: Option<&'a [String]>,
+}
+
+/// Whether a message received during a request is about that request, see
+/// `ChannelOptions.requestIds`.
+enum RequestIdCheck {
+  Current,
+  /// A response (or chunk of one) to an earlier request, to discard.
+  Stale,
+  /// A call made on behalf of an earlier request, to reject.
+  StaleCall,
+  /// A response without an `<id>`, which can't be told apart from a late one.
+  Missing,
+}
+
+/// Either a successful response payload or an error message reported by the
+/// child.
+pub(crate) type RemoteResult = std::result::Result<Vec<u8>, String>;
+
+/// Why the channel failed, as opposed to an error reported by the child (see
+/// `RemoteResult`). Failures of the connection itself are kept as they are,
+/// to be thrown with a stable `code` (see `wire_error`).
+#[derive(Debug)]
+pub enum WireError {
+  Rpc(RpcError),
+  Other(Error),
+}
+
+impl fmt::Display for WireError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      WireError::Rpc(err) => err.fmt(f),
+      WireError::Other(err) => f.write_str(&err.reason),
+    }
+  }
+}
+
+impl From<RpcError> for WireError {
+  fn from(err: RpcError) -> Self {
+    WireError::Rpc(err)
+  }
+}
+
+impl From<io::Error> for WireError {
+  fn from(err: io::Error) -> Self {
+    WireError::Rpc(err.into())
+  }
+}
+
+impl From<Error> for WireError {
+  fn from(err: Error) -> Self {
+    WireError::Other(err)
+  }
+}
+
+pub type WireResult<T> = std::result::Result<T, WireError>;
+
+/// The `<type>` and `<payload>` of a message read by `Wire::read`, whose
+/// `<name>` is left in `Wire::received_name`.
+pub(crate) type ReceivedMessage = (u8, Vec<u8>);
+
+/// Invokes the callback the child asked for with a `MessageType.Call`,
+/// returning its result, or `None` if no callback is registered under the
+/// given name.
+pub(crate) type CallHandler<'a> = dyn FnMut(&str, Vec<u8>) -> Option<Result<Vec<u8>>> + 'a;
+
+/// The state of a channel's connection to its child: everything a request
+/// needs, so that requests can be made from a worker thread as well as from
+/// the JavaScript thread (see `SyncRpcChannel#requestAsync`).
+pub(crate) struct Wire {
+  pub child: Arc<Mutex<ChildProcess>>,
+  pub conn: ChildConnection,
+  pub metrics: Arc<Metrics>,
+  pub tracer: Option<Tracer>,
+  // Where `MessageType.Log` messages from the child go, if anywhere.
+  pub log: Option<Arc<StderrCallback>>,
+  // Where `MessageType.Notify` messages from the child go, if anywhere.
+  pub notify: Option<Arc<NotifyCallback>>,
+  // Where stderr output that fails requests is collected, see
+  // `ChannelOptions.stderrIsFatal`.
+  pub fatal_stderr: Option<Arc<FatalStderr>>,
+  // Set once the wire is in an unknown state (e.g. after a timeout left a
+  // response unread), so further requests fail fast instead of reading stale
+  // data.
+  pub poisoned: Option<String>,
+  // The method of the request whose timeout poisoned the wire, along with
+  // the reason it was poisoned for, as long as waiting for the child to
+  // finish that request makes the wire usable again (see `resync`).
+  pub abandoned: Option<(String, String)>,
+  // Number of pings that timed out, whose responses may still arrive and
+  // must be skipped.
+  pub unanswered_pings: usize,
+  // Set while an asynchronous request owns the wire, so synchronous methods
+  // fail instead of interleaving with it.
+  pub busy: bool,
+  // The `<id>` to send with the next request that carries one.
+  pub next_request_id: u32,
+  // Whether every request carries an `<id>` (see `ChannelOptions.requestIds`),
+  // and the `<id>` of the request in progress, if so.
+  pub request_ids: bool,
+  pub current_request_id: Option<u32>,
+  // Whether responses must carry the name of the request they are for, see
+  // `ChannelOptions.strictResponseNames`.
+  pub strict_response_names: bool,
+  // The largest callback payload passed on to JavaScript, see
+  // `SyncRpcChannel#setCallbackPayloadLimit`.
+  pub callback_payload_limit: Option<usize>,
+  // Set by `CancellationHandle#cancel` to have the request in progress
+  // cancelled once the callback it was called from returns.
+  pub cancel_requested: Arc<AtomicBool>,
+  // Where messages are read into, reusing the allocation of their `<name>`
+  // (see `received_name`), and that of their `<payload>` unless it's returned.
+  pub read_bufs: MessageBuffers,
+}
+
+impl Wire {
+  /// Sends a request to the child and reads messages until it responds,
+  /// invoking `call` for each `MessageType.Call` it makes in the meantime.
+  pub fn request(
+    &mut self,
+    method: &str,
+    payload: &[u8],
+    opts: RequestOptions<'_>,
+    call: &mut CallHandler<'_>,
+  ) -> WireResult<RemoteResult> {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::info_span!("request", method, payload_len = payload.len()).entered();
+    self.set_deadline(opts.timeout.map(|timeout| Instant::now() + timeout));
+    let res = self
+      .check_stderr()
+      .map_err(WireError::from)
+      .and_then(|()| self.send_request(method, payload, opts.child_deadline_ms))
+      .and_then(|()| self.receive_response(method, opts, call))
+      .and_then(|res| Ok(self.check_stderr().map(|()| res)?));
+    self.set_deadline(None);
+    self.metrics.record_request(res.as_ref());
+    res
+  }
+
+  // Helper method to fail the request in progress if the child wrote to its
+  // stderr and that's fatal.
+  fn check_stderr(&self) -> Result<()> {
+    match &self.fatal_stderr {
+      Some(fatal) => fatal.take_error(),
+      None => Ok(()),
+    }
+  }
+
+  /// Sets the point in time after which reading from or writing to the child
+  /// fails with `io::ErrorKind::TimedOut`, or clears it.
+  pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+    self.conn.reader_mut().set_deadline(deadline);
+    self.conn.writer_mut().get_mut().set_deadline(deadline);
+  }
+
+  /// Sends a request to the child without waiting for it to respond. Must be
+  /// followed by [`Wire::receive_response`] before anything else is sent.
+  pub fn send_request(
+    &mut self,
+    method: &str,
+    payload: &[u8],
+    child_deadline_ms: Option<u32>,
+  ) -> WireResult<()> {
+    check_method(method)?;
+    if let Some(reason) = &self.poisoned {
+      return Err(Error::from_reason(format!("channel is no longer usable: {reason}")).into());
+    }
+    self.current_request_id = self.request_ids.then(|| self.take_request_id());
+    let id = self.current_request_id;
+    match self.write_message(
+      MessageType::Request,
+      method.as_bytes(),
+      payload,
+      child_deadline_ms,
+      id,
+    ) {
+      // The child may have written its response and exited without reading
+      // the request. Whatever it wrote is still buffered on our end, so read
+      // that (or find out how it exited) rather than reporting a broken pipe.
+      Err(RpcError::Io(e)) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+      res => Ok(res?),
+    }
+  }
+
+  /// Reads messages until the child responds to the request for `method`
+  /// sent by [`Wire::send_request`], invoking `call` for each
+  /// `MessageType.Call` it makes in the meantime.
+  pub fn receive_response(
+    &mut self,
+    method: &str,
+    opts: RequestOptions<'_>,
+    call: &mut CallHandler<'_>,
+  ) -> WireResult<RemoteResult> {
+    let method_bytes = method.as_bytes();
+    // An error thrown by `opts.on_chunk`, or a callback payload over the
+    // limit, reported once the child is done with the request so the wire is
+    // left in a known state.
+    let mut deferred_error: Option<Error> = None;
+    loop {
+      let msg = match self.read_with_id() {
+        Err(RpcError::Io(e)) if e.kind() == io::ErrorKind::TimedOut => {
+          let timeout_ms = opts.timeout.unwrap_or_default().as_millis();
+          let reason = format!("request to `{method}` timed out after {timeout_ms}ms");
+          // With request ids, the late response can be told apart from that
+          // of the next request, unless the timeout cut a message short.
+          if !self.request_ids {
+            self.poisoned = Some(reason.clone());
+            self.abandoned = Some((method.to_owned(), reason.clone()));
+          }
+          return Err(Error::from_reason(reason).into());
+        }
+        msg => msg?,
+      };
+      let Some(((ty, payload), id)) = msg else {
+        let status = self.describe_exit_status();
+        return Err(
+          Error::from_reason(format!(
+            "child process closed the connection before responding to `{method}` ({status})"
+          ))
+          .into(),
+        );
+      };
+      let msg_ty = ty.try_into().map_err(Error::from_reason)?;
+      match self.check_request_id(&msg_ty, &self.read_bufs.name, id) {
+        RequestIdCheck::Current => {}
+        RequestIdCheck::Stale => continue,
+        RequestIdCheck::StaleCall => {
+          let name = String::from_utf8_lossy(&self.read_bufs.name).into_owned();
+          let message =
+            format!("callback `{name}` was invoked for a request that is no longer in progress");
+          self.write_message(
+            MessageType::CallError,
+            name.as_bytes(),
+            message.as_bytes(),
+            None,
+            id,
+          )?;
+          continue;
+        }
+        RequestIdCheck::Missing => {
+          let name = String::from_utf8_lossy(&self.read_bufs.name);
+          return Err(
+            Error::from_reason(format!(
+              "response to `{name}` has no `<id>`, which the child must echo when `requestIds` is set"
+            ))
+            .into(),
+          );
+        }
+      }
+      match msg_ty {
+        MessageType::Response
+          if self.read_bufs.name == PING_METHOD.as_bytes() && self.unanswered_pings > 0 =>
+        {
+          // A late response to a ping that timed out.
+          self.unanswered_pings -= 1;
+        }
+        MessageType::Response | MessageType::Error if deferred_error.is_some() => {
+          return Err(deferred_error.expect("checked above").into());
+        }
+        MessageType::Response => {
+          if self.is_response_to(&self.read_bufs.name, method) {
+            return Ok(Ok(payload));
+          } else {
+            return Err(self.name_mismatch(method).into());
+          }
+        }
+        MessageType::Error => {
+          let name = String::from_utf8_lossy(&self.read_bufs.name);
+          if !self.is_response_to(name.as_bytes(), method) {
+            return Err(self.conn.create_error(&name, payload, method).into());
+          }
+          return Ok(Err(
+            self.conn.create_error(&name, payload, &name).to_string(),
+          ));
+        }
+        MessageType::Call => {
+          let name = String::from_utf8_lossy(&self.read_bufs.name).into_owned();
+          let rejected = self.handle_call(&name, payload, id, opts.allowed_callbacks, call)?;
+          if deferred_error.is_none() {
+            deferred_error = rejected;
+          }
+          if self.cancel_requested.swap(false, Ordering::Relaxed) {
+            self.write_message(
+              MessageType::Cancel,
+              method_bytes,
+              b"",
+              None,
+              self.current_request_id,
+            )?;
+          }
+        }
+        MessageType::ResponseChunk if opts.on_chunk.is_some() => {
+          if !self.is_response_to(&self.read_bufs.name, method) {
+            return Err(self.name_mismatch(method).into());
+          }
+          if deferred_error.is_none() {
+            let on_chunk = opts.on_chunk.expect("checked above");
+            if let Err(e) = on_chunk.call(payload.into()) {
+              deferred_error = Some(Error::from_reason(format!(
+                "Error handling response chunk for `{method}`: {e}"
+              )));
+            }
+          }
+        }
+        _ => {
+          return Err(Error::from_reason(format!("Invalid message type from child: {ty:?}")).into())
+        }
+      }
+    }
+  }
+
+  /// Sends all of `requests` to the child up front, each with its own
+  /// `<id>`, then reads messages until the child has responded to all of
+  /// them, in whatever order it likes. Results are returned in the order of
+  /// `requests`.
+  pub fn request_batch(
+    &mut self,
+    requests: &[(&str, &[u8])],
+    call: &mut CallHandler<'_>,
+  ) -> WireResult<Vec<RemoteResult>> {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::info_span!("request_batch", len = requests.len()).entered();
+    for (method, _) in requests {
+      check_method(method)?;
+    }
+    if let Some(reason) = &self.poisoned {
+      return Err(Error::from_reason(format!("channel is no longer usable: {reason}")).into());
+    }
+    let res = self
+      .check_stderr()
+      .map_err(WireError::from)
+      .and_then(|()| self.run_batch(requests, call))
+      .and_then(|res| Ok(self.check_stderr().map(|()| res)?));
+    match &res {
+      Ok(results) => results
+        .iter()
+        .for_each(|res| self.metrics.record_request(Ok(res))),
+      Err(e) => requests
+        .iter()
+        .for_each(|_| self.metrics.record_request(Err(e))),
+    }
+    res
+  }
+
+  fn run_batch(
+    &mut self,
+    requests: &[(&str, &[u8])],
+    call: &mut CallHandler<'_>,
+  ) -> WireResult<Vec<RemoteResult>> {
+    let first_id = self.next_request_id;
+    self.next_request_id = first_id.wrapping_add(requests.len() as u32);
+    for (i, (method, payload)) in requests.iter().enumerate() {
+      let id = first_id.wrapping_add(i as u32);
+      match self.write_message(
+        MessageType::Request,
+        method.as_bytes(),
+        payload,
+        None,
+        Some(id),
+      ) {
+        // As in `send_request`, read whatever the child wrote before it went
+        // away.
+        Err(RpcError::Io(e)) if e.kind() == io::ErrorKind::BrokenPipe => break,
+        res => res?,
+      }
+    }
+    let mut results = vec![None; requests.len()];
+    let mut remaining = requests.len();
+    // A callback payload over the limit, reported once the child has
+    // responded to every request.
+    let mut deferred_error = None;
+    while remaining > 0 {
+      let Some(((ty, payload), id)) = self.read_with_id()? else {
+        let status = self.describe_exit_status();
+        return Err(
+          Error::from_reason(format!(
+            "child process closed the connection before responding to {remaining} batched requests ({status})"
+          ))
+          .into(),
+        );
+      };
+      match ty.try_into().map_err(Error::from_reason)? {
+        MessageType::Response
+          if self.read_bufs.name == PING_METHOD.as_bytes() && self.unanswered_pings > 0 =>
+        {
+          // A late response to a ping that timed out.
+          self.unanswered_pings -= 1;
+        }
+        ty @ (MessageType::Response | MessageType::Error) => {
+          let name = String::from_utf8_lossy(&self.read_bufs.name);
+          let Some(index) = id
+            .map(|id| id.wrapping_sub(first_id) as usize)
+            .filter(|index| *index < requests.len())
+          else {
+            // A late response to an earlier request that timed out.
+            if self.request_ids && id.is_some() {
+              continue;
+            }
+            return Err(
+              Error::from_reason(format!(
+                "response to `{name}` does not match any batched request (id: {id:?})"
+              ))
+              .into(),
+            );
+          };
+          let method = requests[index].0;
+          if !self.is_response_to(name.as_bytes(), method) {
+            return Err(self.name_mismatch(method).into());
+          }
+          if results[index].is_some() {
+            return Err(
+              Error::from_reason(format!(
+                "received more than one response to batched request {index} (`{method}`)"
+              ))
+              .into(),
+            );
+          }
+          results[index] = Some(match ty {
+            MessageType::Response => Ok(payload),
+            _ => Err(self.conn.create_error(&name, payload, &name).to_string()),
+          });
+          remaining -= 1;
+        }
+        MessageType::Call => {
+          let name = String::from_utf8_lossy(&self.read_bufs.name).into_owned();
+          let rejected = self.handle_call(&name, payload, id, None, call)?;
+          if deferred_error.is_none() {
+            deferred_error = rejected;
+          }
+          if self.cancel_requested.swap(false, Ordering::Relaxed) {
+            self.cancel_batch(requests, first_id, &results)?;
+          }
+        }
+        _ => {
+          return Err(Error::from_reason(format!("Invalid message type from child: {ty:?}")).into())
+        }
+      }
+    }
+    if let Some(e) = deferred_error {
+      return Err(e.into());
+    }
+    Ok(
+      results
+        .into_iter()
+        .map(|res| res.expect("every request has been responded to"))
+        .collect(),
+    )
+  }
+
+  /// Reads messages while no request is in progress, forwarding
+  /// notifications, until the deadline passes or the child closes the
+  /// connection, see `SyncRpcChannel#pumpNotifications`.
+  pub fn pump_notifications(&mut self) -> WireResult<()> {
+    if let Some(reason) = &self.poisoned {
+      return Err(Error::from_reason(format!("channel is no longer usable: {reason}")).into());
+    }
+    loop {
+      let msg = match self.read() {
+        Err(RpcError::Io(e)) if e.kind() == io::ErrorKind::TimedOut => return Ok(()),
+        msg => msg?,
+      };
+      // The next request finds out why the child went away.
+      let Some((ty, _)) = msg else {
+        return Ok(());
+      };
+      if ty == MessageType::Response as u8
+        && self.read_bufs.name == PING_METHOD.as_bytes()
+        && self.unanswered_pings > 0
+      {
+        self.unanswered_pings -= 1;
+        continue;
+      }
+      let name = String::from_utf8_lossy(&self.read_bufs.name);
+      return Err(
+        Error::from_reason(format!(
+          "unexpected message while no request is in progress: ({ty}) `{name}`"
+        ))
+        .into(),
+      );
+    }
+  }
+
+  /// Reads and discards messages until the child finishes the request that
+  /// timed out and poisoned the wire, see `SyncRpcChannel#resync`. Returns
+  /// whether it did before the deadline.
+  pub fn resync(&mut self) -> WireResult<bool> {
+    let Some(reason) = &self.poisoned else {
+      return Ok(true);
+    };
+    // Anything else that poisoned the wire since the timeout replaced the
+    // reason, and can't be waited out.
+    let method = match &self.abandoned {
+      Some((method, abandoned_reason)) if abandoned_reason == reason => method.clone(),
+      _ => {
+        return Err(
+          Error::from_reason(format!("channel cannot be resynchronized: {reason}")).into(),
+        )
+      }
+    };
+    loop {
+      let msg = match self.read_with_id() {
+        Err(RpcError::Io(e)) if e.kind() == io::ErrorKind::TimedOut => return Ok(false),
+        msg => msg?,
+      };
+      let Some(((ty, _), id)) = msg else {
+        let status = self.describe_exit_status();
+        return Err(
+          Error::from_reason(format!(
+            "child process closed the connection before finishing `{method}` ({status})"
+          ))
+          .into(),
+        );
+      };
+      match ty.try_into().map_err(Error::from_reason)? {
+        MessageType::Response
+          if self.read_bufs.name == PING_METHOD.as_bytes() && self.unanswered_pings > 0 =>
+        {
+          self.unanswered_pings -= 1;
+        }
+        MessageType::Response | MessageType::Error if self.read_bufs.name == method.as_bytes() => {
+          self.poisoned = None;
+          self.abandoned = None;
+          return Ok(true);
+        }
+        MessageType::Call => {
+          let name = String::from_utf8_lossy(&self.read_bufs.name).into_owned();
+          let message =
+            format!("callback `{name}` was invoked for a request that timed out and was abandoned");
+          self.write_message(
+            MessageType::CallError,
+            name.as_bytes(),
+            message.as_bytes(),
+            None,
+            id,
+          )?;
+        }
+        // Parts of the response to the abandoned request.
+        MessageType::ResponseChunk => {}
+        ty => {
+          let name = String::from_utf8_lossy(&self.read_bufs.name);
+          return Err(
+            Error::from_reason(format!(
+              "unexpected message while waiting for the child to finish `{method}`: ({}) `{name}`",
+              ty as u8
+            ))
+            .into(),
+          );
+        }
+      }
+    }
+  }
+
+  // Helper method to allocate the `<id>` of a request.
+  fn take_request_id(&mut self) -> u32 {
+    let id = self.next_request_id;
+    self.next_request_id = id.wrapping_add(1);
+    id
+  }
+
+  // Helper method to fail a request for `method` with a response (or chunk of
+  // one) for something else, named as in `read_bufs`.
+  fn name_mismatch(&self, method: &str) -> RpcError {
+    RpcError::NameMismatch {
+      expected: method.into(),
+      actual: String::from_utf8_lossy(&self.read_bufs.name).into_owned(),
+    }
+  }
+
+  // Helper method to tell whether a response (or chunk of one) named `name`
+  // is for the request for `method`, which it always is unless
+  // `strict_response_names` is set.
+  fn is_response_to(&self, name: &[u8], method: &str) -> bool {
+    !self.strict_response_names || name == method.as_bytes()
+  }
+
+  // Helper method to tell whether a message received during a request with
+  // an `<id>` is about that request. Messages without an `<id>` are assumed
+  // to be, except for responses, which must echo it.
+  fn check_request_id(&self, ty: &MessageType, name: &[u8], id: Option<u32>) -> RequestIdCheck {
+    let Some(current) = self.current_request_id else {
+      return RequestIdCheck::Current;
+    };
+    match (ty, id) {
+      // Pings are answered without an `<id>`.
+      (MessageType::Response, _) if name == PING_METHOD.as_bytes() => RequestIdCheck::Current,
+      (_, Some(id)) if id == current => RequestIdCheck::Current,
+      (MessageType::Call, Some(_)) => RequestIdCheck::StaleCall,
+      (_, Some(_)) => RequestIdCheck::Stale,
+      (MessageType::Response | MessageType::Error, None) => RequestIdCheck::Missing,
+      (_, None) => RequestIdCheck::Current,
+    }
+  }
+
+  // Helper method to cancel every request in a batch that the child hasn't
+  // responded to yet.
+  fn cancel_batch(
+    &mut self,
+    requests: &[(&str, &[u8])],
+    first_id: u32,
+    results: &[Option<RemoteResult>],
+  ) -> std::result::Result<(), RpcError> {
+    for (i, ((method, _), res)) in requests.iter().zip(results).enumerate() {
+      if res.is_none() {
+        let id = first_id.wrapping_add(i as u32);
+        self.write_message(MessageType::Cancel, method.as_bytes(), b"", None, Some(id))?;
+      }
+    }
+    Ok(())
+  }
+
+  // Helper method to handle callback calls, echoing the call's `<id>`, if
+  // any, in the response. Calls without a name, or to callbacks outside of
+  // `allowed`, if given, are rejected without invoking anything, and so are
+  // calls whose payload is over the limit, returning the error to fail the
+  // request with.
+  fn handle_call(
+    &mut self,
+    name: &str,
+    payload: Vec<u8>,
+    id: Option<u32>,
+    allowed: Option<&[String]>,
+    call: &mut CallHandler<'_>,
+  ) -> WireResult<Option<Error>> {
+    if name.is_empty() {
+      self.write_message(
+        MessageType::CallError,
+        b"",
+        b"callback name must not be empty",
+        None,
+        id,
+      )?;
+      return Ok(None);
+    }
+    if allowed.is_some_and(|allowed| !allowed.iter().any(|allowed| allowed == name)) {
+      let message = format!("callback `{name}` is not allowed during this request");
+      self.write_message(
+        MessageType::CallError,
+        name.as_bytes(),
+        message.as_bytes(),
+        None,
+        id,
+      )?;
+      return Ok(None);
+    }
+    if let Some(limit) = self
+      .callback_payload_limit
+      .filter(|limit| payload.len() > *limit)
+    {
+      let message = format!(
+        "payload of {} bytes for callback `{name}` exceeds the limit of {limit} bytes",
+        payload.len()
+      );
+      self.write_message(
+        MessageType::CallError,
+        name.as_bytes(),
+        message.as_bytes(),
+        None,
+        id,
+      )?;
+      return Ok(Some(Error::from_reason(message)));
+    }
+    #[cfg(feature = "tracing")]
+    let span = tracing::info_span!(
+      "callback",
+      name,
+      payload_len = payload.len(),
+      duration_us = tracing::field::Empty,
+    )
+    .entered();
+    #[cfg(feature = "tracing")]
+    let start = Instant::now();
+    let res = call(name, payload);
+    self.metrics.callbacks.fetch_add(1, Ordering::Relaxed);
+    #[cfg(feature = "tracing")]
+    span.record("duration_us", start.elapsed().as_micros() as u64);
+    match res {
+      Some(Ok(res)) => {
+        self.write_message(MessageType::CallResponse, name.as_bytes(), &res, None, id)?;
+      }
+      // The child is told about the failure and decides whether the request
+      // can still succeed, so it doesn't fail the request here.
+      Some(Err(e)) => {
+        self.write_message(
+          MessageType::CallError,
+          name.as_bytes(),
+          format!("{e}").trim().as_bytes(),
+          None,
+          id,
+        )?;
+      }
+      None => {
+        self.write_message(MessageType::CallError, name.as_bytes(), format!("unknown callback: `{name}`. Please make sure to register it on the JavaScript side before invoking it.").as_bytes(), None, id)?;
+      }
+    }
+    Ok(None)
+  }
+
+  // Helper method to write a message to the child, keeping count of it.
+  pub fn write(
+    &mut self,
+    ty: MessageType,
+    name: &[u8],
+    payload: &[u8],
+  ) -> std::result::Result<(), RpcError> {
+    self.write_message(ty, name, payload, None, None)
+  }
+
+  // Helper method to write a request to the child, optionally telling it how
+  // long it has to respond, keeping count of it.
+  pub fn write_request(
+    &mut self,
+    method: &[u8],
+    payload: &[u8],
+    deadline_ms: Option<u32>,
+  ) -> std::result::Result<(), RpcError> {
+    self.write_message(MessageType::Request, method, payload, deadline_ms, None)
+  }
+
+  // Helper method to write a message with any optional items to the child,
+  // keeping count of it.
+  fn write_message(
+    &mut self,
+    ty: MessageType,
+    name: &[u8],
+    payload: &[u8],
+    deadline_ms: Option<u32>,
+    id: Option<u32>,
+  ) -> std::result::Result<(), RpcError> {
+    let ty = ty as u8;
+    if let Err(e) = self.conn.write_with_id(ty, name, payload, deadline_ms, id) {
+      // The message may have been partially written.
+      if matches!(&e, RpcError::Io(e) if e.kind() == io::ErrorKind::TimedOut) {
+        self.poisoned = Some(e.to_string());
+      }
+      return Err(e);
+    }
+    self.record_sent(ty, name, payload);
+    Ok(())
+  }
+
+  // Helper method to read a message from the child, keeping count of it,
+  // returning its `<type>` and `<payload>`, with its `<name>` left in
+  // `received_name`. Fails with `io::ErrorKind::TimedOut` if the current
+  // request's deadline has passed, even if a message is already buffered (e.g.
+  // after a slow callback).
+  pub fn read(&mut self) -> std::result::Result<Option<ReceivedMessage>, RpcError> {
+    Ok(self.read_with_id()?.map(|(msg, _)| msg))
+  }
+
+  /// The `<name>` of the message last returned by `read`.
+  pub fn received_name(&self) -> &[u8] {
+    &self.read_bufs.name
+  }
+
+  // Like `read`, but also returns the message's `<id>`, if any.
+  // `MessageType.Log` and `MessageType.Notify` messages are forwarded as they
+  // arrive, never returned.
+  fn read_with_id(
+    &mut self,
+  ) -> std::result::Result<Option<(ReceivedMessage, Option<u32>)>, RpcError> {
+    loop {
+      let Some(ty) = self.read_message()? else {
+        return Ok(None);
+      };
+      let bufs = &mut self.read_bufs;
+      if ty == MessageType::Log as u8 {
+        if let Some(log) = &self.log {
+          let message = String::from_utf8_lossy(&bufs.payload).into_owned();
+          log.call(message, ThreadsafeFunctionCallMode::NonBlocking);
+        }
+      } else if ty == MessageType::Notify as u8 {
+        if let Some(notify) = &self.notify {
+          let name = String::from_utf8_lossy(&bufs.name).into_owned();
+          let payload = String::from_utf8_lossy(&bufs.payload).into_owned();
+          notify.call(
+            (name, payload).into(),
+            ThreadsafeFunctionCallMode::NonBlocking,
+          );
+        }
+      } else {
+        // The payload is handed over as is (e.g. to JavaScript), so it can't
+        // be reused.
+        return Ok(Some(((ty, std::mem::take(&mut bufs.payload)), bufs.id)));
+      }
+    }
+  }
+
+  // Helper method to read the next message of any type into `read_bufs`,
+  // keeping count of (and tracing) it.
+  fn read_message(&mut self) -> std::result::Result<Option<u8>, RpcError> {
+    if self.conn.reader_mut().expired() {
+      return Err(RpcError::Io(io::ErrorKind::TimedOut.into()));
+    }
+    let bufs = &mut self.read_bufs;
+    let consumed = self.conn.reader_mut().consumed();
+    let ty = match self.conn.read_into(bufs) {
+      Ok(Some(ty)) => ty,
+      Ok(None) => return Ok(None),
+      // Only part of the message was read, and the rest can't be told apart
+      // from the start of the next one.
+      Err(RpcError::Io(e))
+        if e.kind() == io::ErrorKind::TimedOut && self.conn.reader_mut().consumed() != consumed =>
+      {
+        self.poisoned = Some(format!("timed out in the middle of a message: {e}"));
+        return Err(RpcError::Io(e));
+      }
+      Err(e) => {
+        // The rest of the message is still unread, or what was read can't
+        // be trusted.
+        if matches!(
+          e,
+          RpcError::FramingError(_)
+            | RpcError::PayloadTooLarge { .. }
+            | RpcError::ChecksumMismatch { .. }
+        ) {
+          self.poisoned = Some(e.to_string());
+        }
+        return Err(e);
+      }
+    };
+    if let Some(count) = self.metrics.received.get(ty as usize) {
+      count.fetch_add(1, Ordering::Relaxed);
+    }
+    if let Some(tracer) = &mut self.tracer {
+      tracer.record(Direction::Receive, ty, &bufs.name, &bufs.payload);
+    }
+    #[cfg(feature = "tracing")]
+    tracing::debug!(
+      ty,
+      type_name = ?libsyncrpc_connection::MessageType::try_from(ty).ok(),
+      name = %String::from_utf8_lossy(&bufs.name),
+      payload_len = bufs.payload.len(),
+      id = bufs.id,
+      "received message",
+    );
+    Ok(Some(ty))
+  }
+
+  // Helper method to keep count of (and trace) a message sent to the child.
+  fn record_sent(&mut self, ty: u8, name: &[u8], payload: &[u8]) {
+    self.metrics.sent[ty as usize].fetch_add(1, Ordering::Relaxed);
+    if let Some(tracer) = &mut self.tracer {
+      tracer.record(Direction::Send, ty, name, payload);
+    }
+  }
+
+  // Helper method to reap the child after it closed its end of the
+  // connection, describing how it exited. The child usually exits right after
+  // closing its stdout, so give it a short grace period before giving up.
+  pub fn describe_exit_status(&self) -> String {
+    if self.is_attached() {
+      return "exit status unknown, since the channel didn't spawn it".into();
+    }
+    match self.wait_for_exit(EXIT_GRACE_PERIOD) {
+      Ok(Some(status)) => status.to_string(),
+      Ok(None) => "child process is still running".into(),
+      Err(e) => format!("failed to get child exit status: {e}"),
+    }
+  }
+
+  /// Whether the channel was attached to a process it didn't spawn, see
+  /// `SyncRpcChannel.fromRaw`.
+  pub fn is_attached(&self) -> bool {
+    self
+      .child
+      .lock()
+      .unwrap_or_else(PoisonError::into_inner)
+      .is_attached()
+  }
+
+  // Helper method to wait up to `timeout` for the child to exit.
+  pub fn wait_for_exit(&self, timeout: Duration) -> io::Result<Option<ExitStatus>> {
+    wait_for_exit(&self.child, timeout)
+  }
+}
+
+// Helper function to reject an empty method name before anything is sent,
+// since its response couldn't be told apart from an unnamed message.
+fn check_method(method: &str) -> Result<()> {
+  if method.is_empty() {
+    return Err(Error::from_reason("method name must not be empty"));
+  }
+  Ok(())
+}
+
+/// Waits up to `timeout` for `child` to exit, returning its exit status if it
+/// did. A process the channel didn't spawn is never waited for.
+pub(crate) fn wait_for_exit(
+  child: &Mutex<ChildProcess>,
+  timeout: Duration,
+) -> io::Result<Option<ExitStatus>> {
+  if child
+    .lock()
+    .unwrap_or_else(PoisonError::into_inner)
+    .is_attached()
+  {
+    return Ok(None);
+  }
+  let deadline = Instant::now() + timeout;
+  loop {
+    let status = child
+      .lock()
+      .unwrap_or_else(PoisonError::into_inner)
+      .try_wait()?;
+    match status {
+      Some(status) => return Ok(Some(status)),
+      None if Instant::now() < deadline => std::thread::sleep(EXIT_POLL_INTERVAL),
+      None => return Ok(None),
+    }
+  }
+}